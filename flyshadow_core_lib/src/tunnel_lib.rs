@@ -2,10 +2,23 @@ use std::ffi::{CStr, CString};
 use std::mem::forget;
 use std::os::raw::c_char;
 use std::sync::Arc;
+use std::time::Duration;
 
+use serde_json::json;
 use tokio::runtime::Runtime;
 
 use tunnel::context::context::TunnelContext;
+use tunnel::context::rate_limit::{RateLimit, RateLimitSnapshot};
+use tunnel::context::traffic::{TrafficKind, TrafficSnapshot};
+use tunnel::context::tunnel_group::GroupStrategy;
+use tunnel::error::TunnelError;
+use tunnel::tunnel::cipher::CipherSuite;
+use tunnel::tunnel::compress::Compression;
+use tunnel::tunnel::event::{TunnelEvent, TunnelEventKind};
+use tunnel::tunnel::quic::QuicTransport;
+use tunnel::tunnel::tls::TlsConfig;
+use tunnel::tunnel::transport::{TcpTransport, TlsTransport};
+use tunnel::tunnel::websocket::{WsConfig, WsTransport};
 
 #[no_mangle]
 pub extern "C" fn connect_tunnel(rt: i64, context_ptr: i64, host: *const c_char, port: u32, password: *const c_char) -> *mut c_char {
@@ -17,12 +30,7 @@ pub extern "C" fn connect_tunnel(rt: i64, context_ptr: i64, host: *const c_char,
     let result = rt.block_on(async move {
         let host = unsafe { CStr::from_ptr(host).to_string_lossy() };
         let password = unsafe { CStr::from_ptr(password).to_string_lossy() };
-        match context_clone.connect_tunnel(host.to_string(), port as u16, password.to_string()).await {
-            Ok(_) => { "".to_string() }
-            Err(e) => {
-                e.to_string()
-            }
-        }
+        result_json(&context_clone.connect_tunnel(host.to_string(), port as u16, password.to_string()).await).to_string()
     });
     forget(tc);
     forget(rt);
@@ -105,4 +113,533 @@ pub extern "C" fn get_tunnel_status(rt: i64, context_ptr: i64) -> i32 {
     forget(tc);
     forget(rt);
     result
-}
\ No newline at end of file
+}
+
+/// 注册隧道状态事件回调 事件以json字符串传入，回调返回后字符串即被释放
+/// 在运行时的线程上调用，传空指针取消回调
+#[no_mangle]
+pub extern "C" fn set_tunnel_event_callback(rt: i64, context_ptr: i64, callback: Option<extern "C" fn(*const c_char)>) {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    rt.block_on(async move {
+        let listener: Option<Box<dyn Fn(TunnelEvent) + Send + Sync>> = match callback {
+            Some(callback) => {
+                Some(Box::new(move |event: TunnelEvent| {
+                    let json = CString::new(event_json(&event)).unwrap();
+                    callback(json.as_ptr());
+                }))
+            }
+            None => { None }
+        };
+        context_clone.set_event_listener(listener).await;
+    });
+
+    forget(tc);
+    forget(rt);
+}
+
+#[no_mangle]
+pub extern "C" fn set_tunnel_cipher_suite(rt: i64, context_ptr: i64, cipher_suite: i32) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        match CipherSuite::from_byte(cipher_suite as u8) {
+            Some(cipher_suite) => {
+                context_clone.set_cipher_suite(cipher_suite).await;
+                "".to_string()
+            }
+            None => {
+                format!("Unknown cipher suite: {}", cipher_suite)
+            }
+        }
+    });
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn set_tunnel_compression(rt: i64, context_ptr: i64, compression: i32) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        match Compression::from_index(compression) {
+            Some(compression) => {
+                context_clone.set_compression(compression).await;
+                "".to_string()
+            }
+            None => {
+                format!("Unknown compression: {}", compression)
+            }
+        }
+    });
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn get_tunnel_compression(rt: i64, context_ptr: i64) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let upload = context_clone.get_tunnel_upload_compression().await;
+        let download = context_clone.get_tunnel_download_compression().await;
+        json!({
+            "upload_original": upload.original,
+            "upload_compressed": upload.compressed,
+            "upload_ratio": upload.ratio(),
+            "download_original": download.original,
+            "download_compressed": download.compressed,
+            "download_ratio": download.ratio(),
+        }).to_string()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+/// 服务端不支持版本协商时是否按旧版本握手 enable为0时不允许，之后添加的服务器生效
+#[no_mangle]
+pub extern "C" fn set_tunnel_legacy_handshake(rt: i64, context_ptr: i64, enable: i32) {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    rt.block_on(async move {
+        context_clone.set_legacy_handshake(enable != 0).await;
+    });
+
+    forget(tc);
+    forget(rt);
+}
+
+#[no_mangle]
+pub extern "C" fn get_tunnel_reconnect_attempts(rt: i64, context_ptr: i64) -> i32 {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        context_clone.get_reconnect_attempts().await as i32
+    });
+
+    forget(tc);
+    forget(rt);
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn get_tunnel_last_error(rt: i64, context_ptr: i64) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        context_clone.get_last_error().await.unwrap_or_default()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn set_tunnel_heartbeat(rt: i64, context_ptr: i64, interval_secs: u32, max_missed: u32) {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    rt.block_on(async move {
+        context_clone.set_heartbeat(Duration::from_secs(interval_secs as u64), max_missed).await;
+    });
+
+    forget(tc);
+    forget(rt);
+}
+
+/// 设置全局限速 字节每秒，0表示不限制
+#[no_mangle]
+pub extern "C" fn set_rate_limit(rt: i64, context_ptr: i64, upload: u64, download: u64) {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    tc.set_rate_limit(RateLimit { upload, download });
+
+    forget(tc);
+    forget(rt);
+}
+
+/// 设置每个连接的限速 字节每秒，0表示不限制
+#[no_mangle]
+pub extern "C" fn set_connection_rate_limit(rt: i64, context_ptr: i64, upload: u64, download: u64) {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    rt.block_on(async move {
+        context_clone.set_connection_rate_limit(RateLimit { upload, download }).await;
+    });
+
+    forget(tc);
+    forget(rt);
+}
+
+#[no_mangle]
+pub extern "C" fn get_tunnel_rtt_summary(rt: i64, context_ptr: i64) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let summary = context_clone.get_tunnel_rtt_summary().await;
+        json!({
+            "samples": summary.samples,
+            "last": summary.last,
+            "min": summary.min,
+            "avg": summary.avg,
+            "p95": summary.p95,
+            "jitter": summary.jitter,
+        }).to_string()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn get_traffic_stats(rt: i64, context_ptr: i64) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let snapshot_json = |snapshot: TrafficSnapshot| {
+            json!({
+                "connections": snapshot.connections,
+                "upload_bytes": snapshot.upload_bytes,
+                "download_bytes": snapshot.download_bytes,
+                "upload_packets": snapshot.upload_packets,
+                "download_packets": snapshot.download_packets,
+            })
+        };
+        let connections: Vec<_> = context_clone.get_connection_traffic().await.iter().map(|traffic| {
+            let snapshot = traffic.snapshot();
+            json!({
+                "source": traffic.source_addr,
+                "target": traffic.target_addr,
+                "kind": traffic.kind.as_str(),
+                "upload_bytes": snapshot.upload_bytes,
+                "download_bytes": snapshot.download_bytes,
+                "upload_packets": snapshot.upload_packets,
+                "download_packets": snapshot.download_packets,
+                "limit": rate_limit_json(traffic.limiter.snapshot()),
+            })
+        }).collect();
+        let rules: Vec<_> = context_clone.get_rule_rate_limits().await.into_iter().map(|(domain, snapshot)| {
+            let mut value = rate_limit_json(snapshot);
+            value["domain"] = json!(domain);
+            value
+        }).collect();
+        let connection_limit = context_clone.get_connection_rate_limit().await;
+        json!({
+            "tunnel": snapshot_json(context_clone.get_traffic(TrafficKind::Tunnel)),
+            "direct": snapshot_json(context_clone.get_traffic(TrafficKind::Direct)),
+            "reject": snapshot_json(context_clone.get_traffic(TrafficKind::Reject)),
+            "connections": connections,
+            "limits": {
+                "global": rate_limit_json(context_clone.get_rate_limit()),
+                "connection": {
+                    "upload": connection_limit.upload,
+                    "download": connection_limit.download,
+                },
+                "rules": rules,
+            },
+        }).to_string()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn add_tunnel_server(rt: i64, context_ptr: i64, name: *const c_char, host: *const c_char, port: u32, password: *const c_char) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let name = unsafe { CStr::from_ptr(name).to_string_lossy() };
+        let host = unsafe { CStr::from_ptr(host).to_string_lossy() };
+        let password = unsafe { CStr::from_ptr(password).to_string_lossy() };
+        result_json(&context_clone.add_tunnel_server(name.to_string(), host.to_string(), port as u16, password.to_string()).await).to_string()
+    });
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn remove_tunnel_server(rt: i64, context_ptr: i64, name: *const c_char) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let name = unsafe { CStr::from_ptr(name).to_string_lossy() };
+        result_json(&context_clone.remove_tunnel_server(&name).await).to_string()
+    });
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn get_tunnel_servers(rt: i64, context_ptr: i64) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let servers: Vec<_> = context_clone.get_tunnel_servers().await.into_iter().map(|server| {
+            json!({
+                "name": server.name,
+                "host": server.host,
+                "port": server.port,
+                "status": server.status.as_index(),
+                "selected": server.selected,
+                "ping": server.rtt.last,
+                "avg": server.rtt.avg,
+                "jitter": server.rtt.jitter,
+            })
+        }).collect();
+        json!(servers).to_string()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+/// 通过隧道服务端解析域名 返回json，成功时带records
+#[no_mangle]
+pub extern "C" fn resolve_remote(rt: i64, context_ptr: i64, host: *const c_char) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let host = unsafe { CStr::from_ptr(host).to_string_lossy() };
+        let result = context_clone.resolve_remote(&host).await;
+        let mut value = result_json(&result);
+        if let Ok(records) = result {
+            let records: Vec<_> = records.into_iter().map(|record| {
+                json!({
+                    "ip": record.addr.to_string(),
+                    "ttl": record.ttl,
+                })
+            }).collect();
+            value["records"] = json!(records);
+        }
+        value.to_string()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+#[no_mangle]
+pub extern "C" fn set_tunnel_group_strategy(rt: i64, context_ptr: i64, strategy: i32) {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    rt.block_on(async move {
+        context_clone.set_group_strategy(GroupStrategy::from_index(strategy)).await;
+    });
+
+    forget(tc);
+    forget(rt);
+}
+
+/// 设置隧道TLS 之后连接或添加的服务器生效
+/// enable为0时使用TCP，sni和ca_file为空时使用默认值，spki_pins和alpn用逗号分隔
+#[no_mangle]
+pub extern "C" fn set_tunnel_tls(rt: i64, context_ptr: i64, enable: i32, sni: *const c_char, ca_file: *const c_char, spki_pins: *const c_char, alpn: *const c_char) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        if enable == 0 {
+            context_clone.set_transport(Arc::new(TcpTransport)).await;
+            return "".to_string();
+        }
+        match tls_config(sni, ca_file, spki_pins, alpn) {
+            Ok(config) => {
+                context_clone.set_transport(Arc::new(TlsTransport { config })).await;
+                "".to_string()
+            }
+            Err(e) => { e }
+        }
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+/// 设置隧道使用WebSocket 之后连接或添加的服务器生效
+/// host为空时使用服务器地址，tls不为0时使用wss，TLS参数同set_tunnel_tls
+#[no_mangle]
+pub extern "C" fn set_tunnel_websocket(rt: i64, context_ptr: i64, path: *const c_char, host: *const c_char, tls: i32, sni: *const c_char, ca_file: *const c_char, spki_pins: *const c_char, alpn: *const c_char) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        let path = unsafe { CStr::from_ptr(path).to_string_lossy() }.to_string();
+        let host = unsafe { CStr::from_ptr(host).to_string_lossy() }.to_string();
+        let tls = if tls == 0 {
+            None
+        } else {
+            match tls_config(sni, ca_file, spki_pins, alpn) {
+                Ok(config) => { Some(config) }
+                Err(e) => { return e; }
+            }
+        };
+        let config = WsConfig {
+            path,
+            host: if host.is_empty() { None } else { Some(host) },
+            tls,
+        };
+        context_clone.set_transport(Arc::new(WsTransport { config })).await;
+        "".to_string()
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+/// 设置隧道使用QUIC 之后连接或添加的服务器生效，端口为服务端的QUIC端口
+/// 每个连接走单独的QUIC流，UDP数据走QUIC数据报；TLS参数同set_tunnel_tls，alpn为空时使用flyshadow
+#[no_mangle]
+pub extern "C" fn set_tunnel_quic(rt: i64, context_ptr: i64, sni: *const c_char, ca_file: *const c_char, spki_pins: *const c_char, alpn: *const c_char) -> *mut c_char {
+    let rt = unsafe { Box::from_raw(rt as *mut Runtime) };
+    let tc = unsafe { Box::from_raw(context_ptr as *mut Arc<TunnelContext>) };
+
+    let context_clone = Arc::clone(tc.as_ref());
+
+    let result = rt.block_on(async move {
+        match tls_config(sni, ca_file, spki_pins, alpn) {
+            Ok(config) => {
+                context_clone.set_transport(Arc::new(QuicTransport { config })).await;
+                "".to_string()
+            }
+            Err(e) => { e }
+        }
+    });
+
+    forget(tc);
+    forget(rt);
+    return CString::new(result).unwrap().into_raw();
+}
+
+/// 解析TLS参数
+fn tls_config(sni: *const c_char, ca_file: *const c_char, spki_pins: *const c_char, alpn: *const c_char) -> Result<TlsConfig, String> {
+    let sni = unsafe { CStr::from_ptr(sni).to_string_lossy() }.to_string();
+    let ca_file = unsafe { CStr::from_ptr(ca_file).to_string_lossy() }.to_string();
+    let spki_pins = unsafe { CStr::from_ptr(spki_pins).to_string_lossy() }.to_string();
+    let alpn = unsafe { CStr::from_ptr(alpn).to_string_lossy() }.to_string();
+
+    let mut pins = vec![];
+    for pin in spki_pins.split(',').filter(|pin| !pin.trim().is_empty()) {
+        pins.push(TlsConfig::parse_pin(pin)?);
+    }
+    Ok(TlsConfig {
+        sni: if sni.is_empty() { None } else { Some(sni) },
+        ca_file: if ca_file.is_empty() { None } else { Some(ca_file) },
+        spki_pins: pins,
+        alpn: alpn.split(',').map(|protocol| protocol.trim().to_string()).filter(|protocol| !protocol.is_empty()).collect(),
+    })
+}
+
+/// 隧道事件转成json
+fn event_json(event: &TunnelEvent) -> String {
+    let mut value = json!({
+        "server": event.server,
+        "event": event.kind.as_str(),
+    });
+    match &event.kind {
+        TunnelEventKind::Connecting { host, port } => {
+            value["host"] = json!(host);
+            value["port"] = json!(port);
+        }
+        TunnelEventKind::LoginSuccess => {}
+        TunnelEventKind::LoginFail { reason } | TunnelEventKind::ProtocolError { reason } => {
+            value["reason"] = json!(reason);
+        }
+        TunnelEventKind::RttUpdate { rtt } => {
+            value["rtt"] = json!(rtt);
+        }
+        TunnelEventKind::ReconnectScheduled { attempt, delay_ms } => {
+            value["attempt"] = json!(attempt);
+            value["delay_ms"] = json!(delay_ms);
+        }
+        TunnelEventKind::Closed { reason } => {
+            value["reason"] = json!(reason);
+        }
+    }
+    value.to_string()
+}
+
+/// 限速状态转成json
+fn rate_limit_json(snapshot: RateLimitSnapshot) -> serde_json::Value {
+    json!({
+        "upload": snapshot.upload.rate,
+        "download": snapshot.download.rate,
+        "upload_tokens": snapshot.upload.tokens,
+        "download_tokens": snapshot.download.tokens,
+        "upload_wait_ms": snapshot.upload.wait_ms,
+        "download_wait_ms": snapshot.download.wait_ms,
+    })
+}
+
+/// 执行结果转成json 成功时code为0
+fn result_json<T>(result: &Result<T, TunnelError>) -> serde_json::Value {
+    match result {
+        Ok(_) => { json!({ "code": 0, "message": "" }) }
+        Err(e) => { json!({ "code": e.code(), "message": e.to_string() }) }
+    }
+}
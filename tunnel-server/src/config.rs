@@ -0,0 +1,96 @@
+use tunnel::tunnel::compress::Compression;
+
+/// 默认监听地址
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:6001";
+
+pub const USAGE: &str = "Usage: tunnel-server --password <password> [--listen <address>] [--compression none|lz4|zstd] [--quic <address> --cert <pem> --key <pem>]";
+
+/// 服务端配置
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// 监听地址
+    pub listen: String,
+    /// 客户端登录密码
+    pub password: String,
+    /// 下行数据的压缩算法 只在客户端支持压缩时生效
+    pub compression: Compression,
+    /// QUIC监听地址 为空时不监听QUIC
+    pub quic: Option<QuicConfig>,
+}
+
+/// QUIC监听配置 证书和私钥为PEM文件
+#[derive(Clone, Debug)]
+pub struct QuicConfig {
+    pub listen: String,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+impl ServerConfig {
+    pub fn new(listen: String, password: String, compression: Compression) -> ServerConfig {
+        ServerConfig { listen, password, compression, quic: None }
+    }
+
+    /// 解析命令行参数 不包含程序名
+    pub fn from_args(args: impl IntoIterator<Item=String>) -> Result<ServerConfig, String> {
+        let mut listen = DEFAULT_LISTEN.to_string();
+        let mut password = None;
+        let mut compression = Compression::None;
+        let mut quic_listen = None;
+        let mut cert_file = None;
+        let mut key_file = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--listen" => { listen = value()?; }
+                "--password" => { password = Some(value()?); }
+                "--compression" => {
+                    compression = match value()?.as_str() {
+                        "none" => { Compression::None }
+                        "lz4" => { Compression::Lz4 }
+                        "zstd" => { Compression::Zstd }
+                        other => { return Err(format!("Unknown compression: {}", other)); }
+                    };
+                }
+                "--quic" => { quic_listen = Some(value()?); }
+                "--cert" => { cert_file = Some(value()?); }
+                "--key" => { key_file = Some(value()?); }
+                _ => { return Err(format!("Unknown argument: {}", arg)); }
+            }
+        }
+
+        let mut config = match password {
+            Some(password) if !password.is_empty() => { ServerConfig::new(listen, password, compression) }
+            _ => { return Err("Missing --password".to_string()); }
+        };
+        config.quic = match (quic_listen, cert_file, key_file) {
+            (Some(listen), Some(cert_file), Some(key_file)) => { Some(QuicConfig { listen, cert_file, key_file }) }
+            (Some(_), _, _) => { return Err("--quic requires --cert and --key".to_string()); }
+            (None, _, _) => { None }
+        };
+        Ok(config)
+    }
+}
+
+#[test]
+fn test_server_config() {
+    let args = ["--password", "secret", "--compression", "zstd"].map(String::from);
+    let config = ServerConfig::from_args(args).unwrap();
+    assert_eq!(config.listen, DEFAULT_LISTEN);
+    assert_eq!(config.password, "secret");
+    assert_eq!(config.compression, Compression::Zstd);
+    assert!(config.quic.is_none());
+
+    let args = ["--password", "a", "--quic", "0.0.0.0:6002", "--cert", "cert.pem", "--key", "key.pem"].map(String::from);
+    let quic = ServerConfig::from_args(args).unwrap().quic.unwrap();
+    assert_eq!(quic.listen, "0.0.0.0:6002");
+    assert_eq!(quic.cert_file, "cert.pem");
+    assert_eq!(quic.key_file, "key.pem");
+
+    assert!(ServerConfig::from_args(["--listen", "127.0.0.1:7000"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--password"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--password", "a", "--compression", "gzip"].map(String::from)).is_err());
+    assert!(ServerConfig::from_args(["--password", "a", "--quic", "0.0.0.0:6002"].map(String::from)).is_err());
+}
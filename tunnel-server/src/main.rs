@@ -0,0 +1,38 @@
+use std::process::exit;
+
+use tunnel_server::config::{ServerConfig, USAGE};
+use tunnel_server::server::Server;
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("error")).init();
+
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => { config }
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            exit(2);
+        }
+    };
+
+    let server = match Server::bind(config).await {
+        Ok(server) => { server }
+        Err(e) => {
+            eprintln!("Bind error: {}", e);
+            exit(1);
+        }
+    };
+    match server.local_addr() {
+        Ok(addr) => { println!("Tunnel server listening on {}", addr); }
+        Err(e) => { log::error!("Local addr error: {}", e); }
+    }
+    match server.quic_local_addr() {
+        Ok(Some(addr)) => { println!("Tunnel server listening on {} (quic)", addr); }
+        Ok(None) => {}
+        Err(e) => { log::error!("Quic local addr error: {}", e); }
+    }
+    if let Err(e) = server.run().await {
+        eprintln!("Server error: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,85 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use quinn::Endpoint;
+use tokio::net::TcpListener;
+use tokio::spawn;
+
+use tunnel::tunnel::quic::{server_config, split_connection};
+
+use crate::config::ServerConfig;
+use crate::session::Session;
+
+/// 隧道服务端 每个客户端连接一个会话
+pub struct Server {
+    listener: TcpListener,
+    /// 配置了QUIC监听时的端点
+    quic: Option<Endpoint>,
+    config: Arc<ServerConfig>,
+}
+
+impl Server {
+    pub async fn bind(config: ServerConfig) -> io::Result<Server> {
+        let listener = TcpListener::bind(&config.listen).await?;
+        let quic = match config.quic.as_ref() {
+            Some(quic) => {
+                let addr: SocketAddr = quic.listen.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Some(Endpoint::server(server_config(&quic.cert_file, &quic.key_file)?, addr)?)
+            }
+            None => { None }
+        };
+        Ok(Server { listener, quic, config: Arc::new(config) })
+    }
+
+    /// 实际监听的地址 监听端口为0时由系统分配
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// QUIC实际监听的地址 未配置QUIC时为空
+    pub fn quic_local_addr(&self) -> io::Result<Option<SocketAddr>> {
+        self.quic.as_ref().map(|endpoint| endpoint.local_addr()).transpose()
+    }
+
+    /// 接受客户端连接 直到监听出错
+    pub async fn run(self) -> io::Result<()> {
+        if let Some(endpoint) = self.quic {
+            spawn(accept_quic(endpoint, self.config.clone()));
+        }
+        loop {
+            let (stream, client_addr) = self.listener.accept().await?;
+            let config = self.config.clone();
+            spawn(async move {
+                log::error!("Client connect: {}", client_addr);
+                match Session::handle(stream, config).await {
+                    Ok(_) => { log::error!("Client disconnect: {}", client_addr); }
+                    Err(e) => { log::error!("Client {} error: {}", client_addr, e); }
+                }
+            });
+        }
+    }
+}
+
+/// 接受QUIC客户端连接 直到端点关闭
+async fn accept_quic(endpoint: Endpoint, config: Arc<ServerConfig>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let config = config.clone();
+        spawn(async move {
+            let client_addr = incoming.remote_address();
+            let connection = match incoming.await {
+                Ok(connection) => { connection }
+                Err(e) => {
+                    log::error!("Quic client {} handshake error: {}", client_addr, e);
+                    return;
+                }
+            };
+            log::error!("Quic client connect: {}", client_addr);
+            let (reader, writer) = split_connection(connection);
+            match Session::serve(Box::new(reader), Box::new(writer), config).await {
+                Ok(_) => { log::error!("Quic client disconnect: {}", client_addr); }
+                Err(e) => { log::error!("Quic client {} error: {}", client_addr, e); }
+            }
+        });
+    }
+}
@@ -0,0 +1,629 @@
+use std::collections::HashMap;
+use std::future::pending;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::spawn;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::{JoinHandle, spawn_blocking};
+use tokio::time::timeout;
+
+use tunnel::error::TunnelError;
+use tunnel::tunnel::cipher::{CipherSuite, FrameCipher, FrameDirection, random_salt, SessionKeys, transcript_hash};
+use tunnel::tunnel::codec::{FrameDecoder, FrameEncoder, peek_suite};
+use tunnel::tunnel::hello::{Capabilities, FRAME_COUNTER_VERSION, Hello, HelloAck};
+use tunnel::tunnel::resolve::{resolve_result_package, ResolveRecord, ResolveResult};
+use tunnel::tunnel::transport::{FrameRoute, split_stream, TransportReader, TransportWriter};
+use tunnel::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+use tunnel::tunnel::window::{ReceiveWindow, SendWindow, WINDOW_UPDATE_THRESHOLD, window_increment, window_update_package};
+
+use crate::config::ServerConfig;
+
+/// 握手和登录必须在这个时间内完成
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// 读缓冲区每次预留的空间
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// 从目标读取数据的缓冲区大小
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+/// 发往客户端的数据包队列长度
+const WRITE_QUEUE_SIZE: usize = 1024;
+/// 每个连接发往目标的队列长度
+const STREAM_QUEUE_SIZE: usize = 256;
+/// 解析域名的最长时间
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 系统解析器不返回TTL 解析结果统一使用这个有效期
+const RESOLVE_TTL: u32 = 60;
+/// 每个UDP连接最多记录的目标数
+const MAX_UDP_TARGETS: usize = 1024;
+/// UDP目标超过这个时间没有收发数据就不再转发它返回的数据
+const UDP_TARGET_IDLE: Duration = Duration::from_secs(120);
+
+/// 隧道上的一个连接
+struct Stream {
+    sender: StreamSender,
+    /// 向客户端发送数据的窗口
+    window: SendWindow,
+    /// 客户端发来还未归还的数据
+    receive: ReceiveWindow,
+    job: JoinHandle<()>,
+}
+
+/// 发往目标的数据
+enum StreamSender {
+    Tcp(Sender<Vec<u8>>),
+    /// UDP数据带目标地址
+    Udp(Sender<(String, Vec<u8>)>),
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.window.close();
+        self.job.abort();
+    }
+}
+
+/// 客户端会话
+/// 完成握手和登录后，按连接编号把数据转发到目标，并把目标返回的数据发回客户端
+pub struct Session {
+    reader: Box<dyn TransportReader>,
+    read_buffer: BytesMut,
+    /// 发往客户端的数据包 由写线程统一编码发送
+    sender: Sender<TunnelPackage>,
+    /// 是否开启了流量控制
+    flow_control: bool,
+    /// 传输方式是否多路复用 多路复用时UDP数据走数据报，不做流量控制
+    multiplexed: bool,
+    streams: HashMap<u32, Stream>,
+}
+
+impl Session {
+    /// 处理一个客户端TCP连接 直到连接断开或者协议错误
+    pub async fn handle(stream: TcpStream, config: Arc<ServerConfig>) -> Result<(), String> {
+        let _ = stream.set_nodelay(true);
+        let (reader, writer) = split_stream(stream);
+        Session::serve(reader, writer, config).await
+    }
+
+    /// 在已建立的传输连接上处理客户端会话
+    pub async fn serve(mut reader: Box<dyn TransportReader>, mut writer: Box<dyn TransportWriter>, config: Arc<ServerConfig>) -> Result<(), String> {
+        let mut read_buffer = BytesMut::new();
+
+        let (hello, hello_ack, transcript) = timeout(LOGIN_TIMEOUT, handshake(reader.as_mut(), writer.as_mut(), &mut read_buffer)).await
+            .map_err(|_| "握手超时".to_string())??;
+        // 新版本的会话密钥绑定双方实际收发的握手数据
+        // 拉伸密码比较耗时 放到阻塞线程，不影响其他会话
+        let session_keys = if hello_ack.version >= FRAME_COUNTER_VERSION {
+            let (config, client_salt, server_salt) = (config.clone(), hello.salt.clone(), hello_ack.salt.clone());
+            spawn_blocking(move || SessionKeys::derive(config.password.as_bytes(), &client_salt, &server_salt, &transcript)).await
+                .map_err(|e| e.to_string())??
+        } else {
+            SessionKeys::derive_legacy(config.password.as_bytes(), &hello.salt, &hello_ack.salt)?
+        };
+
+        // 客户端选择的加密套件写在登录帧里
+        let suite = timeout(LOGIN_TIMEOUT, async {
+            loop {
+                if let Some(suite) = peek_suite(&read_buffer) {
+                    return Ok(suite);
+                }
+                read_buffer.reserve(READ_BUFFER_SIZE);
+                if reader.read_buf(&mut read_buffer).await.map_err(|e| e.to_string())? == 0 {
+                    return Err("Client disconnect".to_string());
+                }
+            }
+        }).await.map_err(|_| "登录超时".to_string())??;
+        let suite = CipherSuite::from_byte(suite).ok_or(format!("未知的加密套件: {}", suite))?;
+        if suite == CipherSuite::ChaCha20Poly1305 && !hello_ack.capabilities.contains(Capabilities::CHACHA20_POLY1305) {
+            return Err("未协商的加密套件".to_string());
+        }
+        let mut decoder = FrameDecoder::new(FrameCipher::new(suite, &session_keys.client_key, FrameDirection::ClientToServer));
+        // 双方都支持多路复用时不同连接的帧才走不同的流
+        let multiplexed = writer.multiplexed() && hello_ack.capabilities.contains(Capabilities::MULTIPLEX);
+        if multiplexed {
+            decoder.set_unordered();
+        }
+        let mut encoder = FrameEncoder::new(FrameCipher::new(suite, &session_keys.server_key, FrameDirection::ServerToClient));
+        decoder.set_version(hello_ack.version);
+        encoder.set_version(hello_ack.version);
+        if hello_ack.capabilities.contains(Capabilities::COMPRESSION) {
+            encoder.set_compression(config.compression);
+        }
+
+        let (sender, receiver) = channel(WRITE_QUEUE_SIZE);
+        let writer_job = spawn(write_loop(writer, encoder, receiver, multiplexed));
+        let mut session = Session {
+            reader,
+            read_buffer,
+            sender,
+            flow_control: hello_ack.capabilities.contains(Capabilities::FLOW_CONTROL),
+            multiplexed,
+            streams: HashMap::new(),
+        };
+        let result = session.run(decoder, session_keys, hello_ack.version).await;
+
+        // 出错时先通知客户端原因，等写线程发送完再断开
+        if let Err(e) = &result {
+            let _ = session.sender.send(TunnelPackage::protocol_error(0, e)).await;
+        }
+        session.streams.clear();
+        drop(session);
+        let _ = writer_job.await;
+        result
+    }
+
+    async fn run(&mut self, mut decoder: FrameDecoder, session_keys: SessionKeys, version: u8) -> Result<(), String> {
+        // 登录
+        let package = timeout(LOGIN_TIMEOUT, self.read_package(&mut decoder)).await
+            .map_err(|_| "登录超时".to_string())?
+            .map_err(|e| e.to_string())?;
+        let package = match package {
+            Some(package) => { package }
+            None => { return Ok(()); }
+        };
+        if package.cmd != PackageCmd::Login {
+            return Err("未登录".to_string());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs();
+        // 旧版本的登录不带时间戳
+        let verified = if version >= FRAME_COUNTER_VERSION {
+            session_keys.verify_login_request(package.data.as_deref().unwrap_or_default(), now)
+        } else {
+            session_keys.verify_legacy_login_proof(package.data.as_deref().unwrap_or_default())
+        };
+        if let Err(e) = verified {
+            log::error!("Login fail: {}", e);
+            let _ = self.send(TunnelPackage::new(PackageCmd::LoginFail, PackageProtocol::TCP, 0, None, None, Some(e.into_bytes()))).await;
+            return Ok(());
+        }
+        self.send(TunnelPackage::new(PackageCmd::LoginSuccess, PackageProtocol::TCP, 0, None, None, None)).await?;
+
+        while let Some(package) = self.read_package(&mut decoder).await.map_err(|e| e.to_string())? {
+            match package.cmd {
+                PackageCmd::PING => {
+                    self.send(TunnelPackage::new(PackageCmd::PONG, PackageProtocol::TCP, 0, None, None, None)).await?;
+                }
+                PackageCmd::NewConnect => {
+                    self.new_connect(package);
+                }
+                PackageCmd::TData => {
+                    self.stream_data(package).await?;
+                }
+                PackageCmd::CloseConnect => {
+                    self.streams.remove(&package.stream_id);
+                }
+                PackageCmd::Resolve => {
+                    match package.target_address {
+                        Some(host) => { spawn(resolve(package.stream_id, host, self.sender.clone())); }
+                        None => { log::error!("Resolve without host: {}", package.stream_id); }
+                    }
+                }
+                PackageCmd::WindowUpdate => {
+                    if let (Some(stream), Some(increment)) = (self.streams.get(&package.stream_id), window_increment(&package)) {
+                        stream.window.grant(increment);
+                    }
+                }
+                // 客户端认为这个连接违反了流量控制 只关闭这个连接
+                PackageCmd::ProtocolError if package.stream_id != 0 => {
+                    log::error!("Stream {} protocol error", package.stream_id);
+                    self.streams.remove(&package.stream_id);
+                }
+                PackageCmd::Login => {
+                    return Err("重复登录".to_string());
+                }
+                _ => {
+                    log::error!("Unexpected package: {:?}", package.cmd);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取下一个数据包 连接断开时返回None
+    async fn read_package(&mut self, decoder: &mut FrameDecoder) -> Result<Option<TunnelPackage>, TunnelError> {
+        read_package(self.reader.as_mut(), &mut self.read_buffer, decoder).await
+    }
+
+    async fn send(&self, package: TunnelPackage) -> Result<(), String> {
+        self.sender.send(package).await.map_err(|_| "Client disconnect".to_string())
+    }
+
+    /// 建立TCP连接 连接成功前收到的数据先排队
+    fn new_connect(&mut self, package: TunnelPackage) {
+        let stream_id = package.stream_id;
+        let target = match package.target_address {
+            Some(target) => { target }
+            None => {
+                log::error!("NewConnect without target: {}", stream_id);
+                return;
+            }
+        };
+        log::error!("NewConnect {} -> {} source_addr: {:?}", stream_id, target, package.source_address);
+        let (sender, receiver) = channel(STREAM_QUEUE_SIZE);
+        let window = SendWindow::default();
+        let receive = ReceiveWindow::new(self.flow_control, self.multiplexed);
+        let job = spawn(relay_tcp(stream_id, target, receiver, self.sender.clone(), window.clone(), receive.clone(), self.flow_control));
+        self.streams.insert(stream_id, Stream { sender: StreamSender::Tcp(sender), window, receive, job });
+    }
+
+    /// 转发客户端数据 UDP连接第一次发送数据时创建
+    async fn stream_data(&mut self, package: TunnelPackage) -> Result<(), String> {
+        let stream_id = package.stream_id;
+        let data = package.data.unwrap_or_default();
+        if matches!(package.protocol, PackageProtocol::UDP) && !self.streams.contains_key(&stream_id) {
+            let (sender, receiver) = channel(STREAM_QUEUE_SIZE);
+            let window = SendWindow::default();
+            let receive = ReceiveWindow::new(self.flow_control, self.multiplexed);
+            // 数据报可能丢失 丢失的数据不会归还窗口
+            let job = spawn(relay_udp(stream_id, receiver, self.sender.clone(), window.clone(), receive.clone(), self.flow_control && !self.multiplexed));
+            self.streams.insert(stream_id, Stream { sender: StreamSender::Udp(sender), window, receive, job });
+        }
+
+        // 客户端超出接收窗口继续发送 关闭这个连接
+        if let Some(stream) = self.streams.get(&stream_id) {
+            if let Err(e) = stream.receive.receive(&package.protocol, data.len()) {
+                log::error!("Stream {} reset: {}", stream_id, e);
+                self.streams.remove(&stream_id);
+                return self.send(TunnelPackage::protocol_error(stream_id, &e.to_string())).await;
+            }
+        }
+
+        match self.streams.get(&stream_id).map(|stream| &stream.sender) {
+            // 目标写入慢时等待 不再读取隧道上的数据
+            Some(StreamSender::Tcp(sender)) => {
+                let _ = sender.send(data).await;
+            }
+            // 队列已满时丢弃UDP数据
+            Some(StreamSender::Udp(sender)) => {
+                match package.target_address {
+                    Some(target) => {
+                        if let Err(TrySendError::Full(_)) = sender.try_send((target, data)) {
+                            log::error!("Udp queue full, drop data: {}", stream_id);
+                        }
+                    }
+                    None => { log::error!("Udp data without target: {}", stream_id); }
+                }
+            }
+            None => {
+                log::error!("Unknown stream: {}", stream_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 从传输连接读取下一个数据包 连接断开时返回None
+async fn read_package(reader: &mut dyn TransportReader, read_buffer: &mut BytesMut, decoder: &mut FrameDecoder) -> Result<Option<TunnelPackage>, TunnelError> {
+    loop {
+        if let Some(package) = decoder.decode(read_buffer)? {
+            return Ok(Some(package));
+        }
+        read_buffer.reserve(READ_BUFFER_SIZE);
+        if reader.read_buf(read_buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// 握手 返回客户端和服务端的握手数据，以及握手记录的哈希
+async fn handshake(reader: &mut dyn TransportReader, writer: &mut dyn TransportWriter, read_buffer: &mut BytesMut) -> Result<(Hello, HelloAck, Vec<u8>), String> {
+    let mut decoder = FrameDecoder::plain();
+    let mut encoder = FrameEncoder::plain();
+    let mut write_buffer = BytesMut::new();
+
+    let package = match read_package(reader, read_buffer, &mut decoder).await.map_err(|e| e.to_string())? {
+        Some(package) => { package }
+        None => { return Err("Client disconnect".to_string()); }
+    };
+    if package.cmd != PackageCmd::Handshake {
+        return Err("握手数据包错误".to_string());
+    }
+    let hello_data = package.data.unwrap_or_default();
+    let hello = Hello::from_byte_array(&hello_data)?;
+
+    let reply = match hello.select_version() {
+        Some(version) => {
+            let mut hello_ack = HelloAck {
+                version,
+                capabilities: hello.select_capabilities(version),
+                salt: random_salt()?.to_vec(),
+            };
+            // 客户端只支持旧版本时标记降级 客户端握手被篡改时新客户端可以发现
+            hello_ack.mark_downgrade();
+            Ok(hello_ack)
+        }
+        None => { Err(format!("不支持的协议版本: {}-{}", hello.min_version, hello.max_version)) }
+    };
+    let package = match &reply {
+        Ok(hello_ack) => { TunnelPackage::new(PackageCmd::HandshakeAck, PackageProtocol::TCP, 0, None, None, Some(hello_ack.to_byte_array())) }
+        Err(e) => { TunnelPackage::protocol_error(0, e) }
+    };
+    encoder.encode(&package, &mut write_buffer).map_err(|e| e.to_string())?;
+    writer.write_frame(&write_buffer).await.map_err(|e| e.to_string())?;
+    let hello_ack = reply?;
+    let transcript = transcript_hash(&hello_data, &hello_ack.to_byte_array())?;
+    Ok((hello, hello_ack, transcript))
+}
+
+/// 写线程 把排队的数据包合并编码后一次写入
+/// 多路复用时每个数据包按路由单独写入
+async fn write_loop(mut writer: Box<dyn TransportWriter>, mut encoder: FrameEncoder, mut receiver: Receiver<TunnelPackage>, multiplexed: bool) {
+    let mut write_buffer = BytesMut::new();
+    while let Some(package) = receiver.recv().await {
+        write_buffer.clear();
+        if multiplexed {
+            if let Err(e) = encoder.encode(&package, &mut write_buffer) {
+                log::error!("Encode package error: {}", e);
+                return;
+            }
+            if let Err(e) = writer.write_routed(FrameRoute::of(&package), &write_buffer).await {
+                log::error!("Write to client error: {}", e);
+                return;
+            }
+            continue;
+        }
+        let mut next = Some(package);
+        while let Some(package) = next {
+            if let Err(e) = encoder.encode(&package, &mut write_buffer) {
+                log::error!("Encode package error: {}", e);
+                return;
+            }
+            next = if write_buffer.len() < READ_BUFFER_SIZE { receiver.try_recv().ok() } else { None };
+        }
+        if let Err(e) = writer.write_frame(&write_buffer).await {
+            log::error!("Write to client error: {}", e);
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// 累计写入目标的数据，超过阈值后向客户端归还窗口
+/// 归还的大小和客户端消耗的信用一致
+struct WindowUpdater {
+    stream_id: u32,
+    window: ReceiveWindow,
+    consumed: u32,
+}
+
+impl WindowUpdater {
+    async fn consume(&mut self, protocol: &PackageProtocol, len: usize, sender: &Sender<TunnelPackage>) {
+        self.consumed += self.window.cost(protocol, len);
+        if self.consumed >= WINDOW_UPDATE_THRESHOLD {
+            let _ = sender.send(window_update_package(self.stream_id, self.consumed)).await;
+            self.window.release(self.consumed);
+            self.consumed = 0;
+        }
+    }
+}
+
+/// 解析域名 解析失败时也返回结果
+async fn resolve(request_id: u32, host: String, sender: Sender<TunnelPackage>) {
+    let result = match timeout(RESOLVE_TIMEOUT, lookup_host((host.as_str(), 0))).await {
+        Ok(Ok(addrs)) => {
+            let mut records: Vec<ResolveRecord> = vec![];
+            for addr in addrs {
+                if !records.iter().any(|record| record.addr == addr.ip()) {
+                    records.push(ResolveRecord { addr: addr.ip(), ttl: RESOLVE_TTL });
+                }
+            }
+            ResolveResult::Success(records)
+        }
+        Ok(Err(e)) => { ResolveResult::Fail(e.to_string()) }
+        Err(_) => { ResolveResult::Fail("解析超时".to_string()) }
+    };
+    log::error!("Resolve {} {:?}", host, result);
+    let _ = sender.send(resolve_result_package(request_id, &host, &result)).await;
+}
+
+/// TCP转发 目标断开或连接失败时通知客户端关闭连接
+async fn relay_tcp(stream_id: u32,
+                   target: String,
+                   mut receiver: Receiver<Vec<u8>>,
+                   sender: Sender<TunnelPackage>,
+                   window: SendWindow,
+                   receive: ReceiveWindow,
+                   flow_control: bool) {
+    let close = TunnelPackage::new(PackageCmd::CloseConnect, PackageProtocol::TCP, stream_id, None, None, None);
+    let target_stream = match TcpStream::connect(&target).await {
+        Ok(target_stream) => { target_stream }
+        Err(e) => {
+            log::error!("Connect target {} error: {}", target, e);
+            let _ = sender.send(close).await;
+            return;
+        }
+    };
+    let _ = target_stream.set_nodelay(true);
+    let (mut target_reader, mut target_writer) = target_stream.into_split();
+
+    // 上行 客户端关闭连接后结束
+    let upload_sender = sender.clone();
+    let upload = spawn(async move {
+        let mut window_updater = WindowUpdater { stream_id, window: receive, consumed: 0 };
+        while let Some(data) = receiver.recv().await {
+            if target_writer.write_all(&data).await.is_err() {
+                break;
+            }
+            window_updater.consume(&PackageProtocol::TCP, data.len(), &upload_sender).await;
+        }
+        let _ = target_writer.shutdown().await;
+    });
+
+    // 下行
+    let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let n = match target_reader.read(&mut buffer).await {
+            Ok(0) | Err(_) => { break; }
+            Ok(n) => { n }
+        };
+        if flow_control && window.acquire(n).await.is_err() {
+            break;
+        }
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, stream_id, None, None, Some(buffer[..n].to_vec()));
+        if sender.send(package).await.is_err() {
+            break;
+        }
+    }
+    let _ = sender.send(close).await;
+    upload.abort();
+}
+
+/// UDP转发使用的套接字 按目标地址族在第一次发送时绑定
+#[derive(Default)]
+struct UdpSockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl UdpSockets {
+    /// 返回发往目标使用的套接字 IPv6目标绑定[::]:0
+    async fn get(&mut self, address: &SocketAddr) -> std::io::Result<&UdpSocket> {
+        let (socket, bind) = if address.is_ipv4() { (&mut self.v4, "0.0.0.0:0") } else { (&mut self.v6, "[::]:0") };
+        if socket.is_none() {
+            *socket = Some(UdpSocket::bind(bind).await?);
+        }
+        Ok(socket.as_ref().unwrap())
+    }
+}
+
+/// UDP连接发送过的目标 解析后的地址对应客户端发送时的目标地址
+/// 空闲超时的目标不再转发，超过上限时先删除空闲的，再删除最久没有使用的
+struct UdpTargets {
+    targets: HashMap<SocketAddr, (String, Instant)>,
+    capacity: usize,
+    idle: Duration,
+}
+
+impl UdpTargets {
+    fn new(capacity: usize, idle: Duration) -> UdpTargets {
+        UdpTargets { targets: HashMap::new(), capacity, idle }
+    }
+
+    fn insert(&mut self, address: SocketAddr, target: String) {
+        let now = Instant::now();
+        if !self.targets.contains_key(&address) && self.targets.len() >= self.capacity {
+            let idle = self.idle;
+            self.targets.retain(|_, (_, last)| now.duration_since(*last) < idle);
+            if self.targets.len() >= self.capacity {
+                if let Some(oldest) = self.targets.iter().min_by_key(|(_, (_, last))| *last).map(|(address, _)| *address) {
+                    self.targets.remove(&oldest);
+                }
+            }
+        }
+        self.targets.insert(address, (target, now));
+    }
+
+    /// 目标地址 收到数据时刷新使用时间
+    fn get(&mut self, address: &SocketAddr) -> Option<String> {
+        let now = Instant::now();
+        let (target, last) = self.targets.get_mut(address)?;
+        if now.duration_since(*last) >= self.idle {
+            self.targets.remove(address);
+            return None;
+        }
+        *last = now;
+        Some(target.clone())
+    }
+}
+
+/// 从套接字接收数据 套接字还未绑定时一直等待
+async fn recv_from(socket: &Option<UdpSocket>, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => { socket.recv_from(buffer).await }
+        None => { pending().await }
+    }
+}
+
+/// UDP转发 目标返回的数据带上客户端发送时的目标地址
+async fn relay_udp(stream_id: u32,
+                   mut receiver: Receiver<(String, Vec<u8>)>,
+                   sender: Sender<TunnelPackage>,
+                   window: SendWindow,
+                   receive: ReceiveWindow,
+                   flow_control: bool) {
+    let mut sockets = UdpSockets::default();
+    let mut targets = UdpTargets::new(MAX_UDP_TARGETS, UDP_TARGET_IDLE);
+    let mut window_updater = WindowUpdater { stream_id, window: receive, consumed: 0 };
+    let mut buffer_v4 = vec![0u8; 65536];
+    let mut buffer_v6 = vec![0u8; 65536];
+    loop {
+        let (n, address, buffer) = select! {
+            message = receiver.recv() => {
+                let (target, data) = match message {
+                    Some(message) => { message }
+                    None => { break; }
+                };
+                let address = match lookup_host(&target).await.map(|mut addresses| addresses.next()) {
+                    Ok(Some(address)) => { address }
+                    _ => {
+                        log::error!("Resolve udp target {} fail", target);
+                        continue;
+                    }
+                };
+                match sockets.get(&address).await {
+                    Ok(socket) => {
+                        if let Err(e) = socket.send_to(&data, address).await {
+                            log::error!("Send udp to {} error: {}", target, e);
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Bind udp socket for {} error: {}", target, e);
+                    }
+                }
+                targets.insert(address, target);
+                window_updater.consume(&PackageProtocol::UDP, data.len(), &sender).await;
+                continue;
+            }
+            result = recv_from(&sockets.v4, &mut buffer_v4) => {
+                match result {
+                    Ok((n, address)) => { (n, address, &buffer_v4) }
+                    Err(_) => { break; }
+                }
+            }
+            result = recv_from(&sockets.v6, &mut buffer_v6) => {
+                match result {
+                    Ok((n, address)) => { (n, address, &buffer_v6) }
+                    Err(_) => { break; }
+                }
+            }
+        };
+        let target = match targets.get(&address) {
+            Some(target) => { target }
+            None => { continue; }
+        };
+        if flow_control && window.acquire(n).await.is_err() {
+            break;
+        }
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::UDP, stream_id, None, Some(target), Some(buffer[..n].to_vec()));
+        if sender.send(package).await.is_err() {
+            break;
+        }
+    }
+}
+
+
+#[test]
+fn test_udp_targets() {
+    let address = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+    let mut targets = UdpTargets::new(2, Duration::from_secs(60));
+    targets.insert(address(1), "a:1".to_string());
+    targets.insert(address(2), "b:2".to_string());
+    std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(targets.get(&address(1)), Some("a:1".to_string()));
+    // 超过上限时删除最久没有使用的目标
+    targets.insert(address(3), "c:3".to_string());
+    assert_eq!(targets.get(&address(2)), None);
+    assert_eq!(targets.get(&address(1)), Some("a:1".to_string()));
+    assert_eq!(targets.get(&address(3)), Some("c:3".to_string()));
+
+    // 空闲超时的目标不再转发
+    let mut targets = UdpTargets::new(2, Duration::ZERO);
+    targets.insert(address(1), "a:1".to_string());
+    assert_eq!(targets.get(&address(1)), None);
+    assert!(targets.targets.is_empty());
+}
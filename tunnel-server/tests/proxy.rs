@@ -0,0 +1,176 @@
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::spawn;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use tunnel::context::traffic::TrafficKind;
+use tunnel::tunnel::compress::Compression;
+
+use crate::support::*;
+
+mod support;
+
+#[tokio::test]
+async fn test_http_connect() {
+    let tunnel_port = start_tunnel_server(Compression::None).await;
+    let echo = start_tcp_echo().await;
+    let (context, proxy) = start_proxy(tunnel_port).await;
+
+    let mut stream = connect_proxy(&proxy).await;
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes()).await.unwrap();
+    assert_eq!(read_http_header(&mut stream).await, "HTTP/1.1 200 Connection Established\r\n\r\n");
+
+    for i in 0..3u8 {
+        let data = vec![i; 1000];
+        stream.write_all(&data).await.unwrap();
+        assert_eq!(read_exact(&mut stream, data.len()).await, data);
+    }
+
+    // 累计流量不随读取清零 活跃连接单独统计
+    let traffic = context.get_traffic(TrafficKind::Tunnel);
+    assert_eq!(traffic.connections, 1);
+    assert!(traffic.upload_bytes >= 3000 && traffic.download_bytes >= 3000);
+    assert_eq!(context.get_traffic(TrafficKind::Tunnel), traffic);
+    let connections = context.get_connection_traffic().await;
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].target_addr, echo);
+    assert_eq!(connections[0].snapshot().upload_bytes, 3000);
+}
+
+#[tokio::test]
+async fn test_http_proxy() {
+    let tunnel_port = start_tunnel_server(Compression::Zstd).await;
+    let target = start_http_target().await;
+    let (context, proxy) = start_proxy(tunnel_port).await;
+
+    // 请求头随NewConnect之后的第一个数据包发送
+    let mut stream = connect_proxy(&proxy).await;
+    let request_line = format!("GET http://{}/hello HTTP/1.1", target);
+    stream.write_all(format!("{}\r\nHost: {}\r\n\r\n", request_line, target).as_bytes()).await.unwrap();
+    let header = read_http_header(&mut stream).await;
+    assert!(header.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(header.contains(&format!("Content-Length: {}\r\n", request_line.len())));
+    assert_eq!(read_exact(&mut stream, request_line.len()).await, request_line.as_bytes());
+
+    // 经过隧道而不是直连
+    wait_tunnel_traffic(&context, 1).await;
+}
+
+#[tokio::test]
+async fn test_socks5_tcp() {
+    let tunnel_port = start_tunnel_server(Compression::None).await;
+    let echo = start_tcp_echo().await;
+    let (context, proxy) = start_proxy(tunnel_port).await;
+
+    let mut stream = connect_proxy(&proxy).await;
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    assert_eq!(read_exact(&mut stream, 2).await, [0x05, 0x00]);
+    let mut request = vec![0x05, 0x01, 0x00];
+    request.extend(socks5_address(&echo));
+    stream.write_all(&request).await.unwrap();
+    assert_eq!(read_exact(&mut stream, 10).await[..2], [0x05, 0x00]);
+
+    let data = b"hello socks5".repeat(100);
+    stream.write_all(&data).await.unwrap();
+    assert_eq!(read_exact(&mut stream, data.len()).await, data);
+
+    // 经过隧道而不是直连
+    wait_tunnel_traffic(&context, data.len() as u64).await;
+}
+
+#[tokio::test]
+async fn test_socks5_udp() {
+    let tunnel_port = start_tunnel_server(Compression::None).await;
+    let echo = start_udp_echo().await;
+    let (context, proxy) = start_proxy(tunnel_port).await;
+
+    // UDP ASSOCIATE 返回本地UDP端口
+    let mut stream = connect_proxy(&proxy).await;
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    assert_eq!(read_exact(&mut stream, 2).await, [0x05, 0x00]);
+    let mut request = vec![0x05, 0x03, 0x00];
+    request.extend(socks5_address("0.0.0.0:0"));
+    stream.write_all(&request).await.unwrap();
+    let reply = read_exact(&mut stream, 10).await;
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let udp_port = u16::from_be_bytes([reply[8], reply[9]]);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut datagram = vec![0x00, 0x00, 0x00];
+    datagram.extend(socks5_address(&echo));
+    let header_len = datagram.len();
+    datagram.extend_from_slice(b"hello udp");
+    socket.send_to(&datagram, ("127.0.0.1", udp_port)).await.unwrap();
+
+    // 返回的数据带目标地址
+    let mut buffer = [0u8; 4096];
+    let (n, _) = timeout(WAIT, socket.recv_from(&mut buffer)).await.unwrap().unwrap();
+    assert_eq!(buffer[..header_len], datagram[..header_len]);
+    assert_eq!(&buffer[header_len..n], b"hello udp");
+
+    // 经过隧道而不是直连
+    wait_tunnel_traffic(&context, 1).await;
+}
+
+#[tokio::test]
+async fn test_tunnel_shutdown() {
+    let tunnel_port = start_tunnel_server(Compression::None).await;
+    // 目标服务读到连接结束时通知
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap().to_string();
+    let (eof_sender, eof_receiver) = oneshot::channel();
+    spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        while let Ok(n) = stream.read(&mut buffer).await {
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buffer[..n]).await.unwrap();
+        }
+        let _ = eof_sender.send(());
+    });
+    let (context, proxy) = start_proxy(tunnel_port).await;
+
+    let mut stream = connect_proxy(&proxy).await;
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target).as_bytes()).await.unwrap();
+    read_http_header(&mut stream).await;
+    stream.write_all(b"hello").await.unwrap();
+    assert_eq!(read_exact(&mut stream, 5).await, b"hello");
+
+    // 关闭隧道后本地连接读到结束 服务端也关闭了到目标的连接
+    context.close_tunnel().await;
+    let mut buffer = [0u8; 16];
+    assert_eq!(timeout(WAIT, stream.read(&mut buffer)).await.unwrap().unwrap_or(0), 0);
+    timeout(WAIT, eof_receiver).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_rule_rate_limit() {
+    let tunnel_port = start_tunnel_server(Compression::None).await;
+    let echo = start_tcp_echo().await;
+    let (context, proxy) = start_proxy(tunnel_port).await;
+    // 兜底规则下载限速每秒10000字节
+    context.set_domain_rule(r#"[{"matching":10,"domain":"","proxyType":2,"downloadLimit":10000}]"#.to_string()).await;
+
+    let mut stream = connect_proxy(&proxy).await;
+    stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes()).await.unwrap();
+    read_http_header(&mut stream).await;
+
+    // 桶里一秒的量立即通过 剩下的按速率等待
+    let start = Instant::now();
+    let data = vec![7u8; 20000];
+    stream.write_all(&data).await.unwrap();
+    assert_eq!(read_exact(&mut stream, data.len()).await, data);
+    assert!(start.elapsed() >= Duration::from_millis(700));
+
+    let limits = context.get_rule_rate_limits().await;
+    assert_eq!(limits.len(), 1);
+    assert_eq!(limits[0].1.download.rate, 10000);
+    assert_eq!(limits[0].1.upload.rate, 0);
+    assert!(limits[0].1.download.wait_ms > 0);
+    assert_eq!(context.get_rate_limit().download.wait_ms, 0);
+}
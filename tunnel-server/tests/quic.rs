@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::spawn;
+use tokio::time::timeout;
+
+use tunnel::context::context::TunnelContext;
+use tunnel::tunnel::quic::QuicTransport;
+use tunnel::tunnel::tls::TlsConfig;
+use tunnel::tunnel::transport::Transport;
+
+use crate::support::*;
+
+mod support;
+
+fn quic_transport(ca_file: String) -> Option<Arc<dyn Transport>> {
+    let config = TlsConfig {
+        sni: Some("localhost".to_string()),
+        ca_file: Some(ca_file),
+        ..TlsConfig::default()
+    };
+    Some(Arc::new(QuicTransport { config }))
+}
+
+#[tokio::test]
+async fn test_quic_http_connect() {
+    let (quic_port, ca_file) = start_quic_tunnel_server().await;
+    let echo = start_tcp_echo().await;
+    let (_context, proxy) = start_proxy_with(quic_port, quic_transport(ca_file)).await;
+
+    // 每个连接走各自的QUIC流 同时收发互不影响
+    let mut jobs = vec![];
+    for i in 0..4u8 {
+        let mut stream = connect_proxy(&proxy).await;
+        let echo = echo.clone();
+        jobs.push(spawn(async move {
+            stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", echo, echo).as_bytes()).await.unwrap();
+            assert_eq!(read_http_header(&mut stream).await, "HTTP/1.1 200 Connection Established\r\n\r\n");
+            for _ in 0..8 {
+                let data = vec![i; 64 * 1024];
+                stream.write_all(&data).await.unwrap();
+                assert_eq!(read_exact(&mut stream, data.len()).await, data);
+            }
+        }));
+    }
+    for job in jobs {
+        job.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_quic_socks5_udp() {
+    let (quic_port, ca_file) = start_quic_tunnel_server().await;
+    let echo = start_udp_echo().await;
+    let (_context, proxy) = start_proxy_with(quic_port, quic_transport(ca_file)).await;
+
+    let mut stream = connect_proxy(&proxy).await;
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    assert_eq!(read_exact(&mut stream, 2).await, [0x05, 0x00]);
+    let mut request = vec![0x05, 0x03, 0x00];
+    request.extend(socks5_address("0.0.0.0:0"));
+    stream.write_all(&request).await.unwrap();
+    let reply = read_exact(&mut stream, 10).await;
+    assert_eq!(reply[..2], [0x05, 0x00]);
+    let udp_port = u16::from_be_bytes([reply[8], reply[9]]);
+
+    // 小数据走QUIC数据报，超过数据报大小的走控制流
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut header = vec![0x00, 0x00, 0x00];
+    header.extend(socks5_address(&echo));
+    for payload in [b"hello quic".to_vec(), vec![7u8; 4000]] {
+        let mut datagram = header.clone();
+        datagram.extend_from_slice(&payload);
+        socket.send_to(&datagram, ("127.0.0.1", udp_port)).await.unwrap();
+
+        let mut buffer = [0u8; 8192];
+        let (n, _) = timeout(WAIT, socket.recv_from(&mut buffer)).await.unwrap().unwrap();
+        assert_eq!(buffer[..header.len()], header[..]);
+        assert_eq!(&buffer[header.len()..n], &payload[..]);
+    }
+}
+
+#[tokio::test]
+async fn test_quic_bad_certificate() {
+    let (quic_port, _) = start_quic_tunnel_server().await;
+    // 另一张证书签发的CA不能通过校验
+    let (_, other_ca_file) = start_quic_tunnel_server().await;
+    let context = TunnelContext::new();
+    context.set_transport(quic_transport(other_ca_file).unwrap()).await;
+    assert!(context.connect_tunnel("127.0.0.1".to_string(), quic_port, PASSWORD.to_string()).await.is_err());
+}
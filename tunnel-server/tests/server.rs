@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::{sleep, timeout};
+
+use tunnel::context::context::TunnelContext;
+use tunnel::error::TunnelError;
+use tunnel::tunnel::cipher::CipherSuite;
+use tunnel::tunnel::compress::Compression;
+use tunnel::tunnel::event::{EventPublisher, TunnelEvent, TunnelEventKind};
+use tunnel::tunnel::transport::TcpTransport;
+use tunnel::tunnel::tunnel::{Tunnel, TunnelStatus};
+use tunnel::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+
+use crate::support::*;
+
+mod support;
+
+async fn connect(port: u16, password: &str, cipher_suite: CipherSuite) -> (Tunnel, Receiver<TunnelPackage>, broadcast::Receiver<TunnelEvent>) {
+    let (sender, receiver) = channel(1024);
+    let (events, event_receiver) = broadcast::channel(16);
+    let events = EventPublisher::new("test".to_string(), events);
+    let tunnel = Tunnel::new("127.0.0.1".to_string(), port, password.to_string(), cipher_suite, Compression::Zstd, false, &TcpTransport, sender, events).await.unwrap();
+    (tunnel, receiver, event_receiver)
+}
+
+async fn next_event(receiver: &mut broadcast::Receiver<TunnelEvent>) -> TunnelEventKind {
+    let event = timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+    assert_eq!(event.server, "test");
+    event.kind
+}
+
+async fn wait_status(tunnel: &Tunnel, status: TunnelStatus) {
+    timeout(WAIT, async {
+        while tunnel.get_status().await.as_index() != status.as_index() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+}
+
+/// 读取下一个数据包 跳过窗口更新
+async fn next_package(receiver: &mut Receiver<TunnelPackage>) -> TunnelPackage {
+    loop {
+        let package = timeout(WAIT, receiver.recv()).await.unwrap().unwrap();
+        if package.cmd != PackageCmd::WindowUpdate {
+            return package;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_and_udp_relay() {
+    let port = start_tunnel_server(Compression::Zstd).await;
+    let tcp_echo = start_tcp_echo().await;
+    let udp_echo = start_udp_echo().await;
+    let (mut tunnel, mut receiver, mut events) = connect(port, PASSWORD, CipherSuite::ChaCha20Poly1305).await;
+    wait_status(&tunnel, TunnelStatus::Success).await;
+    assert_eq!(next_event(&mut events).await, TunnelEventKind::LoginSuccess);
+
+    // TCP 连接成功前发送的数据也会转发
+    tunnel.write_to_tunnel(TunnelPackage::new(PackageCmd::NewConnect, PackageProtocol::TCP, 1, Some("127.0.0.1:50000".to_string()), Some(tcp_echo), None)).await.unwrap();
+    let text = b"hello tunnel server ".repeat(100);
+    tunnel.write_to_tunnel(TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 1, None, None, Some(text.clone()))).await.unwrap();
+    let mut echoed = Vec::new();
+    while echoed.len() < text.len() {
+        let package = next_package(&mut receiver).await;
+        assert_eq!(package.cmd, PackageCmd::TData);
+        assert_eq!(package.stream_id, 1);
+        echoed.extend(package.data.unwrap());
+    }
+    assert_eq!(echoed, text);
+    // 下行文本数据被压缩
+    assert!(tunnel.get_download_compression().ratio() < 1.0);
+
+    // UDP 返回的数据带客户端发送时的目标地址
+    tunnel.write_to_tunnel(TunnelPackage::new(PackageCmd::TData, PackageProtocol::UDP, 2, None, Some(udp_echo.clone()), Some(b"ping".to_vec()))).await.unwrap();
+    let package = next_package(&mut receiver).await;
+    assert_eq!(package.stream_id, 2);
+    assert_eq!(package.target_address, Some(udp_echo));
+    assert_eq!(package.data, Some(b"ping".to_vec()));
+    // 同一个UDP连接也能发往IPv6目标
+    let udp_echo_v6 = start_udp_echo_on("[::1]:0").await;
+    tunnel.write_to_tunnel(TunnelPackage::new(PackageCmd::TData, PackageProtocol::UDP, 2, None, Some(udp_echo_v6.clone()), Some(b"ping6".to_vec()))).await.unwrap();
+    let package = next_package(&mut receiver).await;
+    assert_eq!(package.stream_id, 2);
+    assert_eq!(package.target_address, Some(udp_echo_v6));
+    assert_eq!(package.data, Some(b"ping6".to_vec()));
+
+    // 目标连接失败时通知客户端关闭
+    tunnel.write_to_tunnel(TunnelPackage::new(PackageCmd::NewConnect, PackageProtocol::TCP, 3, None, Some("127.0.0.1:1".to_string()), None)).await.unwrap();
+    let package = next_package(&mut receiver).await;
+    assert_eq!(package.cmd, PackageCmd::CloseConnect);
+    assert_eq!(package.stream_id, 3);
+
+    // 登录后的PING收到PONG
+    assert!(matches!(next_event(&mut events).await, TunnelEventKind::RttUpdate { .. }));
+    assert_eq!(tunnel.get_rtt_summary().await.samples, 1);
+    tunnel.disconnect().await;
+    assert_eq!(next_event(&mut events).await, TunnelEventKind::Closed { reason: None });
+}
+
+#[tokio::test]
+async fn test_login_fail() {
+    let port = start_tunnel_server(Compression::None).await;
+    let (sender, _receiver) = channel(1024);
+    let (events, mut event_receiver) = broadcast::channel(16);
+    let events = EventPublisher::new("test".to_string(), events);
+    let result = Tunnel::new("127.0.0.1".to_string(), port, "wrong password".to_string(), CipherSuite::Aes256Gcm, Compression::None, false, &TcpTransport, sender, events).await;
+    // 密码错误时双方的会话密钥不同 服务端的响应无法解密，按认证失败返回
+    let e = result.err().unwrap();
+    assert!(matches!(e, TunnelError::Auth(_)));
+    assert_eq!(e.code(), 4);
+    assert!(matches!(next_event(&mut event_receiver).await, TunnelEventKind::ProtocolError { .. }));
+
+    // 通过上下文连接时同样返回认证失败
+    let context = TunnelContext::new();
+    let e = context.add_tunnel_server("test".to_string(), "127.0.0.1".to_string(), port, "wrong password".to_string()).await.unwrap_err();
+    assert!(matches!(e, TunnelError::Auth(_)));
+    context.close_tunnel().await;
+}
+
+#[tokio::test]
+async fn test_context_events() {
+    let port = start_tunnel_server(Compression::None).await;
+    let context = TunnelContext::new();
+    let mut events = context.subscribe_events();
+    context.add_tunnel_server("test".to_string(), "127.0.0.1".to_string(), port, PASSWORD.to_string()).await.unwrap();
+    assert_eq!(next_event(&mut events).await, TunnelEventKind::Connecting { host: "127.0.0.1".to_string(), port });
+    assert_eq!(next_event(&mut events).await, TunnelEventKind::LoginSuccess);
+    assert!(matches!(next_event(&mut events).await, TunnelEventKind::RttUpdate { .. }));
+    context.close_tunnel().await;
+    assert_eq!(next_event(&mut events).await, TunnelEventKind::Closed { reason: None });
+}
+
+#[tokio::test]
+async fn test_context_close_streams() {
+    let port = start_tunnel_server(Compression::None).await;
+    let echo = start_tcp_echo().await;
+    let context = TunnelContext::new();
+    context.add_tunnel_server("test".to_string(), "127.0.0.1".to_string(), port, PASSWORD.to_string()).await.unwrap();
+    timeout(WAIT, async {
+        while context.get_tunnel_status().await != 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+
+    // 本地连接自己也持有发送端 和TUN的TcpPipe一样
+    let (sender, mut receiver) = channel(10);
+    context.add_proxy_mapping("127.0.0.1:50000".to_string(), sender.clone()).await;
+    let stream_id = context.tunnel_connect_server(echo, "127.0.0.1:50000".to_string()).await.unwrap();
+
+    // 关闭隧道时本地连接收到关闭命令
+    context.close_tunnel().await;
+    let package = next_package(&mut receiver).await;
+    assert_eq!(package.cmd, PackageCmd::CloseConnect);
+    assert_eq!(package.stream_id, stream_id);
+    drop(sender);
+}
+
+#[tokio::test]
+async fn test_context_resolve_remote() {
+    let port = start_tunnel_server(Compression::None).await;
+    let context = TunnelContext::new();
+    context.add_tunnel_server("test".to_string(), "127.0.0.1".to_string(), port, PASSWORD.to_string()).await.unwrap();
+    timeout(WAIT, async {
+        while context.get_tunnel_status().await != 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+
+    let records = context.resolve_remote("localhost").await.unwrap();
+    assert!(!records.is_empty());
+    assert!(records.iter().all(|record| record.addr.is_loopback() && record.ttl > 0));
+    // 解析失败时返回服务端的原因
+    assert!(context.resolve_remote("nonexistent.invalid").await.is_err());
+}
@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::spawn;
+use tokio::time::{sleep, timeout};
+
+use tunnel::context::context::TunnelContext;
+use tunnel::context::traffic::TrafficKind;
+use tunnel::proxy::proxy::Proxy;
+use tunnel::tunnel::compress::Compression;
+use tunnel::tunnel::transport::Transport;
+use tunnel_server::config::{QuicConfig, ServerConfig};
+use tunnel_server::server::Server;
+
+pub const PASSWORD: &str = "password";
+/// 等待单个结果的最长时间
+pub const WAIT: Duration = Duration::from_secs(5);
+
+/// 进程内的隧道服务端
+pub async fn start_tunnel_server(compression: Compression) -> u16 {
+    let server = Server::bind(ServerConfig::new("127.0.0.1:0".to_string(), PASSWORD.to_string(), compression)).await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    spawn(server.run());
+    port
+}
+
+/// 同时监听QUIC的隧道服务端 使用localhost的自签名证书
+/// 返回QUIC端口和CA证书文件
+pub async fn start_quic_tunnel_server() -> (u16, String) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir();
+    let id = format!("{}-{:?}", std::process::id(), std::time::SystemTime::now());
+    let cert_file = dir.join(format!("flyshadow-quic-cert-{}.pem", id)).to_string_lossy().to_string();
+    let key_file = dir.join(format!("flyshadow-quic-key-{}.pem", id)).to_string_lossy().to_string();
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+
+    let mut config = ServerConfig::new("127.0.0.1:0".to_string(), PASSWORD.to_string(), Compression::None);
+    config.quic = Some(QuicConfig { listen: "127.0.0.1:0".to_string(), cert_file: cert_file.clone(), key_file });
+    let server = Server::bind(config).await.unwrap();
+    let port = server.quic_local_addr().unwrap().unwrap().port();
+    spawn(server.run());
+    (port, cert_file)
+}
+
+/// 本地TCP回显服务
+pub async fn start_tcp_echo() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            spawn(async move {
+                let mut buffer = [0u8; 4096];
+                while let Ok(n) = stream.read(&mut buffer).await {
+                    if n == 0 || stream.write_all(&buffer[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// 本地UDP回显服务
+pub async fn start_udp_echo() -> String {
+    start_udp_echo_on("127.0.0.1:0").await
+}
+
+/// 绑定到指定地址的UDP回显服务
+pub async fn start_udp_echo_on(addr: &str) -> String {
+    let socket = UdpSocket::bind(addr).await.unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    spawn(async move {
+        let mut buffer = [0u8; 4096];
+        while let Ok((n, peer)) = socket.recv_from(&mut buffer).await {
+            let _ = socket.send_to(&buffer[..n], peer).await;
+        }
+    });
+    addr
+}
+
+/// 本地HTTP服务 响应体为请求行
+pub async fn start_http_target() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => { return; }
+                        Ok(n) => { request.extend_from_slice(&buffer[..n]); }
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let body = request.lines().next().unwrap_or_default().to_string();
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    addr
+}
+
+/// 连接隧道并启动本地代理 所有域名都走隧道
+pub async fn start_proxy(tunnel_port: u16) -> (Arc<TunnelContext>, Proxy) {
+    start_proxy_with(tunnel_port, None).await
+}
+
+/// 使用指定的传输方式连接隧道并启动本地代理
+pub async fn start_proxy_with(tunnel_port: u16, transport: Option<Arc<dyn Transport>>) -> (Arc<TunnelContext>, Proxy) {
+    let mut context = TunnelContext::new();
+    context.set_proxy_type(2);
+    let context = Arc::new(context);
+    if let Some(transport) = transport {
+        context.set_transport(transport).await;
+    }
+    // 规则模式下没有规则时默认直连 兜底规则让所有连接都走隧道
+    context.set_domain_rule(r#"[{"matching":10,"domain":"","proxyType":2}]"#.to_string()).await;
+    context.connect_tunnel("127.0.0.1".to_string(), tunnel_port, PASSWORD.to_string()).await.unwrap();
+    timeout(WAIT, async {
+        while context.get_tunnel_status().await != 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+
+    let mut proxy = Proxy::new(context.clone(), 0);
+    proxy.start().await.unwrap();
+    (context, proxy)
+}
+
+/// 等待隧道上下行都统计到至少bytes字节 下载量在数据交给本地连接之后才记录
+pub async fn wait_tunnel_traffic(context: &TunnelContext, bytes: u64) {
+    timeout(WAIT, async {
+        loop {
+            let traffic = context.get_traffic(TrafficKind::Tunnel);
+            if traffic.upload_bytes >= bytes && traffic.download_bytes >= bytes {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+}
+
+pub async fn connect_proxy(proxy: &Proxy) -> TcpStream {
+    TcpStream::connect(("127.0.0.1", proxy.get_port() as u16)).await.unwrap()
+}
+
+/// 读取正好len个字节
+pub async fn read_exact(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    timeout(WAIT, stream.read_exact(&mut data)).await.unwrap().unwrap();
+    data
+}
+
+/// 读取到HTTP响应头结束
+pub async fn read_http_header(stream: &mut TcpStream) -> String {
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        header.extend(read_exact(stream, 1).await);
+    }
+    String::from_utf8(header).unwrap()
+}
+
+/// SOCKS5地址 只用IPv4
+pub fn socks5_address(addr: &str) -> Vec<u8> {
+    let addr: std::net::SocketAddrV4 = addr.parse().unwrap();
+    let mut data = vec![0x01];
+    data.extend(addr.ip().octets());
+    data.extend(addr.port().to_be_bytes());
+    data
+}
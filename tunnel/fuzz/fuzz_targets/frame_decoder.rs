@@ -0,0 +1,18 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use tunnel::tunnel::cipher::{CipherSuite, FrameCipher, FrameDirection};
+use tunnel::tunnel::codec::FrameDecoder;
+
+// 明文和加密解码器处理任意输入都不会panic
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = FrameDecoder::plain();
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(_)) = decoder.decode(&mut buffer) {}
+
+    let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &[0u8; 32], FrameDirection::ClientToServer));
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(_)) = decoder.decode(&mut buffer) {}
+});
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use tunnel::tunnel::tunnel_package::TunnelPackage;
+
+// 任意输入不会panic，解析成功的数据包重新编码后可以得到同样的数据包
+fuzz_target!(|data: &[u8]| {
+    if let Ok(package) = TunnelPackage::from_byte_array(data) {
+        let mut vec = Vec::new();
+        package.to_byte_array(&mut vec);
+        assert_eq!(TunnelPackage::from_byte_array(&vec).unwrap(), package);
+    }
+});
@@ -1,42 +1,139 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
 use serde_json::Value;
 use tokio::spawn;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 
 use crate::context::connect_info::ConnectInfo;
 use crate::context::proxy_type::ProxyType;
-use crate::context::rule_matcher::{AllDomainMatcher, GEOIPMatcher, IPV4DomainMatcher, KeywordDomainMatcher, MatchMatcher, RuleMatcher, SuffixDomainMatcher};
-use crate::tunnel::tunnel::{Tunnel, TunnelStatus};
+use crate::context::rate_limit::{ConnectionLimiter, RateLimit, RateLimiter, RateLimitSnapshot};
+use crate::context::reconnect::{CHECK_INTERVAL, HeartbeatConfig};
+use crate::context::rule_matcher::{AllDomainMatcher, DomainRule, GEOIPMatcher, IPV4DomainMatcher, KeywordDomainMatcher, MatchMatcher, RuleMatcher, SuffixDomainMatcher};
+use crate::context::tunnel_group::{GroupStrategy, refresh_group, TunnelGroup};
+use crate::context::traffic::{ConnectionTraffic, TrafficKind, TrafficSnapshot, TrafficStats};
+use crate::context::tunnel_server::{close_local_stream, TunnelServer, TunnelServerInfo};
+use crate::error::TunnelError;
+use crate::tunnel::cipher::CipherSuite;
+use crate::tunnel::compress::{Compression, CompressionSummary};
+use crate::tunnel::event::{EVENT_CAPACITY, TunnelEvent};
+use crate::tunnel::hello::Capabilities;
+use crate::tunnel::resolve::{resolve_package, ResolveRecord, ResolveResult};
+use crate::tunnel::rtt::RttSummary;
+use crate::tunnel::transport::{TcpTransport, Transport};
+use crate::tunnel::tunnel::TunnelStatus;
 use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+use crate::tunnel::window::{INITIAL_WINDOW, WINDOW_UPDATE_THRESHOLD, window_cost, window_increment};
+
+/// 兼容单服务器接口时使用的服务器名称
+const DEFAULT_SERVER_NAME: &str = "default";
+/// 等待服务端解析域名的最长时间
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 每个连接本地转发队列的长度
+const STREAM_QUEUE_SIZE: usize = 256;
 
 pub struct TunnelContext {
-    tunnel: RwLock<Option<Tunnel>>,
+    group: Arc<RwLock<TunnelGroup>>,
+    /// 连接源地址对应的连接编号 只在建立和关闭连接时使用
+    stream_ids: RwLock<HashMap<String, u32>>,
+    next_stream_id: AtomicU32,
+    /// 等待服务端返回的解析请求
+    pending_resolves: Arc<RwLock<HashMap<u32, oneshot::Sender<TunnelPackage>>>>,
+    next_resolve_id: AtomicU32,
+    /// 连接编号对应的隧道服务器，同一连接始终走同一台服务器
+    stream_map: Arc<RwLock<HashMap<u32, Arc<TunnelServer>>>>,
+    /// 连接编号-连接流量 发送数据时按编号找到连接
+    stream_traffic: Arc<RwLock<HashMap<u32, Arc<ConnectionTraffic>>>>,
+    traffic: Arc<TrafficStats>,
+    /// 所有连接共用的限速
+    global_limiter: Arc<RateLimiter>,
+    /// 每个新连接的限速
+    connection_limit: RwLock<RateLimit>,
+    cipher_suite: RwLock<CipherSuite>,
+    compression: RwLock<Compression>,
+    /// 服务端不支持版本协商时是否按旧版本握手
+    legacy_handshake: RwLock<bool>,
+    transport: RwLock<Arc<dyn Transport>>,
+    heartbeat_config: Arc<RwLock<HeartbeatConfig>>,
+    group_job: Option<JoinHandle<()>>,
+    /// 所有服务器的隧道状态事件
+    events: broadcast::Sender<TunnelEvent>,
+    event_listener_job: RwLock<Option<JoinHandle<()>>>,
     tunnel_sender: Sender<TunnelPackage>,
     tunnel_receiver: Option<Receiver<TunnelPackage>>,
-    proxy_map: Arc<RwLock<HashMap<String, Sender<TunnelPackage>>>>,
+    /// 连接编号对应的本地转发队列
+    proxy_map: Arc<RwLock<HashMap<u32, Sender<TunnelPackage>>>>,
     proxy_type: ProxyType,
     tunnel_receiver_job: Option<JoinHandle<()>>,
-    domain_rule_matcher: RwLock<Vec<Box<dyn RuleMatcher>>>,
+    domain_rule_matcher: RwLock<Vec<DomainRule>>,
     connect_infos: RwLock<HashMap<String, ConnectInfo>>,
 }
 
 impl TunnelContext {
     /// 开启tunnel数据包接收线程
+    /// 转发到各连接自己的队列，不会因为某个连接处理慢而阻塞其他连接
     fn start_tunnel_receiver_job(&mut self) {
         let proxy_map = self.proxy_map.clone();
+        let stream_map = self.stream_map.clone();
+        let pending_resolves = self.pending_resolves.clone();
         if let Some(mut tunnel_receiver) = self.tunnel_receiver.take() {
             let tunnel_receiver_job = spawn(async move {
                 // 读TunnelPackage
                 while let Some(tunnel_package) = tunnel_receiver.recv().await {
-                    // 有源地址
-                    if let Some(ref source_addr) = tunnel_package.source_address {
-                        // 取映射中的客户端
-                        if let Some(sender) = proxy_map.read().await.get(&source_addr.to_string()) {
-                            let _ = sender.send(tunnel_package).await;
+                    let stream_id = tunnel_package.stream_id;
+                    // 不属于任何连接
+                    if stream_id == 0 {
+                        continue;
+                    }
+                    // 服务端的窗口更新
+                    if tunnel_package.cmd == PackageCmd::WindowUpdate {
+                        let server = stream_map.read().await.get(&stream_id).cloned();
+                        if let (Some(server), Some(increment)) = (server, window_increment(&tunnel_package)) {
+                            server.grant_send_window(stream_id, increment).await;
+                        }
+                        continue;
+                    }
+                    // 解析结果按请求编号返回给等待的请求
+                    if tunnel_package.cmd == PackageCmd::ResolveResult {
+                        if let Some(sender) = pending_resolves.write().await.remove(&stream_id) {
+                            let _ = sender.send(tunnel_package);
+                        }
+                        continue;
+                    }
+                    // 服务端超出连接的接收窗口 关闭这个连接
+                    if tunnel_package.cmd == PackageCmd::TData {
+                        let server = stream_map.read().await.get(&stream_id).cloned();
+                        if let Some(server) = server {
+                            let len = tunnel_package.data.as_ref().map_or(0, |data| data.len());
+                            if let Err(e) = server.receive_stream_data(stream_id, &tunnel_package.protocol, len).await {
+                                log::error!("stream {} reset: {}", stream_id, e);
+                                stream_map.write().await.remove(&stream_id);
+                                server.reset_stream(stream_id, &e.to_string()).await;
+                                if let Some(sender) = proxy_map.write().await.remove(&stream_id) {
+                                    close_local_stream(&sender, stream_id);
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    // 取映射中的客户端
+                    let sender = proxy_map.read().await.get(&stream_id).cloned();
+                    if let Some(sender) = sender {
+                        // 队列已满时丢弃UDP数据 其他数据等待本地消费
+                        if let Err(TrySendError::Full(tunnel_package)) = sender.try_send(tunnel_package) {
+                            if !matches!(tunnel_package.protocol, PackageProtocol::UDP) {
+                                let _ = sender.send(tunnel_package).await;
+                            }
                         }
                     }
                 };
@@ -44,6 +141,132 @@ impl TunnelContext {
             self.tunnel_receiver_job = Some(tunnel_receiver_job);
         }
     }
+
+    /// 开启服务器组选择线程
+    /// 定时按延迟和可用状态重新选择服务器，只影响之后新建的连接
+    fn start_group_job(&mut self) {
+        let group = self.group.clone();
+        let group_job = spawn(async move {
+            loop {
+                sleep(CHECK_INTERVAL).await;
+                refresh_group(&group).await;
+            }
+        });
+        self.group_job = Some(group_job);
+    }
+
+    /// 获取源地址对应的连接编号，没有则分配一个
+    /// 编号用完一轮后从头开始，跳过还在使用的编号
+    async fn stream_id(&self, source_addr: &String) -> u32 {
+        if let Some(stream_id) = self.stream_ids.read().await.get(source_addr) {
+            return *stream_id;
+        }
+        let mut stream_ids = self.stream_ids.write().await;
+        if let Some(stream_id) = stream_ids.get(source_addr) {
+            return *stream_id;
+        }
+        let stream_map = self.stream_map.read().await;
+        let proxy_map = self.proxy_map.read().await;
+        let in_use: HashSet<u32> = stream_ids.values().copied().collect();
+        // 0保留给不属于任何连接的数据包
+        let stream_id = loop {
+            let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+            if stream_id != 0 && !in_use.contains(&stream_id) && !stream_map.contains_key(&stream_id) && !proxy_map.contains_key(&stream_id) {
+                break stream_id;
+            }
+        };
+        stream_ids.insert(source_addr.clone(), stream_id);
+        stream_id
+    }
+
+    /// 获取连接对应的服务器，新连接按服务器组的策略分配
+    async fn select_server(&self, stream_id: u32, target_addr: &str) -> Option<Arc<TunnelServer>> {
+        if let Some(server) = self.stream_map.read().await.get(&stream_id) {
+            return Some(server.clone());
+        }
+        let target_host = target_addr.rsplit_once(':').map_or(target_addr, |(host, _)| host);
+        let mut server = self.group.read().await.pick_server(target_host)?;
+        // 分配到的服务器已断开时立即重新选择，不等待选择线程
+        if !server.is_healthy().await {
+            refresh_group(&self.group).await;
+            server = self.group.read().await.pick_server(target_host)?;
+        }
+        server.add_stream(stream_id).await;
+        self.stream_map.write().await.insert(stream_id, server.clone());
+        Some(server)
+    }
+
+    /// 发送连接数据 UDP数据包需要带目标地址
+    async fn send_stream_data(&self, server: Arc<TunnelServer>, stream_id: u32, target_addr: Option<String>, data: Vec<u8>, protocol: PackageProtocol) -> Result<(), TunnelError> {
+        // 服务端未开启UDP转发
+        if matches!(protocol, PackageProtocol::UDP) && !server.get_capabilities().await.contains(Capabilities::UDP) {
+            return Err(TunnelError::TunnelUnavailable("Tunnel not support udp".to_string()));
+        }
+        let traffic = self.stream_traffic.read().await.get(&stream_id).cloned();
+        let len = data.len();
+        if let Some(traffic) = traffic.as_ref() {
+            traffic.throttle_upload(len).await;
+        }
+        // 多路复用时UDP走数据报，丢失的数据不会归还窗口
+        if matches!(protocol, PackageProtocol::UDP) && server.multiplexed().await {
+            let tunnel_package = TunnelPackage::new(PackageCmd::TData, protocol, stream_id, None, target_addr, Some(data));
+            server.write_to_tunnel(tunnel_package).await?;
+            if let Some(traffic) = traffic {
+                traffic.record_upload(len);
+            }
+            return Ok(());
+        }
+        // 超过一个窗口的数据分段发送 每段等待这个连接的发送窗口
+        let mut data = data;
+        loop {
+            let rest = data.split_off(data.len().min(INITIAL_WINDOW as usize));
+            let chunk_len = data.len();
+            server.acquire_send_window(stream_id, chunk_len).await?;
+            let tunnel_package = TunnelPackage::new(PackageCmd::TData, protocol.clone(), stream_id, None, target_addr.clone(), Some(data));
+            server.write_to_tunnel(tunnel_package).await?;
+            if let Some(traffic) = traffic.as_ref() {
+                traffic.record_upload(chunk_len);
+            }
+            if rest.is_empty() {
+                return Ok(());
+            }
+            data = rest;
+        }
+    }
+
+    /// 开始统计经过隧道的连接
+    async fn open_stream_traffic(&self, stream_id: u32, source_addr: &str, target_addr: &str) {
+        if self.stream_traffic.read().await.contains_key(&stream_id) {
+            return;
+        }
+        let limiter = self.connection_limiter(target_addr).await;
+        let traffic = self.traffic.open(source_addr, target_addr, TrafficKind::Tunnel, limiter).await;
+        self.stream_traffic.write().await.insert(stream_id, traffic);
+    }
+
+    /// 匹配域名 同时返回匹配规则的限速
+    async fn match_rule(&self, domain: &String) -> (ProxyType, Option<Arc<RateLimiter>>) {
+        return if self.proxy_type == ProxyType::Proxy {
+            for rule in self.domain_rule_matcher.read().await.iter() {
+                if let Some(proxy_type) = rule.matcher.do_match(domain) {
+                    return (proxy_type, rule.limiter.clone());
+                }
+            }
+            return (ProxyType::Redirect, None);
+        } else { (self.proxy_type.clone(), None) };
+    }
+
+    /// 新连接的限速 按目标地址匹配规则
+    async fn connection_limiter(&self, target_addr: &str) -> ConnectionLimiter {
+        let target_host = target_addr.rsplit_once(':').map_or(target_addr, |(host, _)| host);
+        let (_, rule) = self.match_rule(&target_host.to_string()).await;
+        ConnectionLimiter::new(self.global_limiter.clone(), rule, *self.connection_limit.read().await)
+    }
+
+    /// 获取当前选中的服务器
+    async fn selected_server(&self) -> Option<Arc<TunnelServer>> {
+        self.group.read().await.get_selected()
+    }
 }
 
 impl TunnelContext {
@@ -52,9 +275,27 @@ impl TunnelContext {
         // Tunnel往这里写  Context读取这里数据 写到对应Tunnel receiver
         let (tunnel_sender, tunnel_receiver) = channel::<TunnelPackage>(10);
         let proxy_map = Arc::new(RwLock::new(HashMap::new()));
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
 
         let mut context = TunnelContext {
-            tunnel: RwLock::new(None),
+            group: Arc::new(RwLock::new(TunnelGroup::default())),
+            stream_ids: RwLock::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(1),
+            pending_resolves: Arc::new(RwLock::new(HashMap::new())),
+            next_resolve_id: AtomicU32::new(1),
+            stream_map: Arc::new(RwLock::new(HashMap::new())),
+            stream_traffic: Arc::new(RwLock::new(HashMap::new())),
+            traffic: Arc::new(TrafficStats::default()),
+            global_limiter: Arc::new(RateLimiter::new(RateLimit::default())),
+            connection_limit: RwLock::new(RateLimit::default()),
+            cipher_suite: RwLock::new(CipherSuite::Aes256Gcm),
+            compression: RwLock::new(Compression::None),
+            legacy_handshake: RwLock::new(false),
+            transport: RwLock::new(Arc::new(TcpTransport)),
+            heartbeat_config: Arc::new(RwLock::new(HeartbeatConfig::default())),
+            group_job: None,
+            events,
+            event_listener_job: RwLock::new(None),
             tunnel_sender, // Tunnel往这里写
             tunnel_receiver: Some(tunnel_receiver), // 这里数据转发给Tunnel
             proxy_map: proxy_map.clone(),
@@ -65,6 +306,7 @@ impl TunnelContext {
         };
         // 开启读取tunnel数据包线程
         context.start_tunnel_receiver_job();
+        context.start_group_job();
         context
     }
 
@@ -79,6 +321,7 @@ impl TunnelContext {
         if let Some(connect_info) = guard.remove(source_addr) {
             connect_info.close();
         }
+        self.traffic.close(source_addr).await;
     }
 
     /// 设置域名匹配规则 json格式的
@@ -104,27 +347,22 @@ impl TunnelContext {
                             } else { continue; }
                         } else { continue; };
 
-                        match matching {
-                            0 => {
-                                self.domain_rule_matcher.write().await.push(Box::new(AllDomainMatcher::new(domain.to_string(), proxy_type as i32)));
-                            }
-                            1 => {
-                                self.domain_rule_matcher.write().await.push(Box::new(SuffixDomainMatcher::new(domain.to_string(), proxy_type as i32)));
-                            }
-                            2 => {
-                                self.domain_rule_matcher.write().await.push(Box::new(KeywordDomainMatcher::new(domain.to_string(), proxy_type as i32)));
-                            }
-                            3 => {
-                                self.domain_rule_matcher.write().await.push(Box::new(IPV4DomainMatcher::new(domain.to_string(), proxy_type as i32)));
-                            }
-                            6 => {
-                                self.domain_rule_matcher.write().await.push(Box::new(GEOIPMatcher::new(domain.to_string(), proxy_type as i32)));
-                            }
-                            10 => {
-                                self.domain_rule_matcher.write().await.push(Box::new(MatchMatcher::new(domain.to_string(), proxy_type as i32)));
-                            }
-                            _ => {}
+                        let matcher: Box<dyn RuleMatcher> = match matching {
+                            0 => { Box::new(AllDomainMatcher::new(domain.to_string(), proxy_type as i32)) }
+                            1 => { Box::new(SuffixDomainMatcher::new(domain.to_string(), proxy_type as i32)) }
+                            2 => { Box::new(KeywordDomainMatcher::new(domain.to_string(), proxy_type as i32)) }
+                            3 => { Box::new(IPV4DomainMatcher::new(domain.to_string(), proxy_type as i32)) }
+                            6 => { Box::new(GEOIPMatcher::new(domain.to_string(), proxy_type as i32)) }
+                            10 => { Box::new(MatchMatcher::new(domain.to_string(), proxy_type as i32)) }
+                            _ => { continue; }
+                        };
+                        // 可选的限速 字节每秒
+                        let limit = RateLimit {
+                            upload: item.get("uploadLimit").and_then(|r| r.as_u64()).unwrap_or(0),
+                            download: item.get("downloadLimit").and_then(|r| r.as_u64()).unwrap_or(0),
                         };
+                        let limiter = if limit == RateLimit::default() { None } else { Some(Arc::new(RateLimiter::new(limit))) };
+                        self.domain_rule_matcher.write().await.push(DomainRule { domain: domain.to_string(), matcher, limiter });
                     }
                 }
             }
@@ -137,14 +375,34 @@ impl TunnelContext {
 
     /// 使用匹配器匹配域名
     pub async fn match_domain(&self, domain: &String) -> ProxyType {
-        return if self.proxy_type == ProxyType::Proxy {
-            for matcher in self.domain_rule_matcher.read().await.iter() {
-                if let Some(proxy_type) = matcher.do_match(domain) {
-                    return proxy_type;
-                }
-            }
-            return ProxyType::Redirect;
-        } else { self.proxy_type.clone() };
+        self.match_rule(domain).await.0
+    }
+
+    /// 设置全局限速 立即对所有连接生效
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.global_limiter.set_limit(limit);
+    }
+
+    /// 设置每个连接的限速 之后新建的连接生效
+    pub async fn set_connection_rate_limit(&self, limit: RateLimit) {
+        *self.connection_limit.write().await = limit;
+    }
+
+    /// 全局限速的当前状态
+    pub fn get_rate_limit(&self) -> RateLimitSnapshot {
+        self.global_limiter.snapshot()
+    }
+
+    /// 每个连接的限速配置
+    pub async fn get_connection_rate_limit(&self) -> RateLimit {
+        *self.connection_limit.read().await
+    }
+
+    /// 带限速的规则的当前状态 规则域名-状态
+    pub async fn get_rule_rate_limits(&self) -> Vec<(String, RateLimitSnapshot)> {
+        self.domain_rule_matcher.read().await.iter().filter_map(|rule| {
+            rule.limiter.as_ref().map(|limiter| (rule.domain.clone(), limiter.snapshot()))
+        }).collect()
     }
 
     /// 设置代理规则
@@ -152,163 +410,432 @@ impl TunnelContext {
         self.proxy_type = ProxyType::from_index(id);
     }
 
-    /// 获取隧道的上传流量
+    /// 设置隧道加密套件，下次连接隧道时生效
+    pub async fn set_cipher_suite(&self, cipher_suite: CipherSuite) {
+        *self.cipher_suite.write().await = cipher_suite;
+    }
+
+    /// 设置隧道压缩算法，下次连接隧道时生效
+    pub async fn set_compression(&self, compression: Compression) {
+        *self.compression.write().await = compression;
+    }
+
+    /// 设置是否允许旧版本握手，之后添加的服务器生效
+    /// 旧版本没有帧计数器和登录时间戳，只在服务端还未升级时开启
+    pub async fn set_legacy_handshake(&self, enabled: bool) {
+        *self.legacy_handshake.write().await = enabled;
+    }
+
+    /// 设置隧道传输方式，之后连接或添加的服务器生效
+    pub async fn set_transport(&self, transport: Arc<dyn Transport>) {
+        *self.transport.write().await = transport;
+    }
+
+    /// 获取隧道的上传流量 所有服务器之和
+    /// 返回上次读取以来的增量，兼容旧的用法；累计值见get_traffic
     pub async fn get_tunnel_upload(&self) -> i64 {
-        let read_guard = self.tunnel.read().await;
-        return if let Some(tunnel) = read_guard.as_ref() {
-            tunnel.get_upload().await
-        } else {
-            0
-        };
+        let mut upload = 0;
+        for server in self.group.read().await.get_servers().iter() {
+            upload += server.get_upload().await;
+        }
+        upload
     }
 
-    /// 获取隧道的下载流量
+    /// 获取隧道的下载流量 所有服务器之和
+    /// 返回上次读取以来的增量，兼容旧的用法；累计值见get_traffic
     pub async fn get_tunnel_download(&self) -> i64 {
-        let read_guard = self.tunnel.read().await;
-        return if let Some(tunnel) = read_guard.as_ref() {
-            tunnel.get_download().await
-        } else {
-            0
-        };
+        let mut download = 0;
+        for server in self.group.read().await.get_servers().iter() {
+            download += server.get_download().await;
+        }
+        download
+    }
+
+    /// 活跃连接的流量
+    pub async fn get_connection_traffic(&self) -> Vec<Arc<ConnectionTraffic>> {
+        self.traffic.connections().await
+    }
+
+    /// 某一分类的累计流量
+    pub fn get_traffic(&self, kind: TrafficKind) -> TrafficSnapshot {
+        self.traffic.snapshot(kind)
+    }
+
+    /// 开始统计一个直连或隧道连接
+    pub async fn open_connection_traffic(&self, source_addr: &str, target_addr: &str, kind: TrafficKind) -> Arc<ConnectionTraffic> {
+        let limiter = self.connection_limiter(target_addr).await;
+        self.traffic.open(source_addr, target_addr, kind, limiter).await
+    }
+
+    /// 记录被规则拒绝的连接
+    pub fn record_reject(&self, len: usize) {
+        self.traffic.reject(len);
+    }
+
+    /// 获取隧道发送方向的压缩统计 所有服务器之和
+    pub async fn get_tunnel_upload_compression(&self) -> CompressionSummary {
+        let mut summary = CompressionSummary::default();
+        for server in self.group.read().await.get_servers().iter() {
+            summary.add(server.get_upload_compression().await);
+        }
+        summary
+    }
+
+    /// 获取隧道接收方向的压缩统计 所有服务器之和
+    pub async fn get_tunnel_download_compression(&self) -> CompressionSummary {
+        let mut summary = CompressionSummary::default();
+        for server in self.group.read().await.get_servers().iter() {
+            summary.add(server.get_download_compression().await);
+        }
+        summary
     }
     /// 获取隧道的Ping延迟
     pub async fn get_tunnel_ping_delay(&self) -> i32 {
-        let read_guard = self.tunnel.read().await;
-        return if let Some(tunnel) = read_guard.as_ref() {
-            tunnel.get_ping_delay().await as i32
+        return if let Some(server) = self.selected_server().await {
+            server.get_ping_delay().await as i32
         } else {
             0
         };
     }
 
+    /// 获取隧道的延迟统计
+    pub async fn get_tunnel_rtt_summary(&self) -> RttSummary {
+        return if let Some(server) = self.selected_server().await {
+            server.get_rtt_summary().await
+        } else {
+            RttSummary::default()
+        };
+    }
+
+    /// 设置心跳间隔和判定隧道断开前允许丢失的PONG数量
+    pub async fn set_heartbeat(&self, interval: Duration, max_missed: u32) {
+        *self.heartbeat_config.write().await = HeartbeatConfig {
+            interval,
+            max_missed: max_missed.max(1),
+        };
+    }
+
     /// 获取隧道的状态
     pub async fn get_tunnel_status(&self) -> i32 {
-        let read_guard = self.tunnel.read().await;
-        return if let Some(tunnel) = read_guard.as_ref() {
-            match tunnel.get_status().await {
-                TunnelStatus::Success => {0}
-                TunnelStatus::WaitLogin => {1}
-                TunnelStatus::Logout => {2}
-            }
+        return if let Some(server) = self.selected_server().await {
+            server.get_status().await.as_index()
         } else {
-            2
+            TunnelStatus::Logout.as_index()
         };
     }
 
+    /// 获取隧道本次断线以来的重连次数
+    pub async fn get_reconnect_attempts(&self) -> u32 {
+        return if let Some(server) = self.selected_server().await {
+            server.get_reconnect_state().await.attempts
+        } else {
+            0
+        };
+    }
+
+    /// 获取隧道最后一次连接失败的原因
+    pub async fn get_last_error(&self) -> Option<String> {
+        self.selected_server().await?.get_reconnect_state().await.last_error
+    }
+
     ///连接Tunnel
-    pub async fn connect_tunnel(&self, host: String, port: u16, password: String) -> Result<(), String> {
-        let mut write_guard = self.tunnel.write().await;
+    /// 兼容单服务器的用法，会替换掉已有的全部服务器
+    /// 首次连接失败也会返回错误，之后由守护线程负责断线重连
+    pub async fn connect_tunnel(&self, host: String, port: u16, password: String) -> Result<(), TunnelError> {
+        self.close_tunnel().await;
+        self.group.write().await.clear();
+        for (stream_id, sender) in self.proxy_map.write().await.drain() {
+            close_local_stream(&sender, stream_id);
+        }
+        self.stream_ids.write().await.clear();
+        self.stream_traffic.write().await.clear();
+        return self.add_tunnel_server(DEFAULT_SERVER_NAME.to_string(), host, port, password).await;
+    }
 
-        if let Some(mut tunnel) = write_guard.take() {
-            tunnel.disconnect().await;
+    /// 添加隧道服务器并连接，同名服务器会被替换
+    /// 首次连接失败也会返回错误，之后由守护线程负责断线重连
+    pub async fn add_tunnel_server(&self, name: String, host: String, port: u16, password: String) -> Result<(), TunnelError> {
+        let server = Arc::new(TunnelServer::new(name, host, port, password, self.transport.read().await.clone(), self.tunnel_sender.clone(), self.proxy_map.clone(), self.events.clone()));
+        let old_server = self.group.write().await.add_server(server.clone());
+        if let Some(old_server) = old_server {
+            self.close_server(&old_server).await;
         }
-        self.proxy_map.write().await.clear();
-        return match Tunnel::new(host, port, password, self.tunnel_sender.clone()).await {
-            Ok(tunnel) => {
-                *write_guard = Some(tunnel);
-                Ok(())
+        let cipher_suite = *self.cipher_suite.read().await;
+        let compression = *self.compression.read().await;
+        let legacy_handshake = *self.legacy_handshake.read().await;
+        let result = server.connect(cipher_suite, compression, legacy_handshake, self.heartbeat_config.clone()).await;
+        refresh_group(&self.group).await;
+        return result;
+    }
+
+    /// 删除隧道服务器，经过它的连接会被关闭
+    pub async fn remove_tunnel_server(&self, name: &str) -> Result<(), TunnelError> {
+        let server = self.group.write().await.remove_server(name);
+        return if let Some(server) = server {
+            self.close_server(&server).await;
+            refresh_group(&self.group).await;
+            Ok(())
+        } else {
+            Err(TunnelError::TunnelUnavailable(format!("Tunnel server {} not found", name)))
+        };
+    }
+
+    /// 设置服务器选择策略
+    pub async fn set_group_strategy(&self, strategy: GroupStrategy) {
+        self.group.write().await.set_strategy(strategy);
+        refresh_group(&self.group).await;
+    }
+
+    /// 通过隧道服务端解析域名 返回A和AAAA记录
+    pub async fn resolve_remote(&self, host: &str) -> Result<Vec<ResolveRecord>, TunnelError> {
+        let server = match self.selected_server().await {
+            Some(server) => { server }
+            None => { return Err(TunnelError::TunnelUnavailable("Tunnel is none".to_string())); }
+        };
+        if !server.get_capabilities().await.contains(Capabilities::REMOTE_DNS) {
+            return Err(TunnelError::TunnelUnavailable("Tunnel not support remote dns".to_string()));
+        }
+        // 0保留给不属于任何连接的数据包
+        let request_id = loop {
+            let request_id = self.next_resolve_id.fetch_add(1, Ordering::Relaxed);
+            if request_id != 0 {
+                break request_id;
             }
-            Err(e) => {
-                Err(e.to_string())
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.pending_resolves.write().await.insert(request_id, sender);
+        let result = match server.write_to_tunnel(resolve_package(request_id, host)).await {
+            Ok(_) => {
+                match timeout(RESOLVE_TIMEOUT, receiver).await {
+                    Ok(Ok(tunnel_package)) => { Ok(tunnel_package) }
+                    Ok(Err(_)) => { Err(TunnelError::TunnelUnavailable("Tunnel is closed".to_string())) }
+                    Err(_) => { Err(TunnelError::Timeout(format!("Resolve {} timeout", host))) }
+                }
             }
+            Err(e) => { Err(e) }
         };
+        self.pending_resolves.write().await.remove(&request_id);
+
+        let tunnel_package = result?;
+        match ResolveResult::from_byte_array(tunnel_package.data.as_deref().unwrap_or_default()).map_err(TunnelError::Protocol)? {
+            ResolveResult::Success(records) => { Ok(records) }
+            // 服务端解析失败
+            ResolveResult::Fail(reason) => { Err(io::Error::new(io::ErrorKind::NotFound, reason).into()) }
+        }
+    }
+
+    /// 订阅隧道状态事件 只能收到订阅之后的事件
+    pub fn subscribe_events(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.subscribe()
+    }
+
+    /// 设置隧道状态事件的监听器 替换之前的监听器，为None时取消监听
+    pub async fn set_event_listener(&self, listener: Option<Box<dyn Fn(TunnelEvent) + Send + Sync>>) {
+        if let Some(job) = self.event_listener_job.write().await.take() {
+            job.abort();
+        }
+        let listener = match listener {
+            Some(listener) => { listener }
+            None => { return; }
+        };
+        let mut receiver = self.events.subscribe();
+        let job = spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => { listener(event); }
+                    Err(RecvError::Lagged(n)) => {
+                        log::error!("tunnel event listener lagged, {} events dropped", n);
+                    }
+                    Err(RecvError::Closed) => { break; }
+                }
+            }
+        });
+        *self.event_listener_job.write().await = Some(job);
+    }
+
+    /// 获取所有隧道服务器的信息
+    pub async fn get_tunnel_servers(&self) -> Vec<TunnelServerInfo> {
+        let group = self.group.read().await;
+        let selected = group.get_selected();
+        let mut infos = vec![];
+        for server in group.get_servers().iter() {
+            let is_selected = selected.as_ref().is_some_and(|selected| Arc::ptr_eq(selected, server));
+            infos.push(server.get_info(is_selected).await);
+        }
+        return infos;
+    }
+
+    /// 关闭服务器并释放经过它的连接
+    async fn close_server(&self, server: &Arc<TunnelServer>) {
+        server.close().await;
+        self.stream_map.write().await.retain(|_, stream_server| !Arc::ptr_eq(stream_server, server));
     }
 
     /// 关闭隧道连接
     pub async fn close_tunnel(&self) {
-        let mut tunnel_guard = self.tunnel.write().await;
-        if let Some(mut tunnel) = tunnel_guard.take() {
-            tunnel.disconnect().await;
+        let servers = self.group.read().await.get_servers().clone();
+        for server in servers.iter() {
+            server.close().await;
         }
+        self.stream_map.write().await.clear();
     }
 
-    /// 添加代理映射
-    pub async fn add_proxy_mapping(&self, source_addr: String, sender: Sender<TunnelPackage>) {
-        self.proxy_map.write().await.insert(source_addr, sender);
+    /// 添加代理映射 返回连接编号，之后用编号收发数据
+    /// 隧道数据先进入连接自己的队列，本地消费后再向服务端归还窗口
+    pub async fn add_proxy_mapping(&self, source_addr: String, sender: Sender<TunnelPackage>) -> u32 {
+        let stream_id = self.stream_id(&source_addr).await;
+        let (queue_sender, mut queue_receiver) = channel::<TunnelPackage>(STREAM_QUEUE_SIZE);
+        let stream_map = self.stream_map.clone();
+        let stream_traffic = self.stream_traffic.clone();
+        let traffic = self.traffic.clone();
+        spawn(async move {
+            let mut consumed = 0u32;
+            let mut connection_traffic = None;
+            while let Some(tunnel_package) = queue_receiver.recv().await {
+                let len = window_cost(&tunnel_package);
+                // 归还的窗口和服务端消耗的信用一致 多路复用时UDP数据不归还
+                let server = stream_map.read().await.get(&stream_id).cloned();
+                let cost = match server.as_ref() {
+                    Some(server) => { server.receive_window(stream_id).await.map_or(0, |window| window.cost(&tunnel_package.protocol, len as usize)) }
+                    None => { 0 }
+                };
+                // 映射可能先于连接建立 找到连接前直接记到隧道分类上，也不限速
+                if connection_traffic.is_none() {
+                    connection_traffic = stream_traffic.read().await.get(&stream_id).cloned();
+                }
+                if len > 0 {
+                    if let Some(connection_traffic) = connection_traffic.as_ref() {
+                        connection_traffic.throttle_download(len as usize).await;
+                    }
+                }
+                if sender.send(tunnel_package).await.is_err() {
+                    break;
+                }
+                if len > 0 {
+                    match connection_traffic.as_ref() {
+                        Some(connection_traffic) => { connection_traffic.record_download(len as usize); }
+                        None => { traffic.record_download(TrafficKind::Tunnel, len as usize); }
+                    }
+                }
+                consumed += cost;
+                if consumed >= WINDOW_UPDATE_THRESHOLD {
+                    if let Some(server) = server {
+                        let _ = server.grant_receive_window(stream_id, consumed).await;
+                    }
+                    consumed = 0;
+                }
+            }
+        });
+        self.proxy_map.write().await.insert(stream_id, queue_sender);
+        stream_id
     }
 
     /// 删除代理映射
     pub async fn remove_proxy_mapping(&self, source_addr: &String) {
-        self.proxy_map.write().await.remove(source_addr);
+        let stream_id = self.stream_ids.read().await.get(source_addr).copied();
+        if let Some(stream_id) = stream_id {
+            self.proxy_map.write().await.remove(&stream_id);
+        }
     }
 
-    /// 发送连接服务端命令
-    pub async fn tunnel_connect_server(&self, target_addr: String, source_addr: String) -> Result<(), String> {
+    /// 发送连接服务端命令 返回连接编号，之后用编号发送数据
+    pub async fn tunnel_connect_server(&self, target_addr: String, source_addr: String) -> Result<u32, TunnelError> {
         log::error!("connect to: {}", target_addr);
-        if self.tunnel.read().await.is_none() {
-            return Err("Tunnel is none".to_string());
-        }
-
-        let mut write_guard = self.tunnel.write().await;
-        if let Some(mut tunnel) = write_guard.take() {
-            let tunnel_package = TunnelPackage {
-                cmd: PackageCmd::NewConnect,
-                protocol: PackageProtocol::TCP,
-                source_address: Some(source_addr),
-                target_address: Some(target_addr),
-                data: None,
-            };
-            if let Err(e) = tunnel.write_to_tunnel(tunnel_package).await {
-                *write_guard = Some(tunnel);
-                return Err(e.to_string());
-            }
-            *write_guard = Some(tunnel);
-        } else {
-            return Err("Tunnel is none".to_string());
-        }
+        let stream_id = self.stream_id(&source_addr).await;
+        let server = match self.select_server(stream_id, &target_addr).await {
+            Some(server) => { server }
+            None => { return Err(TunnelError::TunnelUnavailable("Tunnel is none".to_string())); }
+        };
+        self.open_stream_traffic(stream_id, &source_addr, &target_addr).await;
 
-        return Ok(());
+        let tunnel_package = TunnelPackage::new(PackageCmd::NewConnect, PackageProtocol::TCP, stream_id, Some(source_addr), Some(target_addr), None);
+        server.write_to_tunnel(tunnel_package).await?;
+        Ok(stream_id)
     }
 
-    /// 发送数据到Tunnel
-    pub async fn tunnel_send_data(&self, target_addr: String, source_addr: String, data: Vec<u8>, protocol: PackageProtocol) -> Result<(), String> {
-        if self.tunnel.read().await.is_none() {
-            return Err("Tunnel is none".to_string());
-        }
+    /// 按连接编号发送TCP数据到Tunnel
+    pub async fn tunnel_send_stream_data(&self, stream_id: u32, data: Vec<u8>) -> Result<(), TunnelError> {
+        let server = match self.stream_map.read().await.get(&stream_id) {
+            Some(server) => { server.clone() }
+            None => { return Err(TunnelError::TunnelUnavailable("Stream not found".to_string())); }
+        };
+        self.send_stream_data(server, stream_id, None, data, PackageProtocol::TCP).await
+    }
 
-        let mut write_guard = self.tunnel.write().await;
-        if let Some(mut tunnel) = write_guard.take() {
-            let tunnel_package = TunnelPackage {
-                cmd: PackageCmd::TData,
-                protocol,
-                source_address: Some(source_addr),
-                target_address: Some(target_addr),
-                data: Some(data),
-            };
-            if let Err(e) = tunnel.write_to_tunnel(tunnel_package).await {
-                *write_guard = Some(tunnel);
-                return Err(e.to_string());
+    /// 按连接编号发送UDP数据到Tunnel 第一个数据包按目标地址分配服务器
+    pub async fn tunnel_send_udp_data(&self, stream_id: u32, source_addr: &str, target_addr: String, data: Vec<u8>) -> Result<(), TunnelError> {
+        let server = self.stream_map.read().await.get(&stream_id).cloned();
+        let server = match server {
+            Some(server) => { server }
+            None => {
+                let server = match self.select_server(stream_id, &target_addr).await {
+                    Some(server) => { server }
+                    None => { return Err(TunnelError::TunnelUnavailable("Tunnel is none".to_string())); }
+                };
+                self.open_stream_traffic(stream_id, source_addr, &target_addr).await;
+                server
             }
-            *write_guard = Some(tunnel);
-        } else {
-            return Err("Tunnel is none".to_string());
-        }
-
-        return Ok(());
+        };
+        self.send_stream_data(server, stream_id, Some(target_addr), data, PackageProtocol::UDP).await
     }
 
     /// 发送关闭服务端连接命令
-    pub async fn tunnel_close_server(&self, source_addr: String) -> Result<(), String> {
+    pub async fn tunnel_close_server(&self, source_addr: String) -> Result<(), TunnelError> {
         log::error!("dis connect ,source addr: {}", source_addr);
-        if self.tunnel.read().await.is_none() {
-            return Err("Tunnel is none".to_string());
+        match self.stream_ids.write().await.remove(&source_addr) {
+            Some(stream_id) => { self.close_stream(stream_id, &source_addr).await }
+            // 没有建立过连接
+            None => { Ok(()) }
         }
+    }
 
-        let mut write_guard = self.tunnel.write().await;
-        if let Some(mut tunnel) = write_guard.take() {
-            let tunnel_package = TunnelPackage {
-                cmd: PackageCmd::CloseConnect,
-                protocol: PackageProtocol::TCP,
-                source_address: Some(source_addr),
-                target_address: None,
-                data: None,
-            };
-            let _ = tunnel.write_to_tunnel(tunnel_package).await;
-            *write_guard = Some(tunnel);
-        } else {
-            return Err("Tunnel is none".to_string());
+    /// 按连接编号发送关闭服务端连接命令
+    pub async fn tunnel_close_stream(&self, stream_id: u32) -> Result<(), TunnelError> {
+        let source_addr = match self.stream_traffic.read().await.get(&stream_id) {
+            Some(traffic) => { traffic.source_addr.clone() }
+            None => {
+                match self.stream_ids.read().await.iter().find(|(_, id)| **id == stream_id) {
+                    Some((source_addr, _)) => { source_addr.clone() }
+                    // 没有建立过连接
+                    None => { return Ok(()); }
+                }
+            }
+        };
+        log::error!("dis connect ,source addr: {}", source_addr);
+        if self.stream_ids.write().await.remove(&source_addr).is_none() {
+            return Ok(());
         }
+        self.close_stream(stream_id, &source_addr).await
+    }
+
+    /// 释放连接编号对应的服务器和流量统计 通知服务端关闭连接
+    async fn close_stream(&self, stream_id: u32, source_addr: &str) -> Result<(), TunnelError> {
+        let server = match self.stream_map.write().await.remove(&stream_id) {
+            Some(server) => { server }
+            None => {
+                match self.selected_server().await {
+                    Some(server) => { server }
+                    None => { return Err(TunnelError::TunnelUnavailable("Tunnel is none".to_string())); }
+                }
+            }
+        };
+        server.remove_stream(stream_id).await;
+        self.stream_traffic.write().await.remove(&stream_id);
+        self.traffic.close(source_addr).await;
+
+        let tunnel_package = TunnelPackage::new(PackageCmd::CloseConnect, PackageProtocol::TCP, stream_id, None, None, None);
+        let _ = server.write_to_tunnel(tunnel_package).await;
 
         return Ok(());
     }
 }
+#[tokio::test]
+async fn test_stream_id_wrap() {
+    let context = TunnelContext::new();
+    context.stream_ids.write().await.insert("127.0.0.1:1000".to_string(), 1);
+    context.next_stream_id.store(u32::MAX, Ordering::Relaxed);
+    assert_eq!(context.stream_id(&"127.0.0.1:1001".to_string()).await, u32::MAX);
+    // 回绕后跳过0和还在使用的编号
+    assert_eq!(context.stream_id(&"127.0.0.1:1002".to_string()).await, 2);
+    assert_eq!(context.stream_id(&"127.0.0.1:1000".to_string()).await, 1);
+}
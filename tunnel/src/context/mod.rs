@@ -1,4 +1,9 @@
 pub mod context;
 pub mod proxy_type;
 mod rule_matcher;
-mod connect_info;
\ No newline at end of file
+mod connect_info;
+pub mod reconnect;
+pub mod tunnel_server;
+pub mod tunnel_group;
+pub mod traffic;
+pub mod rate_limit;
\ No newline at end of file
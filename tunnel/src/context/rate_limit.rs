@@ -0,0 +1,190 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::time::sleep;
+
+/// 速率限制 字节每秒，0表示不限制
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// 令牌桶 容量为一秒的速率
+/// 令牌不足时先记账再等待，单次超过容量的数据也能通过
+pub struct TokenBucket {
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+    /// 累计等待的毫秒数
+    wait_ms: AtomicU64,
+}
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new(BucketState { tokens: rate as f64, last: Instant::now() }),
+            wait_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// 修改速率 原来不限速时桶是满的，已欠下的令牌按新速率补足
+    pub fn set_rate(&self, rate: u64) {
+        let mut state = self.state.lock().unwrap();
+        let old = self.rate.swap(rate, Ordering::Relaxed);
+        state.tokens = if old == 0 { rate as f64 } else { state.tokens.min(rate as f64) };
+        state.last = Instant::now();
+    }
+
+    /// 取len个令牌 不足时等待
+    pub async fn acquire(&self, len: usize) {
+        let wait = {
+            let rate = self.rate.load(Ordering::Relaxed);
+            if rate == 0 {
+                return;
+            }
+            let mut state = self.state.lock().unwrap();
+            Self::refill(&mut state, rate);
+            state.tokens -= len as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate as f64)
+        };
+        self.wait_ms.fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+        sleep(wait).await;
+    }
+
+    fn refill(state: &mut BucketState, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate as f64).min(rate as f64);
+        state.last = now;
+    }
+
+    pub fn snapshot(&self) -> BucketSnapshot {
+        let rate = self.rate.load(Ordering::Relaxed);
+        let tokens = if rate == 0 {
+            0
+        } else {
+            let mut state = self.state.lock().unwrap();
+            Self::refill(&mut state, rate);
+            state.tokens as i64
+        };
+        BucketSnapshot { rate, tokens, wait_ms: self.wait_ms.load(Ordering::Relaxed) }
+    }
+}
+
+/// 令牌桶的当前状态
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct BucketSnapshot {
+    pub rate: u64,
+    /// 可用令牌 为负时表示正在等待
+    pub tokens: i64,
+    pub wait_ms: u64,
+}
+
+/// 上传和下载两个方向的限速
+pub struct RateLimiter {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            upload: TokenBucket::new(limit.upload),
+            download: TokenBucket::new(limit.download),
+        }
+    }
+
+    pub fn set_limit(&self, limit: RateLimit) {
+        self.upload.set_rate(limit.upload);
+        self.download.set_rate(limit.download);
+    }
+
+    pub fn snapshot(&self) -> RateLimitSnapshot {
+        RateLimitSnapshot {
+            upload: self.upload.snapshot(),
+            download: self.download.snapshot(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RateLimitSnapshot {
+    pub upload: BucketSnapshot,
+    pub download: BucketSnapshot,
+}
+
+/// 单个连接的限速 依次经过全局、匹配规则和连接自己的令牌桶
+pub struct ConnectionLimiter {
+    global: Arc<RateLimiter>,
+    rule: Option<Arc<RateLimiter>>,
+    connection: RateLimiter,
+}
+
+impl ConnectionLimiter {
+    pub fn new(global: Arc<RateLimiter>, rule: Option<Arc<RateLimiter>>, connection: RateLimit) -> ConnectionLimiter {
+        ConnectionLimiter {
+            global,
+            rule,
+            connection: RateLimiter::new(connection),
+        }
+    }
+
+    /// 不限速 用于不经过上下文创建的连接
+    pub fn unlimited() -> ConnectionLimiter {
+        ConnectionLimiter::new(Arc::new(RateLimiter::new(RateLimit::default())), None, RateLimit::default())
+    }
+
+    pub async fn acquire_upload(&self, len: usize) {
+        self.global.upload.acquire(len).await;
+        if let Some(rule) = &self.rule {
+            rule.upload.acquire(len).await;
+        }
+        self.connection.upload.acquire(len).await;
+    }
+
+    pub async fn acquire_download(&self, len: usize) {
+        self.global.download.acquire(len).await;
+        if let Some(rule) = &self.rule {
+            rule.download.acquire(len).await;
+        }
+        self.connection.download.acquire(len).await;
+    }
+
+    /// 连接自己的令牌桶状态
+    pub fn snapshot(&self) -> RateLimitSnapshot {
+        self.connection.snapshot()
+    }
+}
+
+#[tokio::test]
+async fn test_token_bucket() {
+    // 不限速时不等待
+    let bucket = TokenBucket::new(0);
+    bucket.acquire(usize::MAX).await;
+    assert_eq!(bucket.snapshot(), BucketSnapshot::default());
+
+    // 桶满时一秒的量立即通过，之后按速率等待
+    let bucket = TokenBucket::new(10_000);
+    let start = Instant::now();
+    bucket.acquire(10_000).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+    bucket.acquire(2_000).await;
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert!(bucket.snapshot().wait_ms >= 150);
+
+    // 关闭限速后立即通过
+    bucket.set_rate(0);
+    let start = Instant::now();
+    bucket.acquire(1_000_000).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use openssl::rand::rand_bytes;
+
+/// 首次重连的退避时间
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// 最大退避时间
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// 检查隧道状态的间隔
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 隧道重连状态
+#[derive(Default, Clone)]
+pub struct ReconnectState {
+    /// 本次断线以来的重连次数，隧道登录成功后清零
+    pub attempts: u32,
+    /// 最后一次连接失败的原因
+    pub last_error: Option<String>,
+}
+
+/// 第attempt次重连前的等待时间
+/// 指数退避，并在[delay/2, delay]之间随机抖动，避免大量客户端同时重连
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(1u32 << attempt.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF);
+    let mut random = [0u8; 4];
+    let _ = rand_bytes(&mut random);
+    let half = delay / 2;
+    half + half.mul_f64(u32::from_be_bytes(random) as f64 / u32::MAX as f64)
+}
+
+/// 心跳配置
+#[derive(Copy, Clone)]
+pub struct HeartbeatConfig {
+    /// 发送PING的间隔
+    pub interval: Duration,
+    /// 连续丢失多少个PONG后判定隧道已断开
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(10),
+            max_missed: 3,
+        }
+    }
+}
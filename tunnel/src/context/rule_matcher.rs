@@ -1,12 +1,22 @@
+use std::sync::Arc;
+
 use ipnet::IpNet;
 
 use crate::context::proxy_type::ProxyType;
+use crate::context::rate_limit::RateLimiter;
 
 pub trait RuleMatcher: Send + Sync {
     fn new(domain: String, proxy_type: i32) -> Self where Self: Sized;
     fn do_match(&self, domain: &String) -> Option<ProxyType>;
 }
 
+/// 一条域名规则 可以带限速，匹配这条规则的连接共用
+pub struct DomainRule {
+    pub domain: String,
+    pub matcher: Box<dyn RuleMatcher>,
+    pub limiter: Option<Arc<RateLimiter>>,
+}
+
 
 pub struct AllDomainMatcher {
     domain: String,
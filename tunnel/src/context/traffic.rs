@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::RwLock;
+
+use crate::context::rate_limit::ConnectionLimiter;
+
+/// 流量分类
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrafficKind {
+    /// 经过隧道
+    Tunnel,
+    /// 直连
+    Direct,
+    /// 被规则拒绝
+    Reject,
+}
+
+impl TrafficKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficKind::Tunnel => { "tunnel" }
+            TrafficKind::Direct => { "direct" }
+            TrafficKind::Reject => { "reject" }
+        }
+    }
+}
+
+/// 流量计数 只增不减，速率由调用方按两次读取的差值计算
+#[derive(Default)]
+pub struct TrafficCounter {
+    connections: AtomicU64,
+    upload_bytes: AtomicU64,
+    download_bytes: AtomicU64,
+    upload_packets: AtomicU64,
+    download_packets: AtomicU64,
+}
+
+impl TrafficCounter {
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self, len: usize) {
+        self.upload_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.upload_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self, len: usize) {
+        self.download_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.download_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            upload_bytes: self.upload_bytes.load(Ordering::Relaxed),
+            download_bytes: self.download_bytes.load(Ordering::Relaxed),
+            upload_packets: self.upload_packets.load(Ordering::Relaxed),
+            download_packets: self.download_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 某一时刻的流量计数
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TrafficSnapshot {
+    /// 建立过的连接数
+    pub connections: u64,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+    pub upload_packets: u64,
+    pub download_packets: u64,
+}
+
+/// 单个连接的流量 同时累加到所属分类
+pub struct ConnectionTraffic {
+    pub source_addr: String,
+    pub target_addr: String,
+    pub kind: TrafficKind,
+    pub limiter: ConnectionLimiter,
+    counter: TrafficCounter,
+    total: Arc<TrafficCounter>,
+}
+
+impl ConnectionTraffic {
+    /// 按限速等待后再发送
+    pub async fn throttle_upload(&self, len: usize) {
+        self.limiter.acquire_upload(len).await;
+    }
+
+    pub async fn throttle_download(&self, len: usize) {
+        self.limiter.acquire_download(len).await;
+    }
+
+    pub fn record_upload(&self, len: usize) {
+        self.counter.record_upload(len);
+        self.total.record_upload(len);
+    }
+
+    pub fn record_download(&self, len: usize) {
+        self.counter.record_download(len);
+        self.total.record_download(len);
+    }
+
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        self.counter.snapshot()
+    }
+}
+
+/// 上下文的流量统计 按分类累计，活跃连接单独统计
+#[derive(Default)]
+pub struct TrafficStats {
+    tunnel: Arc<TrafficCounter>,
+    direct: Arc<TrafficCounter>,
+    reject: Arc<TrafficCounter>,
+    /// 源地址-连接流量
+    connections: RwLock<HashMap<String, Arc<ConnectionTraffic>>>,
+}
+
+impl TrafficStats {
+    fn counter(&self, kind: TrafficKind) -> &Arc<TrafficCounter> {
+        match kind {
+            TrafficKind::Tunnel => { &self.tunnel }
+            TrafficKind::Direct => { &self.direct }
+            TrafficKind::Reject => { &self.reject }
+        }
+    }
+
+    /// 开始统计一个连接 已存在时返回原来的统计
+    pub async fn open(&self, source_addr: &str, target_addr: &str, kind: TrafficKind, limiter: ConnectionLimiter) -> Arc<ConnectionTraffic> {
+        if let Some(traffic) = self.connections.read().await.get(source_addr) {
+            return traffic.clone();
+        }
+        self.connections.write().await.entry(source_addr.to_string()).or_insert_with(|| {
+            let total = self.counter(kind).clone();
+            total.record_connection();
+            Arc::new(ConnectionTraffic {
+                source_addr: source_addr.to_string(),
+                target_addr: target_addr.to_string(),
+                kind,
+                limiter,
+                counter: TrafficCounter::default(),
+                total,
+            })
+        }).clone()
+    }
+
+    /// 连接关闭后不再单独统计 分类的累计值保留
+    pub async fn close(&self, source_addr: &str) {
+        self.connections.write().await.remove(source_addr);
+    }
+
+    /// 记录一个被拒绝的连接 上传为丢弃的请求数据
+    pub fn reject(&self, len: usize) {
+        self.reject.record_connection();
+        self.reject.record_upload(len);
+    }
+
+    /// 不属于任何连接的流量直接记到分类上
+    pub fn record_download(&self, kind: TrafficKind, len: usize) {
+        self.counter(kind).record_download(len);
+    }
+
+    pub fn snapshot(&self, kind: TrafficKind) -> TrafficSnapshot {
+        self.counter(kind).snapshot()
+    }
+
+    /// 活跃连接的流量
+    pub async fn connections(&self) -> Vec<Arc<ConnectionTraffic>> {
+        self.connections.read().await.values().cloned().collect()
+    }
+}
+
+#[tokio::test]
+async fn test_traffic_stats() {
+    let stats = TrafficStats::default();
+    let traffic = stats.open("127.0.0.1:5000", "example.com:443", TrafficKind::Tunnel, ConnectionLimiter::unlimited()).await;
+    traffic.record_upload(100);
+    traffic.record_download(300);
+    // 同一个连接重复打开不会重复计数
+    stats.open("127.0.0.1:5000", "example.com:443", TrafficKind::Tunnel, ConnectionLimiter::unlimited()).await.record_upload(50);
+    stats.reject(20);
+
+    let tunnel = stats.snapshot(TrafficKind::Tunnel);
+    assert_eq!(tunnel, TrafficSnapshot { connections: 1, upload_bytes: 150, download_bytes: 300, upload_packets: 2, download_packets: 1 });
+    assert_eq!(traffic.snapshot(), TrafficSnapshot { connections: 0, ..tunnel });
+    assert_eq!(stats.snapshot(TrafficKind::Reject).connections, 1);
+    assert_eq!(stats.snapshot(TrafficKind::Direct), TrafficSnapshot::default());
+
+    // 关闭连接后分类的累计值不变
+    stats.close("127.0.0.1:5000").await;
+    assert!(stats.connections().await.is_empty());
+    assert_eq!(stats.snapshot(TrafficKind::Tunnel), tunnel);
+}
@@ -0,0 +1,297 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::RwLock;
+
+use crate::context::tunnel_server::TunnelServer;
+
+/// 延迟容差(毫秒) 新服务器的平均延迟至少低这么多才切换，避免来回切换
+const TOLERANCE_MS: u32 = 50;
+/// 一致性哈希环上每台服务器的虚拟节点数
+const VIRTUAL_NODES: u32 = 160;
+
+/// 服务器选择策略
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum GroupStrategy {
+    /// 按PING/PONG延迟选择最快的可用服务器
+    #[default]
+    UrlTest,
+    /// 按添加顺序选择第一个可用的服务器，主服务器恢复后切回
+    Fallback,
+    /// 按目标主机一致性哈希分配到可用的服务器，同一站点固定走同一出口
+    ConsistentHash,
+    /// 新连接轮流分配到可用的服务器
+    RoundRobin,
+}
+
+impl GroupStrategy {
+    pub fn from_index(i: i32) -> Self {
+        match i {
+            0 => {
+                GroupStrategy::UrlTest
+            }
+            1 => {
+                GroupStrategy::Fallback
+            }
+            2 => {
+                GroupStrategy::ConsistentHash
+            }
+            3 => {
+                GroupStrategy::RoundRobin
+            }
+            _ => {
+                GroupStrategy::UrlTest
+            }
+        }
+    }
+}
+
+/// 选择时使用的服务器状态
+pub struct ServerStatus {
+    server: Arc<TunnelServer>,
+    healthy: bool,
+    /// 平均延迟 还没有延迟样本时为u32::MAX
+    latency: u32,
+}
+
+/// 隧道服务器组
+/// 按策略选择可用的服务器，延迟或可用状态变化时自动切换
+#[derive(Default)]
+pub struct TunnelGroup {
+    strategy: GroupStrategy,
+    servers: Vec<Arc<TunnelServer>>,
+    selected: Option<Arc<TunnelServer>>,
+    /// 上次选择时可用的服务器
+    healthy: Vec<Arc<TunnelServer>>,
+    /// 可用服务器的一致性哈希环 按哈希值排序
+    ring: Vec<(u64, Arc<TunnelServer>)>,
+    round_robin: AtomicUsize,
+}
+
+impl TunnelGroup {
+    /// 添加服务器 返回被替换的同名服务器
+    pub fn add_server(&mut self, server: Arc<TunnelServer>) -> Option<Arc<TunnelServer>> {
+        let old_server = self.remove_server(&server.name);
+        self.servers.push(server);
+        old_server
+    }
+
+    /// 删除服务器
+    pub fn remove_server(&mut self, name: &str) -> Option<Arc<TunnelServer>> {
+        let index = self.servers.iter().position(|server| server.name == name)?;
+        let server = self.servers.remove(index);
+        if self.selected.as_ref().is_some_and(|selected| selected.name == name) {
+            self.selected = None;
+        }
+        self.healthy.retain(|healthy| !Arc::ptr_eq(healthy, &server));
+        self.ring.retain(|(_, node)| !Arc::ptr_eq(node, &server));
+        Some(server)
+    }
+
+    /// 删除所有服务器
+    pub fn clear(&mut self) -> Vec<Arc<TunnelServer>> {
+        self.selected = None;
+        self.healthy.clear();
+        self.ring.clear();
+        self.servers.drain(..).collect()
+    }
+
+    pub fn get_servers(&self) -> &Vec<Arc<TunnelServer>> {
+        &self.servers
+    }
+
+    /// 获取当前选中的服务器
+    pub fn get_selected(&self) -> Option<Arc<TunnelServer>> {
+        self.selected.clone()
+    }
+
+    /// 设置选择策略 之后需要重新选择服务器
+    pub fn set_strategy(&mut self, strategy: GroupStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn get_strategy(&self) -> GroupStrategy {
+        self.strategy
+    }
+
+    /// 按策略和服务器状态重新选择服务器 没有状态的服务器视为不可用
+    /// 负载均衡策略下选中的服务器只用于展示延迟和状态
+    pub fn update_selected(&mut self, status: &[ServerStatus]) {
+        let healthy: Vec<&ServerStatus> = self.servers.iter()
+            .filter_map(|server| status.iter().find(|status| Arc::ptr_eq(&status.server, server)))
+            .filter(|status| status.healthy)
+            .collect();
+        match self.strategy {
+            GroupStrategy::UrlTest => { self.select_fastest(&healthy) }
+            GroupStrategy::Fallback => { self.select_first_healthy(&healthy) }
+            GroupStrategy::ConsistentHash | GroupStrategy::RoundRobin => {
+                self.select_first_healthy(&healthy);
+                let healthy: Vec<Arc<TunnelServer>> = healthy.iter().map(|status| status.server.clone()).collect();
+                if self.strategy == GroupStrategy::ConsistentHash {
+                    self.ring = build_ring(&healthy);
+                }
+                self.healthy = healthy;
+            }
+        }
+    }
+
+    /// 为连接到目标主机的新连接分配服务器
+    pub fn pick_server(&self, target_host: &str) -> Option<Arc<TunnelServer>> {
+        match self.strategy {
+            GroupStrategy::UrlTest | GroupStrategy::Fallback => { self.selected.clone() }
+            GroupStrategy::ConsistentHash => {
+                lookup_ring(&self.ring, target_host).or_else(|| self.selected.clone())
+            }
+            GroupStrategy::RoundRobin => {
+                if self.healthy.is_empty() {
+                    return self.selected.clone();
+                }
+                let index = self.round_robin.fetch_add(1, Ordering::Relaxed) % self.healthy.len();
+                Some(self.healthy[index].clone())
+            }
+        }
+    }
+
+    /// 选择延迟最低的服务器
+    /// 当前服务器不可用，或者有服务器比它快超过容差时切换
+    // Option::is_none_or需要Rust 1.82
+    #[allow(clippy::unnecessary_map_or)]
+    fn select_fastest(&mut self, healthy: &[&ServerStatus]) {
+        let mut best: Option<&ServerStatus> = None;
+        let mut current_latency: Option<u32> = None;
+        for status in healthy.iter() {
+            if self.selected.as_ref().is_some_and(|selected| Arc::ptr_eq(selected, &status.server)) {
+                current_latency = Some(status.latency);
+            }
+            if best.map_or(true, |best| status.latency < best.latency) {
+                best = Some(status);
+            }
+        }
+
+        match (best, current_latency) {
+            (Some(best), Some(current_latency)) => {
+                if best.latency.saturating_add(TOLERANCE_MS) < current_latency {
+                    log::error!("tunnel group switch to {} ({}ms)", best.server.name, best.latency);
+                    self.selected = Some(best.server.clone());
+                }
+            }
+            (Some(best), None) => {
+                log::error!("tunnel group select {} ({}ms)", best.server.name, best.latency);
+                self.selected = Some(best.server.clone());
+            }
+            // 没有可用的服务器时等待重连
+            (None, _) => {
+                self.keep_selected();
+            }
+        }
+    }
+
+    /// 选择第一个可用的服务器
+    fn select_first_healthy(&mut self, healthy: &[&ServerStatus]) {
+        match healthy.first() {
+            Some(status) => {
+                if !self.selected.as_ref().is_some_and(|selected| Arc::ptr_eq(selected, &status.server)) {
+                    log::error!("tunnel group fallback to {}", status.server.name);
+                    self.selected = Some(status.server.clone());
+                }
+            }
+            None => { self.keep_selected(); }
+        }
+    }
+
+    /// 没有可用的服务器时保持原来的选择，没有选择过则使用第一个服务器
+    fn keep_selected(&mut self) {
+        if self.selected.is_none() {
+            self.selected = self.servers.first().cloned();
+        }
+    }
+}
+
+/// 重新选择服务器
+/// 读取各服务器的隧道状态时不持有服务器组的锁，避免一台服务器的隧道锁阻塞整个组
+pub async fn refresh_group(group: &RwLock<TunnelGroup>) {
+    let servers = group.read().await.get_servers().clone();
+    let mut status = Vec::with_capacity(servers.len());
+    for server in servers {
+        let healthy = server.is_healthy().await;
+        let latency = if healthy { latency(&server).await } else { u32::MAX };
+        status.push(ServerStatus { server, healthy, latency });
+    }
+    group.write().await.update_selected(&status);
+}
+
+/// 服务器平均延迟 还没有延迟样本的排在最后
+async fn latency(server: &TunnelServer) -> u32 {
+    let rtt = server.get_rtt_summary().await;
+    if rtt.samples == 0 { u32::MAX } else { rtt.avg }
+}
+
+/// 构建一致性哈希环
+/// 每台服务器按名称放置多个虚拟节点，增删服务器时只有相邻区间的主机会换出口
+fn build_ring(servers: &[Arc<TunnelServer>]) -> Vec<(u64, Arc<TunnelServer>)> {
+    let mut ring = vec![];
+    for server in servers.iter() {
+        for node in 0..VIRTUAL_NODES {
+            ring.push((hash(format!("{}#{}", server.name, node).as_bytes()), server.clone()));
+        }
+    }
+    ring.sort_by_key(|(hash, _)| *hash);
+    ring
+}
+
+/// 顺时针找到第一个不小于主机哈希值的节点
+fn lookup_ring(ring: &[(u64, Arc<TunnelServer>)], host: &str) -> Option<Arc<TunnelServer>> {
+    if ring.is_empty() {
+        return None;
+    }
+    let key = hash(host.as_bytes());
+    let index = ring.partition_point(|(hash, _)| *hash < key) % ring.len();
+    Some(ring[index].1.clone())
+}
+
+/// FNV-1a哈希 再经过splitmix64混淆 相近的字符串也能均匀分布
+fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[test]
+fn test_consistent_hash() {
+    use std::collections::HashMap;
+    use tokio::sync::mpsc::channel;
+    use tokio::sync::RwLock;
+    use crate::tunnel::transport::TcpTransport;
+
+    let (sender, _receiver) = channel(1);
+    let (events, _) = tokio::sync::broadcast::channel(1);
+    let proxy_map = Arc::new(RwLock::new(HashMap::new()));
+    let servers: Vec<Arc<TunnelServer>> = ["hk", "jp", "sg"].iter()
+        .map(|name| Arc::new(TunnelServer::new(name.to_string(), "127.0.0.1".to_string(), 0, "".to_string(), Arc::new(TcpTransport), sender.clone(), proxy_map.clone(), events.clone())))
+        .collect();
+    let hosts: Vec<String> = (0..1000).map(|i| format!("www.site{}.com", i)).collect();
+
+    let ring = build_ring(&servers);
+    let before: Vec<String> = hosts.iter().map(|host| lookup_ring(&ring, host).unwrap().name.clone()).collect();
+    // 同一主机总是分配到同一台服务器
+    assert_eq!(lookup_ring(&ring, &hosts[0]).unwrap().name, before[0]);
+    // 分布大致均匀
+    for server in servers.iter() {
+        let count = before.iter().filter(|name| **name == server.name).count();
+        assert!(count > 200, "{} {}", server.name, count);
+    }
+
+    // 删除一台服务器后 其余服务器上的主机不受影响
+    let ring = build_ring(&servers[1..]);
+    for (host, name) in hosts.iter().zip(before.iter()) {
+        let after = lookup_ring(&ring, host).unwrap();
+        if name != "hk" {
+            assert_eq!(&after.name, name);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::spawn;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::context::reconnect::{backoff_delay, CHECK_INTERVAL, HeartbeatConfig, ReconnectState};
+use crate::error::TunnelError;
+use crate::tunnel::cipher::CipherSuite;
+use crate::tunnel::compress::{Compression, CompressionSummary};
+use crate::tunnel::event::{EventPublisher, TunnelEvent, TunnelEventKind};
+use crate::tunnel::hello::Capabilities;
+use crate::tunnel::rtt::RttSummary;
+use crate::tunnel::transport::Transport;
+use crate::tunnel::tunnel::{Tunnel, TunnelStatus};
+use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+use crate::tunnel::window::{ReceiveWindow, SendWindow, window_update_package};
+
+/// 隧道服务器信息
+pub struct TunnelServerInfo {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub status: TunnelStatus,
+    pub rtt: RttSummary,
+    pub selected: bool,
+}
+
+/// 连接的发送窗口和接收窗口
+#[derive(Clone)]
+struct StreamWindow {
+    send: SendWindow,
+    receive: ReceiveWindow,
+}
+
+/// 隧道服务器
+/// 持有一条隧道连接和它的守护线程，隧道断开后自动重连
+pub struct TunnelServer {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    password: String,
+    transport: Arc<dyn Transport>,
+    tunnel: Arc<RwLock<Option<Tunnel>>>,
+    tunnel_sender: Sender<TunnelPackage>,
+    proxy_map: Arc<RwLock<HashMap<u32, Sender<TunnelPackage>>>>,
+    reconnect_state: Arc<RwLock<ReconnectState>>,
+    /// 经过这台服务器的连接编号和它们的窗口
+    streams: Arc<RwLock<HashMap<u32, StreamWindow>>>,
+    events: EventPublisher,
+    supervisor_job: RwLock<Option<JoinHandle<()>>>,
+}
+
+impl TunnelServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(name: String,
+               host: String,
+               port: u16,
+               password: String,
+               transport: Arc<dyn Transport>,
+               tunnel_sender: Sender<TunnelPackage>,
+               proxy_map: Arc<RwLock<HashMap<u32, Sender<TunnelPackage>>>>,
+               events: broadcast::Sender<TunnelEvent>) -> TunnelServer {
+        TunnelServer {
+            events: EventPublisher::new(name.clone(), events),
+            name,
+            host,
+            port,
+            password,
+            transport,
+            tunnel: Arc::new(RwLock::new(None)),
+            tunnel_sender,
+            proxy_map,
+            reconnect_state: Arc::new(RwLock::new(ReconnectState::default())),
+            streams: Arc::new(RwLock::new(HashMap::new())),
+            supervisor_job: RwLock::new(None),
+        }
+    }
+
+    /// 连接服务器并开启守护线程
+    /// 首次连接失败也会返回错误，之后由守护线程负责断线重连
+    pub async fn connect(&self, cipher_suite: CipherSuite, compression: Compression, legacy_handshake: bool, heartbeat_config: Arc<RwLock<HeartbeatConfig>>) -> Result<(), TunnelError> {
+        self.close().await;
+        *self.reconnect_state.write().await = ReconnectState::default();
+        self.events.publish(TunnelEventKind::Connecting { host: self.host.clone(), port: self.port });
+        let result = match Tunnel::new(self.host.clone(), self.port, self.password.clone(), cipher_suite, compression, legacy_handshake, self.transport.as_ref(), self.tunnel_sender.clone(), self.events.clone()).await {
+            Ok(tunnel) => {
+                *self.tunnel.write().await = Some(tunnel);
+                Ok(())
+            }
+            Err(e) => {
+                self.reconnect_state.write().await.last_error = Some(e.to_string());
+                self.events.publish(TunnelEventKind::Closed { reason: Some(e.to_string()) });
+                Err(e)
+            }
+        };
+        self.start_supervisor_job(cipher_suite, compression, legacy_handshake, heartbeat_config).await;
+        result
+    }
+
+    /// 开启隧道守护线程
+    /// 定时发送心跳，隧道断开后按指数退避重连并重新登录
+    async fn start_supervisor_job(&self, cipher_suite: CipherSuite, compression: Compression, legacy_handshake: bool, heartbeat_config: Arc<RwLock<HeartbeatConfig>>) {
+        let host = self.host.clone();
+        let port = self.port;
+        let password = self.password.clone();
+        let transport = self.transport.clone();
+        let tunnel = self.tunnel.clone();
+        let tunnel_sender = self.tunnel_sender.clone();
+        let proxy_map = self.proxy_map.clone();
+        let reconnect_state = self.reconnect_state.clone();
+        let streams = self.streams.clone();
+        let events = self.events.clone();
+
+        let supervisor_job = spawn(async move {
+            let mut last_heartbeat = Instant::now();
+            loop {
+                sleep(CHECK_INTERVAL).await;
+
+                // 心跳
+                let config = *heartbeat_config.read().await;
+                if last_heartbeat.elapsed() >= config.interval {
+                    last_heartbeat = Instant::now();
+                    if let Some(tunnel) = tunnel.write().await.as_mut() {
+                        tunnel.heartbeat(config.max_missed).await;
+                    }
+                }
+
+                let (status, protocol_error) = match tunnel.read().await.as_ref() {
+                    Some(tunnel) => { (Some(tunnel.get_status().await), tunnel.get_protocol_error().await) }
+                    None => { (None, None) }
+                };
+                match status {
+                    Some(TunnelStatus::Success) => {
+                        reconnect_state.write().await.attempts = 0;
+                        continue;
+                    }
+                    Some(TunnelStatus::WaitLogin) => { continue; }
+                    Some(TunnelStatus::Logout) | None => {}
+                }
+                // 隧道已断开 不必等到重连成功再关闭上面的连接
+                drop_streams(&streams, &proxy_map).await;
+                // 只记录断线时的协议错误，之后记录重连的错误
+                if let Some(protocol_error) = protocol_error {
+                    let mut state = reconnect_state.write().await;
+                    if state.attempts == 0 {
+                        state.last_error = Some(protocol_error);
+                    }
+                }
+
+                // 等待退避时间后重连
+                let attempts = {
+                    let mut state = reconnect_state.write().await;
+                    state.attempts += 1;
+                    state.attempts
+                };
+                let delay = backoff_delay(attempts);
+                log::error!("tunnel {}:{} reconnect attempt {} in {:?}", host, port, attempts, delay);
+                events.publish(TunnelEventKind::ReconnectScheduled { attempt: attempts, delay_ms: delay.as_millis() as u64 });
+                sleep(delay).await;
+
+                events.publish(TunnelEventKind::Connecting { host: host.clone(), port });
+                match Tunnel::new(host.clone(), port, password.clone(), cipher_suite, compression, legacy_handshake, transport.as_ref(), tunnel_sender.clone(), events.clone()).await {
+                    Ok(new_tunnel) => {
+                        let mut write_guard = tunnel.write().await;
+                        if let Some(mut old_tunnel) = write_guard.take() {
+                            old_tunnel.disconnect().await;
+                        }
+                        // 旧隧道上的连接已失效
+                        drop_streams(&streams, &proxy_map).await;
+                        *write_guard = Some(new_tunnel);
+                        last_heartbeat = Instant::now();
+                    }
+                    Err(e) => {
+                        log::error!("tunnel {}:{} reconnect error: {}", host, port, e);
+                        reconnect_state.write().await.last_error = Some(e.to_string());
+                        events.publish(TunnelEventKind::Closed { reason: Some(e.to_string()) });
+                    }
+                }
+            }
+        });
+        *self.supervisor_job.write().await = Some(supervisor_job);
+    }
+
+    /// 停止守护线程并有序关闭隧道
+    /// 先通知服务端关闭经过这台服务器的连接，再关闭本地的连接
+    pub async fn close(&self) {
+        if let Some(supervisor_job) = self.supervisor_job.write().await.take() {
+            supervisor_job.abort();
+        }
+        if let Some(mut tunnel) = self.tunnel.write().await.take() {
+            let stream_ids: Vec<u32> = self.streams.read().await.keys().copied().collect();
+            tunnel.shutdown(&stream_ids).await;
+        }
+        drop_streams(&self.streams, &self.proxy_map).await;
+    }
+
+    /// 登录成功的服务器才可以使用
+    pub async fn is_healthy(&self) -> bool {
+        matches!(self.get_status().await, TunnelStatus::Success)
+    }
+
+    /// 获取隧道状态
+    pub async fn get_status(&self) -> TunnelStatus {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_status().await }
+            None => { TunnelStatus::Logout }
+        }
+    }
+
+    /// 获取延迟统计
+    pub async fn get_rtt_summary(&self) -> RttSummary {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_rtt_summary().await }
+            None => { RttSummary::default() }
+        }
+    }
+
+    /// 获取Ping延迟
+    pub async fn get_ping_delay(&self) -> u128 {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_ping_delay().await }
+            None => { 0 }
+        }
+    }
+
+    /// 获取协商的能力
+    pub async fn get_capabilities(&self) -> Capabilities {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_capabilities() }
+            None => { Capabilities::default() }
+        }
+    }
+
+    /// 隧道的传输方式是否多路复用
+    pub async fn multiplexed(&self) -> bool {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.multiplexed() }
+            None => { false }
+        }
+    }
+
+    /// 获取上传流量
+    pub async fn get_upload(&self) -> i64 {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_upload().await }
+            None => { 0 }
+        }
+    }
+
+    /// 获取下载流量
+    pub async fn get_download(&self) -> i64 {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_download().await }
+            None => { 0 }
+        }
+    }
+
+    /// 获取发送方向的压缩统计
+    pub async fn get_upload_compression(&self) -> CompressionSummary {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_upload_compression() }
+            None => { CompressionSummary::default() }
+        }
+    }
+
+    /// 获取接收方向的压缩统计
+    pub async fn get_download_compression(&self) -> CompressionSummary {
+        match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { tunnel.get_download_compression() }
+            None => { CompressionSummary::default() }
+        }
+    }
+
+    /// 获取重连状态
+    pub async fn get_reconnect_state(&self) -> ReconnectState {
+        self.reconnect_state.read().await.clone()
+    }
+
+    /// 获取服务器信息
+    pub async fn get_info(&self, selected: bool) -> TunnelServerInfo {
+        TunnelServerInfo {
+            name: self.name.clone(),
+            host: self.host.clone(),
+            port: self.port,
+            status: self.get_status().await,
+            rtt: self.get_rtt_summary().await,
+            selected,
+        }
+    }
+
+    /// 记录经过这台服务器的连接 接收窗口按当前隧道协商的能力记录
+    pub async fn add_stream(&self, stream_id: u32) {
+        let (flow_control, multiplexed) = match self.tunnel.read().await.as_ref() {
+            Some(tunnel) => { (tunnel.get_capabilities().contains(Capabilities::FLOW_CONTROL), tunnel.multiplexed()) }
+            None => { (false, false) }
+        };
+        self.streams.write().await.entry(stream_id).or_insert_with(|| StreamWindow {
+            send: SendWindow::default(),
+            receive: ReceiveWindow::new(flow_control, multiplexed),
+        });
+    }
+
+    /// 删除经过这台服务器的连接
+    pub async fn remove_stream(&self, stream_id: u32) {
+        if let Some(window) = self.streams.write().await.remove(&stream_id) {
+            window.send.close();
+        }
+    }
+
+    /// 连接的接收窗口
+    pub async fn receive_window(&self, stream_id: u32) -> Option<ReceiveWindow> {
+        self.streams.read().await.get(&stream_id).map(|window| window.receive.clone())
+    }
+
+    /// 记录收到的连接数据 超出接收窗口时返回错误
+    pub async fn receive_stream_data(&self, stream_id: u32, protocol: &PackageProtocol, len: usize) -> Result<(), TunnelError> {
+        match self.receive_window(stream_id).await {
+            Some(window) => { window.receive(protocol, len) }
+            None => { Ok(()) }
+        }
+    }
+
+    /// 服务端没有遵守连接的接收窗口 通知服务端原因后删除这个连接
+    pub async fn reset_stream(&self, stream_id: u32, reason: &str) {
+        self.remove_stream(stream_id).await;
+        let _ = self.write_to_tunnel(TunnelPackage::protocol_error(stream_id, reason)).await;
+    }
+
+    /// 服务端是否支持流量控制
+    async fn flow_control(&self) -> bool {
+        self.get_capabilities().await.contains(Capabilities::FLOW_CONTROL)
+    }
+
+    /// 等待连接的发送窗口 只阻塞这一个连接
+    pub async fn acquire_send_window(&self, stream_id: u32, len: usize) -> Result<(), TunnelError> {
+        if !self.flow_control().await {
+            return Ok(());
+        }
+        let window = self.streams.read().await.get(&stream_id).cloned();
+        match window {
+            Some(window) => { window.send.acquire(len).await }
+            None => { Ok(()) }
+        }
+    }
+
+    /// 服务端确认了连接的数据 增加发送窗口
+    pub async fn grant_send_window(&self, stream_id: u32, increment: u32) {
+        if let Some(window) = self.streams.read().await.get(&stream_id) {
+            window.send.grant(increment);
+        }
+    }
+
+    /// 本地已消费连接的数据 通知服务端继续发送
+    pub async fn grant_receive_window(&self, stream_id: u32, increment: u32) -> Result<(), TunnelError> {
+        let window = match self.receive_window(stream_id).await {
+            Some(window) => { window }
+            None => { return Ok(()); }
+        };
+        self.write_to_tunnel(window_update_package(stream_id, increment)).await?;
+        window.release(increment);
+        Ok(())
+    }
+
+    /// 写数据包到隧道
+    pub async fn write_to_tunnel(&self, tunnel_package: TunnelPackage) -> Result<(), TunnelError> {
+        match self.tunnel.write().await.as_mut() {
+            Some(tunnel) => { tunnel.write_to_tunnel(tunnel_package).await }
+            None => { Err(TunnelError::TunnelUnavailable("Tunnel is none".to_string())) }
+        }
+    }
+}
+
+/// 删除经过这台服务器的连接映射，对应的本地连接会随之结束
+async fn drop_streams(streams: &Arc<RwLock<HashMap<u32, StreamWindow>>>, proxy_map: &Arc<RwLock<HashMap<u32, Sender<TunnelPackage>>>>) {
+    let mut proxy_map = proxy_map.write().await;
+    for (stream_id, window) in streams.write().await.drain() {
+        window.send.close();
+        if let Some(sender) = proxy_map.remove(&stream_id) {
+            close_local_stream(&sender, stream_id);
+        }
+    }
+}
+
+/// 像服务端关闭连接一样通知本地连接结束
+/// 本地连接可能还持有自己的发送端，只删除映射时收不到结束
+pub fn close_local_stream(sender: &Sender<TunnelPackage>, stream_id: u32) {
+    let _ = sender.try_send(TunnelPackage::new(PackageCmd::CloseConnect, PackageProtocol::TCP, stream_id, None, None, None));
+}
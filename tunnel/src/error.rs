@@ -0,0 +1,63 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::tunnel::tunnel_package::DecodeError;
+
+/// 隧道错误
+/// 错误码对外暴露，只能新增不能修改
+#[derive(Debug, Error)]
+pub enum TunnelError {
+    #[error("IO错误: {0}")]
+    Io(#[from] io::Error),
+    /// 密钥派生、加密或解密失败
+    #[error("加密错误: {0}")]
+    Crypto(String),
+    /// 数据包或握手不符合协议
+    #[error("协议错误: {0}")]
+    Protocol(String),
+    /// 登录认证失败
+    #[error("认证失败: {0}")]
+    Auth(String),
+    /// 被规则拒绝的连接
+    #[error("连接被拒绝: {0}")]
+    Rejected(String),
+    /// 没有可用的隧道或隧道不支持该功能
+    #[error("隧道不可用: {0}")]
+    TunnelUnavailable(String),
+    #[error("超时: {0}")]
+    Timeout(String),
+}
+
+impl TunnelError {
+    /// 对外暴露的错误码 0表示成功
+    pub fn code(&self) -> i32 {
+        match self {
+            TunnelError::Io(_) => { 1 }
+            TunnelError::Crypto(_) => { 2 }
+            TunnelError::Protocol(_) => { 3 }
+            TunnelError::Auth(_) => { 4 }
+            TunnelError::Rejected(_) => { 5 }
+            TunnelError::TunnelUnavailable(_) => { 6 }
+            TunnelError::Timeout(_) => { 7 }
+        }
+    }
+}
+
+impl From<DecodeError> for TunnelError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Decrypt(_) => { TunnelError::Crypto(e.to_string()) }
+            _ => { TunnelError::Protocol(e.to_string()) }
+        }
+    }
+}
+
+#[test]
+fn test_tunnel_error() {
+    let e = TunnelError::from(DecodeError::Decrypt("数据帧认证失败".to_string()));
+    assert_eq!(e.code(), 2);
+    assert_eq!(TunnelError::from(DecodeError::Replay(1)).code(), 3);
+    assert_eq!(TunnelError::from(io::Error::from(io::ErrorKind::ConnectionReset)).code(), 1);
+    assert_eq!(TunnelError::Rejected("example.com".to_string()).to_string(), "连接被拒绝: example.com");
+}
@@ -1,4 +1,5 @@
 pub mod tunnel;
 pub mod proxy;
 pub mod context;
-pub mod tun;
\ No newline at end of file
+pub mod tun;
+pub mod error;
\ No newline at end of file
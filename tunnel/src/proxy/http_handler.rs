@@ -7,8 +7,10 @@ use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::context::context::TunnelContext;
 use crate::context::proxy_type::ProxyType;
+use crate::context::traffic::TrafficKind;
+use crate::error::TunnelError;
 use crate::proxy::uri_util::{HttpMethod, resolve_uri};
-use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+use crate::tunnel::tunnel_package::{PackageCmd, TunnelPackage};
 
 /// 选择隧道和连接
 /// 可以选择直连http或者Socks或者隧道
@@ -16,7 +18,7 @@ pub async fn handle(header_data: Vec<u8>,
                     client_sender: Sender<Vec<u8>>,
                     client_receiver: Receiver<Vec<u8>>,
                     source_addr: String,
-                    context: Arc<TunnelContext>) -> String {
+                    context: Arc<TunnelContext>) -> Result<(), TunnelError> {
     let (host, port, method) = resolve_uri(&header_data);
 
     // https
@@ -28,7 +30,7 @@ pub async fn handle(header_data: Vec<u8>,
     else if method == HttpMethod::Http {
         proxy_http_connect(&host, &port, client_sender, client_receiver, Some(header_data.clone()), source_addr, context).await
     } else {
-        format!("Unknown uri :{}", String::from_utf8_lossy(&header_data))
+        Err(TunnelError::Protocol(format!("Unknown uri :{}", String::from_utf8_lossy(&header_data))))
     };
 }
 
@@ -39,7 +41,7 @@ pub async fn proxy_http_connect(host: &str,
                                 mut client_receiver: Receiver<Vec<u8>>,
                                 mut header_data: Option<Vec<u8>>,
                                 source_addr: String,
-                                context: Arc<TunnelContext>) -> String {
+                                context: Arc<TunnelContext>) -> Result<(), TunnelError> {
     return match context.match_domain(&host.to_string()).await {
         ProxyType::Redirect => {
             log::error!("{} Redirect", host);
@@ -47,10 +49,14 @@ pub async fn proxy_http_connect(host: &str,
                 Ok(server_stream) => {
                     log::error!("Connect Target Success: {:}:{:} source_addr: {}", host, port, source_addr);
 
+                    let traffic = context.open_connection_traffic(&source_addr, &format!("{}:{}", host, port), TrafficKind::Direct).await;
                     let (mut server_reader, mut server_writer) = server_stream.into_split();
                     if let Some(d) = header_data {
-                        let _ = server_writer.write_all(d.as_slice()).await;
+                        if server_writer.write_all(d.as_slice()).await.is_ok() {
+                            traffic.record_upload(d.len());
+                        }
                     }
+                    let download_traffic = traffic.clone();
                     spawn(async move {
                         let mut server_buffer = [0u8; 4096];
                         loop {
@@ -61,9 +67,11 @@ pub async fn proxy_http_connect(host: &str,
                                 }
                                 Ok(n) => {
                                     let server_data = &server_buffer[..n];
+                                    download_traffic.throttle_download(n).await;
                                     if let Err(_e) = client_sender.send(server_data.to_vec()).await {
                                         break;
                                     }
+                                    download_traffic.record_download(n);
                                 }
                                 Err(e) => {
                                     log::error!("connect server {}", e);
@@ -74,20 +82,25 @@ pub async fn proxy_http_connect(host: &str,
                     });
                     while let Some(client_data) = client_receiver.recv().await {
                         let client_data = client_data.as_slice();
-                        if let Err(e) = server_writer.write_all(client_data).await {
-                            log::error!("Write Target {}:{} Error: {:}", host, port, e);
+                        traffic.throttle_upload(client_data.len()).await;
+                        match server_writer.write_all(client_data).await {
+                            Ok(_) => { traffic.record_upload(client_data.len()); }
+                            Err(e) => {
+                                log::error!("Write Target {}:{} Error: {:}", host, port, e);
+                            }
                         }
                     }
-                    "".to_string()
+                    Ok(())
                 }
                 Err(e) => {
                     log::error!("Connect Target {}:{} Error: {:}", host, port, e);
-                    e.to_string()
+                    Err(e.into())
                 }
             }
         }
         ProxyType::Reject => {
-            format!("Reject: {}", host)
+            context.record_reject(header_data.as_ref().map_or(0, |d| d.len()));
+            Err(TunnelError::Rejected(host.to_string()))
         }
         ProxyType::Proxy => {
             log::error!("{} Proxy", host);
@@ -95,10 +108,10 @@ pub async fn proxy_http_connect(host: &str,
             let port = port.to_string();
 
             // 连接服务端
-            match context.tunnel_connect_server(format!("{}:{}", host, port), source_addr.to_string()).await {
-                Ok(_) => {}
-                Err(e) => { return e; }
-            }
+            let stream_id = match context.tunnel_connect_server(format!("{}:{}", host, port), source_addr.to_string()).await {
+                Ok(stream_id) => { stream_id }
+                Err(e) => { return Err(e); }
+            };
 
             // 添加映射
             let (sender_to_proxy, mut tunnel_receiver) = channel::<TunnelPackage>(10);
@@ -106,19 +119,17 @@ pub async fn proxy_http_connect(host: &str,
 
             // 写请求头部数据
             if let Some(data) = header_data.take() {
-                match context.tunnel_send_data(format!("{}:{}", host, port), source_addr.to_string(), data, PackageProtocol::TCP).await {
+                match context.tunnel_send_stream_data(stream_id, data).await {
                     Ok(_) => {}
-                    Err(e) => { return e; }
+                    Err(e) => { return Err(e); }
                 }
             }
 
             // 循环读取Client数据
             let context_clone = context.clone();
-            let source_addr_clone = source_addr.clone();
-            let target_addr = format!("{}:{}", host, port);
             spawn(async move {
                 while let Some(data) = client_receiver.recv().await {
-                    match context_clone.tunnel_send_data(target_addr.to_string(), source_addr_clone.to_string(), data, PackageProtocol::TCP).await {
+                    match context_clone.tunnel_send_stream_data(stream_id, data).await {
                         Ok(_) => {}
                         Err(_) => { break; }
                     }
@@ -142,13 +153,13 @@ pub async fn proxy_http_connect(host: &str,
                     PackageCmd::PONG => {}
                     PackageCmd::NONE => {
                         log::error!("not active tunnel");
-                        return "not tunnel active".to_string();
+                        return Err(TunnelError::TunnelUnavailable("not tunnel active".to_string()));
                     }
                     _ => {}
                 }
             }
 
-            "".to_string()
+            Ok(())
         }
     };
 }
\ No newline at end of file
@@ -29,6 +29,8 @@ impl Proxy {
         let context = self.context.clone();
         match TcpListener::bind(("0.0.0.0", self.port as u16)).await {
             Ok(lis) => {
+                // 端口为0时由系统分配
+                self.port = lis.local_addr()?.port() as usize;
                 log::error!("Proxy start on {:}", self.port);
                 self.start_accept_client(lis, context);
                 Ok(())
@@ -62,6 +64,11 @@ impl Proxy {
         self.tcp_listener_join_handler = Some(job_handler);
     }
 
+    /// 代理监听的端口
+    pub fn get_port(&self) -> usize {
+        self.port
+    }
+
     /// 停止监听
     pub fn stop(&mut self) {
         if let Some(job_handler) = self.tcp_listener_join_handler.take() {
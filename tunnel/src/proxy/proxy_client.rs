@@ -93,8 +93,9 @@ pub async fn handler_client(tcp_stream: TcpStream, socket_addr: String, context:
                     if status == ConnectStatus::Http {
                         let handler = spawn(async move {
                             // 连接服务端 返回服务端的发送者
-                            let e = http_handler::handle(vec, sender.clone(), server_receiver, socket_addr.clone(), context).await;
-                            log::error!("http handler back {}", e);
+                            if let Err(e) = http_handler::handle(vec, sender.clone(), server_receiver, socket_addr.clone(), context).await {
+                                log::error!("http handler back {} code: {}", e, e.code());
+                            }
                             // 回收资源
                             context2.remove_proxy_mapping(&socket_addr).await;
                             let _ = context2.tunnel_close_server(socket_addr.to_string()).await;
@@ -106,13 +107,15 @@ pub async fn handler_client(tcp_stream: TcpStream, socket_addr: String, context:
                         let udp_temp_source_addr3 = udp_temp_source_addr.clone();
                         let handler = spawn(async move {
                             // 连接服务端 返回服务端的发送者
-                            let e = socks5_handler::handle(vec, sender.clone(), server_receiver, socket_addr.clone(), context, udp_temp_source_addr2).await;
-                            log::error!("socks5 handler back {}", e);
+                            if let Err(e) = socks5_handler::handle(vec, sender.clone(), server_receiver, socket_addr.clone(), context, udp_temp_source_addr2).await {
+                                log::error!("socks5 handler back {} code: {}", e, e.code());
+                            }
                             // 回收资源
                             context2.remove_proxy_mapping(&socket_addr).await;
                             let _ = context2.tunnel_close_server(socket_addr.to_string()).await;
                             for source_addr in udp_temp_source_addr3.read().await.iter() {
                                 context2.remove_proxy_mapping(source_addr).await;
+                                let _ = context2.tunnel_close_server(source_addr.to_string()).await;
                             }
                             context2.remove_connect_info(&socket_addr).await;
                         });
@@ -142,6 +145,7 @@ pub async fn handler_client(tcp_stream: TcpStream, socket_addr: String, context:
     let _ = context.tunnel_close_server(socket_addr.to_string()).await;
     for source_addr in udp_temp_source_addr.read().await.iter() {
         context.remove_proxy_mapping(source_addr).await;
+        let _ = context.tunnel_close_server(source_addr.to_string()).await;
     }
     context.remove_connect_info(&socket_addr).await;
     if let Some(handler) = client_write_join_handler {
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -10,14 +10,16 @@ use tokio::sync::RwLock;
 
 use crate::context::context::TunnelContext;
 use crate::context::proxy_type::ProxyType;
-use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+use crate::context::traffic::TrafficKind;
+use crate::error::TunnelError;
+use crate::tunnel::tunnel_package::{PackageCmd, TunnelPackage};
 
 pub async fn handle(header_data: Vec<u8>,
                     client_sender: Sender<Vec<u8>>,
                     mut client_receiver: Receiver<Vec<u8>>,
                     source_addr: String,
                     context: Arc<TunnelContext>,
-                    udp_temp_source_addr: Arc<RwLock<Vec<String>>>) -> String {
+                    udp_temp_source_addr: Arc<RwLock<Vec<String>>>) -> Result<(), TunnelError> {
     let header_data_len = header_data.len();
     let command = header_data[1];
     let address_type = header_data[3];
@@ -46,7 +48,7 @@ pub async fn handle(header_data: Vec<u8>,
             }
             Ipv6Addr::from(data).to_string()
         }
-        _ => { return "resolve domain error".to_string(); }
+        _ => { return Err(TunnelError::Protocol("resolve domain error".to_string())); }
     };
 
     let proxy_type = context.match_domain(&domain).await;
@@ -65,7 +67,9 @@ pub async fn handle(header_data: Vec<u8>,
                     Ok(server_stream) => {
                         log::error!("Connect Target Success: {:}:{:} source_addr: {}", domain, port, source_addr);
 
+                        let traffic = context.open_connection_traffic(&source_addr, &format!("{}:{}", domain, port), TrafficKind::Direct).await;
                         let (mut server_reader, mut server_writer) = server_stream.into_split();
+                        let download_traffic = traffic.clone();
                         spawn(async move {
                             let mut server_buffer = [0u8; 4096];
                             loop {
@@ -76,9 +80,11 @@ pub async fn handle(header_data: Vec<u8>,
                                     }
                                     Ok(n) => {
                                         let server_data = &server_buffer[..n];
+                                        download_traffic.throttle_download(n).await;
                                         if let Err(_e) = client_sender.send(server_data.to_vec()).await {
                                             break;
                                         }
+                                        download_traffic.record_download(n);
                                     }
                                     Err(e) => {
                                         log::error!("connect server {}", e);
@@ -89,20 +95,25 @@ pub async fn handle(header_data: Vec<u8>,
                         });
                         while let Some(client_data) = client_receiver.recv().await {
                             let client_data = client_data.as_slice();
-                            if let Err(e) = server_writer.write_all(client_data).await {
-                                log::error!("Write Target {}:{} Error: {:}", domain, port, e);
+                            traffic.throttle_upload(client_data.len()).await;
+                            match server_writer.write_all(client_data).await {
+                                Ok(_) => { traffic.record_upload(client_data.len()); }
+                                Err(e) => {
+                                    log::error!("Write Target {}:{} Error: {:}", domain, port, e);
+                                }
                             }
                         }
-                        return "".to_string();
+                        return Ok(());
                     }
                     Err(e) => {
                         log::error!("Connect Target {}:{} Error: {:}", domain, port, e);
-                        return e.to_string();
+                        return Err(e.into());
                     }
                 }
             }
             ProxyType::Reject => {
-                return format!("Reject: {}", domain);
+                context.record_reject(0);
+                return Err(TunnelError::Rejected(domain));
             }
             ProxyType::Proxy => {
                 log::error!("{} Proxy", domain);
@@ -110,10 +121,10 @@ pub async fn handle(header_data: Vec<u8>,
                 let port = port.to_string();
 
                 // 连接服务端
-                match context.tunnel_connect_server(format!("{}:{}", host, port), source_addr.to_string()).await {
-                    Ok(_) => {}
-                    Err(e) => { return e; }
-                }
+                let stream_id = match context.tunnel_connect_server(format!("{}:{}", host, port), source_addr.to_string()).await {
+                    Ok(stream_id) => { stream_id }
+                    Err(e) => { return Err(e); }
+                };
 
                 // 添加映射
                 let (sender_to_proxy, mut tunnel_receiver) = channel::<TunnelPackage>(10);
@@ -121,11 +132,9 @@ pub async fn handle(header_data: Vec<u8>,
 
                 // 循环读取Client数据
                 let context_clone = context.clone();
-                let source_addr_clone = source_addr.clone();
-                let target_addr = format!("{}:{}", host, port);
                 spawn(async move {
                     while let Some(data) = client_receiver.recv().await {
-                        match context_clone.tunnel_send_data(target_addr.to_string(), source_addr_clone.to_string(), data, PackageProtocol::TCP).await {
+                        match context_clone.tunnel_send_stream_data(stream_id, data).await {
                             Ok(_) => {}
                             Err(_) => { break; }
                         }
@@ -149,13 +158,13 @@ pub async fn handle(header_data: Vec<u8>,
                         PackageCmd::PONG => {}
                         PackageCmd::NONE => {
                             log::error!("not active tunnel");
-                            return "not tunnel active".to_string();
+                            return Err(TunnelError::TunnelUnavailable("not tunnel active".to_string()));
                         }
                         _ => {}
                     }
                 }
 
-                return "".to_string();
+                return Ok(());
             }
         }
     }
@@ -163,11 +172,11 @@ pub async fn handle(header_data: Vec<u8>,
     else if command == 0x03 {
         let socket = match UdpSocket::bind("0.0.0.0:0").await {
             Ok(s) => { s }
-            Err(e) => { return e.to_string(); }
+            Err(e) => { return Err(e.into()); }
         };
         let (udp_addr, udp_port) = match socket.local_addr() {
             Ok(a) => { (a.ip().to_string(), a.port()) }
-            Err(e) => { return e.to_string(); }
+            Err(e) => { return Err(e.into()); }
         };
         // // 响应
         let _ = client_sender.send(vec![0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, (udp_port >> 8) as u8, udp_port as u8]).await;
@@ -182,7 +191,7 @@ pub async fn handle(header_data: Vec<u8>,
 
         // 添加路由映射
         let (sender_to_proxy, mut tunnel_receiver) = channel::<TunnelPackage>(10);
-        context.add_proxy_mapping(udp_host.to_string(), sender_to_proxy).await;
+        let stream_id = context.add_proxy_mapping(udp_host.to_string(), sender_to_proxy).await;
         // 临时UDP映射表
         udp_temp_source_addr.write().await.push(udp_host.to_string());
 
@@ -198,21 +207,10 @@ pub async fn handle(header_data: Vec<u8>,
                             if let Some(addr) = package.target_address {
                                 // 取映射中的源地址
                                 if let Some(source_addr) = source_target_map.read().await.get(&addr) {
-                                    // 字符串转对象
-                                    if let Ok(socket_addr) = source_addr.as_str().parse::<SocketAddr>() {
-                                        let port = socket_addr.port();
-                                        match socket_addr.ip() {
-                                            IpAddr::V4(ipv4) => {
-                                                // 封装Socks5格式
-                                                let mut vec = vec![0x00, 0x00, 0x00, 0x01];
-                                                vec.extend(ipv4.octets());
-                                                vec.push((port >> 8) as u8);
-                                                vec.push(port as u8);
-                                                vec.extend(data);
-                                                let _ = socket2.send_to(vec.as_slice(), source_addr).await;
-                                            }
-                                            IpAddr::V6(_) => {}
-                                        }
+                                    // 封装Socks5格式 地址为数据来源的目标地址
+                                    if let Some(mut vec) = udp_reply_header(&addr) {
+                                        vec.extend(data);
+                                        let _ = socket2.send_to(vec.as_slice(), source_addr).await;
                                     }
                                 }
                             }
@@ -264,7 +262,7 @@ pub async fn handle(header_data: Vec<u8>,
                             }
                             Ipv6Addr::from(temp_data).to_string()
                         }
-                        _ => { return "".to_string(); }
+                        _ => { return Ok(()); }
                     };
                     let port = (((data[last_domain_index as usize] & 0xff) as i32) << 8) | ((data[(last_domain_index + 1) as usize] & 0xff) as i32);
 
@@ -274,12 +272,38 @@ pub async fn handle(header_data: Vec<u8>,
                     // 添加源-目标地址映射
                     source_target_map2.write().await.insert(target_addr.clone(), addr.to_string());
                     // 写隧道
-                    let _ = context.tunnel_send_data(target_addr, udp_host.to_string(), x.to_vec(), PackageProtocol::UDP).await;
+                    let _ = context.tunnel_send_udp_data(stream_id, &udp_host, target_addr, x.to_vec()).await;
                 }
-                Err(e) => { return e.to_string(); }
+                Err(e) => { return Err(e.into()); }
             }
         }
     }
 
-    return "".to_string();
+    return Ok(());
+}
+
+/// Socks5 UDP响应头 RSV+FRAG+目标地址
+fn udp_reply_header(target_addr: &str) -> Option<Vec<u8>> {
+    let mut vec = vec![0x00, 0x00, 0x00];
+    match target_addr.parse::<SocketAddr>() {
+        Ok(SocketAddr::V4(addr)) => {
+            vec.push(0x01);
+            vec.extend(addr.ip().octets());
+            vec.extend(addr.port().to_be_bytes());
+        }
+        Ok(SocketAddr::V6(addr)) => {
+            vec.push(0x04);
+            vec.extend(addr.ip().octets());
+            vec.extend(addr.port().to_be_bytes());
+        }
+        Err(_) => {
+            let (host, port) = target_addr.rsplit_once(':')?;
+            let port = port.parse::<u16>().ok()?;
+            vec.push(0x03);
+            vec.push(u8::try_from(host.len()).ok()?);
+            vec.extend(host.as_bytes());
+            vec.extend(port.to_be_bytes());
+        }
+    }
+    Some(vec)
 }
\ No newline at end of file
@@ -8,7 +8,7 @@ use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 
 use crate::tun::packet::Packet;
-use crate::tun::tcp_pipe_context::{get_pipe_by_key, remove_pipe_by_key};
+use crate::tun::tcp_pipe_context::{get_pipe_by_key, PipeKey, remove_pipe_by_key};
 use crate::tunnel::tunnel_package::{PackageCmd, TunnelPackage};
 
 pub struct TcpPipe {
@@ -22,6 +22,8 @@ pub struct TcpPipe {
     acknowledgment_number: u32,
     tunnel_read_join_handler: JoinHandle<()>,
     tunnel_sender: Sender<TunnelPackage>,
+    /// 隧道连接编号
+    stream_id: u32,
 }
 
 impl TcpPipe {
@@ -32,7 +34,7 @@ impl TcpPipe {
         target_port: u16,
         client_sequence_number: u32,
         client_sender: Sender<Vec<u8>>,
-        pipe_map: Arc<RwLock<HashMap<String, Arc<RwLock<TcpPipe>>>>>,
+        pipe_map: Arc<RwLock<HashMap<PipeKey, Arc<RwLock<TcpPipe>>>>>,
     ) -> TcpPipe {
         let (tunnel_sender, tunnel_receiver) = channel::<TunnelPackage>(10);
         TcpPipe {
@@ -44,14 +46,17 @@ impl TcpPipe {
             client_sequence_number,
             sequence_number: 0,
             acknowledgment_number: 0,
-            tunnel_read_join_handler: Self::create_tunnel_read_join_handler(tunnel_receiver, client_sender.clone(), pipe_map),
+            tunnel_read_join_handler: Self::create_tunnel_read_join_handler(tunnel_receiver, client_sender.clone(), pipe_map,
+                                                                            (source_addr, source_port, target_addr, target_port)),
             tunnel_sender,
+            stream_id: 0,
         }
     }
 
     pub fn create_tunnel_read_join_handler(mut tunnel_receiver: Receiver<TunnelPackage>,
                                            client_sender: Sender<Vec<u8>>,
-                                           pipe_map: Arc<RwLock<HashMap<String, Arc<RwLock<TcpPipe>>>>>) -> JoinHandle<()> {
+                                           pipe_map: Arc<RwLock<HashMap<PipeKey, Arc<RwLock<TcpPipe>>>>>,
+                                           key: PipeKey) -> JoinHandle<()> {
         // 处理隧道返回的数据
         spawn(async move {
             while let Some(d) = tunnel_receiver.recv().await {
@@ -60,41 +65,38 @@ impl TcpPipe {
                     PackageCmd::NewConnect => {}
                     PackageCmd::CloseConnect => {
                         log::error!("tunnel send close connect ");
-                        if let Some(source_addr) = d.source_address {
-                            if let Some(target_addr) = d.target_address {
-                                if let Some(pipe) = get_pipe_by_key(&pipe_map, &format!("{}-{}", source_addr, target_addr)).await {
-                                    let vec = pipe.write().await.do_fin();
-                                    let _ = client_sender.send(vec).await;
-                                } else {
-                                    log::error!("get none pipe:{}", &format!("{}-{}", source_addr, target_addr))
-                                }
-                                remove_pipe_by_key(&pipe_map, &format!("{}-{}", source_addr, target_addr)).await;
-                            }
+                        if let Some(pipe) = get_pipe_by_key(&pipe_map, &key).await {
+                            let vec = pipe.write().await.do_fin();
+                            let _ = client_sender.send(vec).await;
+                        } else {
+                            log::error!("get none pipe:{:?}", key)
                         }
+                        remove_pipe_by_key(&pipe_map, &key).await;
                     }
                     PackageCmd::TData => {
                         if let Some(data) = d.data {
                             log::error!("read tunnel data:{}", data.len());
-                            if let Some(source_addr) = d.source_address {
-                                if let Some(target_addr) = d.target_address {
-                                    if let Some(pipe) = get_pipe_by_key(&pipe_map, &format!("{}-{}", source_addr, target_addr)).await {
-                                        let mtu = 1000;
-                                        for x in data.chunks(mtu) {
-                                            let vec = pipe.write().await.do_psh(x.to_vec());
-                                            let _ = client_sender.send(vec).await;
-                                        }
-                                    } else {
-                                        log::error!("get none pipe:{}", &format!("{}-{}", source_addr, target_addr))
-                                    }
+                            if let Some(pipe) = get_pipe_by_key(&pipe_map, &key).await {
+                                let mtu = 1000;
+                                for x in data.chunks(mtu) {
+                                    let vec = pipe.write().await.do_psh(x.to_vec());
+                                    let _ = client_sender.send(vec).await;
                                 }
+                            } else {
+                                log::error!("get none pipe:{:?}", key)
                             }
                         }
                     }
                     PackageCmd::PING => {}
+                    PackageCmd::Handshake => {}
+                    PackageCmd::WindowUpdate => {}
                     PackageCmd::LoginSuccess => {}
                     PackageCmd::LoginFail => {}
                     PackageCmd::ProtocolError => {}
                     PackageCmd::PONG => {}
+                    PackageCmd::HandshakeAck => {}
+                    PackageCmd::Resolve => {}
+                    PackageCmd::ResolveResult => {}
                     PackageCmd::NONE => {}
                 }
             }
@@ -105,6 +107,14 @@ impl TcpPipe {
         self.tunnel_sender.clone()
     }
 
+    pub fn set_stream_id(&mut self, stream_id: u32) {
+        self.stream_id = stream_id;
+    }
+
+    pub fn get_stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
     pub fn get_sequence_number(&self) -> u32 {
         self.sequence_number
     }
@@ -130,6 +140,24 @@ impl TcpPipe {
         create_packet.to_byte()
     }
 
+    /// 拒绝Syn数据包 隧道无法连接目标时使用
+    pub fn do_rst(&mut self, packet: &mut Packet) -> Vec<u8> {
+        let mut create_packet = Packet::build_tcp_packet(self.identification,
+                                                         self.target_addr, self.source_addr,
+                                                         self.target_port, self.source_port,
+                                                         None);
+        create_packet.set_rst();
+        create_packet.set_ack();
+
+        create_packet.set_sequence_number(0);
+        create_packet.set_acknowledgment_number(packet.get_sequence_number() + 1);
+
+        create_packet.calculate_checksum();
+        create_packet.calculate_ip_checksum();
+
+        create_packet.to_byte()
+    }
+
     /// 回应Psh数据包
     pub fn do_ack_psh(&mut self, packet: &mut Packet) -> Vec<u8> {
         self.sequence_number = packet.get_acknowledgment_number();
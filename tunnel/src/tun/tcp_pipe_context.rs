@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 use tokio::spawn;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
@@ -10,8 +11,16 @@ use crate::tun::packet::Packet;
 use crate::tun::tcp_pipe::TcpPipe;
 use crate::tunnel::tunnel_package::{PackageCmd, TunnelPackage};
 
+/// 管道的key 源地址、源端口、目标地址、目标端口
+pub type PipeKey = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+/// 数据包对应的管道key
+fn pipe_key(packet: &Packet) -> PipeKey {
+    (packet.get_source_addr(), packet.get_source_port(), packet.get_target_addr(), packet.get_target_port())
+}
+
 pub struct TcpPipeContext {
-    pipe_map: Arc<RwLock<HashMap<String, Arc<RwLock<TcpPipe>>>>>,
+    pipe_map: Arc<RwLock<HashMap<PipeKey, Arc<RwLock<TcpPipe>>>>>,
     client_sender: Sender<Vec<u8>>,
 }
 
@@ -32,11 +41,7 @@ impl TcpPipeContext {
         if !packet.is_syn() {
             return None;
         }
-        let key = format!("{}:{}-{}:{}",
-                          packet.get_source_addr(),
-                          packet.get_source_port(),
-                          packet.get_target_addr(),
-                          packet.get_target_port());
+        let key = pipe_key(packet);
         if let Some(pipe) = self.pipe_map.read().await.get(&key) {
             if pipe.read().await.get_sequence_number() == packet.get_sequence_number() {
                 return None;
@@ -51,23 +56,19 @@ impl TcpPipeContext {
                                                          self.client_sender.clone(),
                                                          self.pipe_map.clone())));
         {
-            self.pipe_map.write().await.insert(key.clone(), Arc::clone(&tcp_pipe));
+            self.pipe_map.write().await.insert(key, Arc::clone(&tcp_pipe));
         }
         return Some(tcp_pipe);
     }
 
     /// 获取管道
     pub async fn get_pipe(&self, packet: &Packet) -> Option<Arc<RwLock<TcpPipe>>> {
-        let key = format!("{}:{}-{}:{}",
-                          packet.get_source_addr(),
-                          packet.get_source_port(),
-                          packet.get_target_addr(),
-                          packet.get_target_port());
+        let key = pipe_key(packet);
         self.get_pipe_by_key(&key).await
     }
 
     /// 根据key获取管道
-    pub async fn get_pipe_by_key(&self, key: &String) -> Option<Arc<RwLock<TcpPipe>>> {
+    pub async fn get_pipe_by_key(&self, key: &PipeKey) -> Option<Arc<RwLock<TcpPipe>>> {
         return if let Some(arc) = self.pipe_map.read().await.get(key) {
             Some(Arc::clone(arc))
         } else {
@@ -77,27 +78,23 @@ impl TcpPipeContext {
 
     /// 删除管道
     pub async fn remove_pipe(&self, packet: &Packet) {
-        let key = format!("{}:{}-{}:{}",
-                          packet.get_source_addr(),
-                          packet.get_source_port(),
-                          packet.get_target_addr(),
-                          packet.get_target_port());
+        let key = pipe_key(packet);
         self.remove_pipe_by_key(&key).await;
     }
 
     /// 根据Key删除管道
-    pub async fn remove_pipe_by_key(&self, key: &String) {
+    pub async fn remove_pipe_by_key(&self, key: &PipeKey) {
         self.pipe_map.write().await.remove(key);
     }
 }
 
 /// 根据Key删除管道
-pub async fn remove_pipe_by_key(pipe_map: &Arc<RwLock<HashMap<String, Arc<RwLock<TcpPipe>>>>>, key: &String) {
+pub async fn remove_pipe_by_key(pipe_map: &Arc<RwLock<HashMap<PipeKey, Arc<RwLock<TcpPipe>>>>>, key: &PipeKey) {
     pipe_map.write().await.remove(key);
 }
 
 /// 根据key获取管道
-pub async fn get_pipe_by_key(pipe_map: &Arc<RwLock<HashMap<String, Arc<RwLock<TcpPipe>>>>>, key: &String) -> Option<Arc<RwLock<TcpPipe>>> {
+pub async fn get_pipe_by_key(pipe_map: &Arc<RwLock<HashMap<PipeKey, Arc<RwLock<TcpPipe>>>>>, key: &PipeKey) -> Option<Arc<RwLock<TcpPipe>>> {
     if let Some(arc) = pipe_map.read().await.get(key) {
         Some(Arc::clone(arc))
     } else {
@@ -8,7 +8,6 @@ use tokio::task::JoinHandle;
 use crate::context::context::TunnelContext;
 use crate::tun::packet::{Packet, Protocol, Version};
 use crate::tun::tcp_pipe_context::TcpPipeContext;
-use crate::tunnel::tunnel_package::PackageProtocol;
 
 pub struct Tun {
     client_receiver: RwLock<Receiver<Vec<u8>>>,
@@ -60,16 +59,29 @@ impl Tun {
                                 log::error!("packet syn ,source:{}:{}  target:{}:{}", packet.get_source_addr(), packet.get_source_port(), packet.get_target_addr(), packet.get_target_port());
                                 if let Some(tcp_pipe) = tcp_pipe_context.create_pipe(&packet).await {
                                     // 隧道映射
-                                    context.add_proxy_mapping(format!("{}:{}", packet.get_source_addr(), packet.get_source_port()),
-                                                              tcp_pipe.read().await.get_tunnel_sender()).await;
+                                    let source_addr = format!("{}:{}", packet.get_source_addr(), packet.get_source_port());
+                                    context.add_proxy_mapping(source_addr.clone(), tcp_pipe.read().await.get_tunnel_sender()).await;
                                     // 发送连接目标命令
-                                    let _ = context.tunnel_connect_server(format!("{}:{}", packet.get_target_addr(), packet.get_target_port()),
-                                                                          format!("{}:{}", packet.get_source_addr(), packet.get_source_port())).await;
-                                    // 响应Syn数据包
-                                    let vec = tcp_pipe.write().await.do_ack_syn(&mut packet);
-                                    log::error!("do ack syn , send to client:  ");
-                                    // print("ack syn",vec.as_slice());
-                                    let _ = sender.send(vec).await;
+                                    match context.tunnel_connect_server(format!("{}:{}", packet.get_target_addr(), packet.get_target_port()),
+                                                                        source_addr.clone()).await {
+                                        Ok(stream_id) => {
+                                            tcp_pipe.write().await.set_stream_id(stream_id);
+                                            // 响应Syn数据包
+                                            let vec = tcp_pipe.write().await.do_ack_syn(&mut packet);
+                                            log::error!("do ack syn , send to client:  ");
+                                            // print("ack syn",vec.as_slice());
+                                            let _ = sender.send(vec).await;
+                                        }
+                                        // 连接失败时拒绝Syn 删除管道和隧道映射
+                                        Err(e) => {
+                                            log::error!("tunnel connect fail: {}", e);
+                                            tcp_pipe_context.remove_pipe(&packet).await;
+                                            context.remove_proxy_mapping(&source_addr).await;
+                                            let _ = context.tunnel_close_server(source_addr).await;
+                                            let vec = tcp_pipe.write().await.do_rst(&mut packet);
+                                            let _ = sender.send(vec).await;
+                                        }
+                                    }
                                 }
                             }
                             if packet.is_ack() {
@@ -82,9 +94,8 @@ impl Tun {
                                     // 发送数据到隧道
                                     log::error!("send data to tunnel size:{}", packet.get_data().len());
                                     if packet.get_data().len() > 0 {
-                                        let _ = context.tunnel_send_data(format!("{}:{}", packet.get_target_addr(), packet.get_target_port()),
-                                                                         format!("{}:{}", packet.get_source_addr(), packet.get_source_port()),
-                                                                         packet.get_data().to_vec(), PackageProtocol::TCP).await;
+                                        let stream_id = tcp_pipe.read().await.get_stream_id();
+                                        let _ = context.tunnel_send_stream_data(stream_id, packet.get_data().to_vec()).await;
                                     }
                                     // 响应Psh数据包
                                     let vec = tcp_pipe.write().await.do_ack_psh(&mut packet);
@@ -102,7 +113,8 @@ impl Tun {
                                     tcp_pipe_context.remove_pipe(&packet).await;
                                     // 发送数据到隧道
                                     if packet.get_data().len() > 0 {
-                                        let _ = context.tunnel_close_server(format!("{}:{}", packet.get_source_addr(), packet.get_source_port())).await;
+                                        let stream_id = tcp_pipe.read().await.get_stream_id();
+                                        let _ = context.tunnel_close_stream(stream_id).await;
                                     }
                                     // 响应Fin数据包
                                     let vec = tcp_pipe.write().await.do_ack_fin(&mut packet);
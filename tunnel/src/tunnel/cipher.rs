@@ -0,0 +1,364 @@
+use openssl::cipher::{Cipher, CipherRef};
+use openssl::cipher_ctx::CipherCtx;
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::md::Md;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::{Id, PKey};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+
+/// AEAD认证标签长度
+pub const TAG_LEN: usize = 16;
+/// 握手时每一端生成的盐长度
+pub const SALT_LEN: usize = 32;
+/// 派生密钥长度
+const KEY_LEN: usize = 32;
+/// 派生会话密钥前拉伸密码的PBKDF2迭代次数
+const PASSWORD_ITERATIONS: usize = 100_000;
+/// 登录时间戳与服务端时间允许的最大偏差(秒)
+pub const LOGIN_WINDOW_SECS: u64 = 60;
+
+/// 隧道加密套件
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CipherSuite {
+    Aes256Gcm = 0x01,
+    ChaCha20Poly1305 = 0x02,
+}
+
+impl CipherSuite {
+    pub fn from_byte(suite: u8) -> Option<CipherSuite> {
+        match suite {
+            0x01 => { Some(CipherSuite::Aes256Gcm) }
+            0x02 => { Some(CipherSuite::ChaCha20Poly1305) }
+            _ => { None }
+        }
+    }
+
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => { 0x01 }
+            CipherSuite::ChaCha20Poly1305 => { 0x02 }
+        }
+    }
+
+    fn cipher(&self) -> &'static CipherRef {
+        match self {
+            CipherSuite::Aes256Gcm => { Cipher::aes_256_gcm() }
+            CipherSuite::ChaCha20Poly1305 => { Cipher::chacha20_poly1305() }
+        }
+    }
+}
+
+/// 数据帧方向，参与nonce计算，保证两个方向的nonce不会重复
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameDirection {
+    ClientToServer = 0x01,
+    ServerToClient = 0x02,
+}
+
+/// 单方向的帧加解密器
+/// nonce由方向和递增的帧计数器组成，帧计数器同时写在帧头并参与认证，被篡改、重放或乱序的帧都会被拒绝
+pub struct FrameCipher {
+    suite: CipherSuite,
+    key: Vec<u8>,
+    direction: FrameDirection,
+    counter: u64,
+    /// 复用的加解密上下文和它的模式(true为加密)
+    ctx: Option<(CipherCtx, bool)>,
+}
+
+impl FrameCipher {
+    pub fn new(suite: CipherSuite, key: &[u8], direction: FrameDirection) -> FrameCipher {
+        FrameCipher {
+            suite,
+            key: key.to_vec(),
+            direction,
+            counter: 0,
+            ctx: None,
+        }
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// 下一帧的帧计数器
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    /// 生成当前帧的nonce并递增计数器
+    fn next_nonce(&mut self) -> Result<[u8; 12], String> {
+        let nonce = self.nonce(self.counter);
+        self.counter = self.counter.checked_add(1).ok_or("帧计数器溢出")?;
+        Ok(nonce)
+    }
+
+    fn nonce(&self, counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[0] = self.direction as u8;
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// 取得加解密上下文并设置本帧的nonce
+    /// 密钥只在第一次使用时设置，之后每帧只更换nonce，不再重新分配上下文
+    fn context(&mut self, encrypt: bool, nonce: &[u8]) -> Result<&mut CipherCtx, String> {
+        match self.ctx.as_mut() {
+            Some((ctx, mode)) if *mode == encrypt => {
+                if encrypt {
+                    ctx.encrypt_init(None, None, Some(nonce))
+                } else {
+                    ctx.decrypt_init(None, None, Some(nonce))
+                }.map_err(|e| e.to_string())?;
+            }
+            _ => {
+                let mut ctx = CipherCtx::new().map_err(|e| e.to_string())?;
+                if encrypt {
+                    ctx.encrypt_init(Some(self.suite.cipher()), Some(&self.key), Some(nonce))
+                } else {
+                    ctx.decrypt_init(Some(self.suite.cipher()), Some(&self.key), Some(nonce))
+                }.map_err(|e| e.to_string())?;
+                self.ctx = Some((ctx, encrypt));
+            }
+        }
+        match self.ctx.as_mut() {
+            Some((ctx, _)) => { Ok(ctx) }
+            None => { Err("加密上下文初始化失败".to_string()) }
+        }
+    }
+
+    /// 原地加密 返回认证标签
+    pub fn seal_in_place(&mut self, aad: &[u8], data: &mut [u8]) -> Result<[u8; TAG_LEN], String> {
+        let nonce = self.next_nonce()?;
+        let ctx = self.context(true, &nonce)?;
+        ctx.cipher_update(aad, None).map_err(|e| e.to_string())?;
+        let len = data.len();
+        ctx.cipher_update_inplace(data, len).map_err(|e| e.to_string())?;
+        ctx.cipher_final(&mut []).map_err(|e| e.to_string())?;
+        let mut tag = [0u8; TAG_LEN];
+        ctx.tag(&mut tag).map_err(|e| e.to_string())?;
+        Ok(tag)
+    }
+
+    /// 原地校验并解密 认证失败时data中的内容不可使用
+    pub fn open_in_place(&mut self, aad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), String> {
+        let nonce = self.next_nonce()?;
+        self.open_with_nonce(&nonce, aad, data, tag)
+    }
+
+    /// 按帧自带的计数器解密 不改变计数器，用于可能乱序到达的帧
+    pub fn open_in_place_at(&mut self, counter: u64, aad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), String> {
+        let nonce = self.nonce(counter);
+        self.open_with_nonce(&nonce, aad, data, tag)
+    }
+
+    fn open_with_nonce(&mut self, nonce: &[u8], aad: &[u8], data: &mut [u8], tag: &[u8]) -> Result<(), String> {
+        let ctx = self.context(false, nonce)?;
+        ctx.cipher_update(aad, None).map_err(|e| e.to_string())?;
+        let len = data.len();
+        ctx.cipher_update_inplace(data, len).map_err(|e| e.to_string())?;
+        ctx.set_tag(tag).map_err(|e| e.to_string())?;
+        ctx.cipher_final(&mut []).map_err(|_| "数据帧认证失败".to_string())?;
+        Ok(())
+    }
+
+    /// 加密，返回 密文+认证标签
+    pub fn seal(&mut self, aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, String> {
+        let mut result = Vec::with_capacity(plain.len() + TAG_LEN);
+        result.extend_from_slice(plain);
+        let tag = self.seal_in_place(aad, &mut result)?;
+        result.extend_from_slice(&tag);
+        Ok(result)
+    }
+
+    /// 校验并解密 密文+认证标签
+    pub fn open(&mut self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+        if data.len() < TAG_LEN {
+            return Err("数据帧长度错误".to_string());
+        }
+        let (cipher_text, tag) = data.split_at(data.len() - TAG_LEN);
+        let mut result = cipher_text.to_vec();
+        self.open_in_place(aad, &mut result, tag)?;
+        Ok(result)
+    }
+}
+
+/// 生成随机盐
+pub fn random_salt() -> Result<[u8; SALT_LEN], String> {
+    let mut salt = [0u8; SALT_LEN];
+    rand_bytes(&mut salt).map_err(|e| e.to_string())?;
+    Ok(salt)
+}
+
+/// 握手记录的哈希 双方按实际收发的握手数据计算
+/// 版本、能力和盐任何一处被篡改，双方的会话密钥都不同，登录无法通过
+pub fn transcript_hash(hello: &[u8], hello_ack: &[u8]) -> Result<Vec<u8>, String> {
+    let mut hasher = Hasher::new(MessageDigest::sha256()).map_err(|e| e.to_string())?;
+    for data in [hello, hello_ack] {
+        hasher.update(&(data.len() as u32).to_be_bytes()).map_err(|e| e.to_string())?;
+        hasher.update(data).map_err(|e| e.to_string())?;
+    }
+    Ok(hasher.finish().map_err(|e| e.to_string())?.to_vec())
+}
+
+/// 会话密钥
+/// 密码先用PBKDF2-HMAC-SHA256拉伸，再和握手交换的会话盐通过HKDF-SHA256派生，每个方向和登录认证各用一个密钥
+pub struct SessionKeys {
+    pub client_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+    auth_key: Vec<u8>,
+    session_salt: Vec<u8>,
+}
+
+impl SessionKeys {
+    /// 派生会话密钥 会话盐为 客户端盐+服务端盐，握手记录的哈希追加在HKDF的info之后
+    /// 密码用会话盐拉伸后作为HKDF的输入，截获握手后离线猜测密码的代价随迭代次数增加
+    pub fn derive(password: &[u8], client_salt: &[u8], server_salt: &[u8], transcript: &[u8]) -> Result<SessionKeys, String> {
+        let session_salt = [client_salt, server_salt].concat();
+        let mut stretched = vec![0u8; KEY_LEN];
+        pbkdf2_hmac(password, &session_salt, PASSWORD_ITERATIONS, MessageDigest::sha256(), &mut stretched).map_err(|e| e.to_string())?;
+        SessionKeys::derive_from(&stretched, session_salt, transcript)
+    }
+
+    /// 旧版本的会话密钥 不拉伸密码，也不绑定握手记录
+    pub fn derive_legacy(password: &[u8], client_salt: &[u8], server_salt: &[u8]) -> Result<SessionKeys, String> {
+        SessionKeys::derive_from(password, [client_salt, server_salt].concat(), &[])
+    }
+
+    fn derive_from(ikm: &[u8], session_salt: Vec<u8>, transcript: &[u8]) -> Result<SessionKeys, String> {
+        let info = |label: &[u8]| [label, transcript].concat();
+        Ok(SessionKeys {
+            client_key: hkdf_sha256(ikm, &session_salt, &info(b"flyshadow client to server"))?,
+            server_key: hkdf_sha256(ikm, &session_salt, &info(b"flyshadow server to client"))?,
+            auth_key: hkdf_sha256(ikm, &session_salt, &info(b"flyshadow login auth"))?,
+            session_salt,
+        })
+    }
+
+    /// 登录凭证 只对当前会话和登录时间有效，不会泄露密码
+    fn login_proof(&self, timestamp: u64) -> Result<Vec<u8>, String> {
+        let key = PKey::hmac(&self.auth_key).map_err(|e| e.to_string())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+        signer.update(&self.session_salt).map_err(|e| e.to_string())?;
+        signer.update(&timestamp.to_be_bytes()).map_err(|e| e.to_string())?;
+        signer.sign_to_vec().map_err(|e| e.to_string())
+    }
+
+    /// 登录数据 登录时间戳(秒)+登录凭证
+    pub fn login_request(&self, timestamp: u64) -> Result<Vec<u8>, String> {
+        let mut data = timestamp.to_be_bytes().to_vec();
+        data.extend(self.login_proof(timestamp)?);
+        Ok(data)
+    }
+
+    /// 旧版本的登录凭证 不带登录时间戳
+    pub fn legacy_login_proof(&self) -> Result<Vec<u8>, String> {
+        let key = PKey::hmac(&self.auth_key).map_err(|e| e.to_string())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+        signer.update(&self.session_salt).map_err(|e| e.to_string())?;
+        signer.sign_to_vec().map_err(|e| e.to_string())
+    }
+
+    /// 校验旧版本的登录凭证
+    pub fn verify_legacy_login_proof(&self, proof: &[u8]) -> Result<(), String> {
+        let expected = self.legacy_login_proof()?;
+        if expected.len() == proof.len() && memcmp::eq(&expected, proof) {
+            Ok(())
+        } else {
+            Err("登录凭证错误".to_string())
+        }
+    }
+
+    /// 校验登录数据 返回拒绝的原因
+    pub fn verify_login_request(&self, data: &[u8], now: u64) -> Result<(), String> {
+        if data.len() < 8 {
+            return Err("登录数据长度错误".to_string());
+        }
+        let (timestamp, proof) = data.split_at(8);
+        let timestamp = u64::from_be_bytes([timestamp[0], timestamp[1], timestamp[2], timestamp[3], timestamp[4], timestamp[5], timestamp[6], timestamp[7]]);
+        if timestamp.abs_diff(now) > LOGIN_WINDOW_SECS {
+            return Err(format!("登录时间戳超出范围: {}", timestamp));
+        }
+        let expected = self.login_proof(timestamp)?;
+        if expected.len() == proof.len() && memcmp::eq(&expected, proof) {
+            Ok(())
+        } else {
+            Err("登录凭证错误".to_string())
+        }
+    }
+}
+
+fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8]) -> Result<Vec<u8>, String> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF).map_err(|e| e.to_string())?;
+    ctx.derive_init().map_err(|e| e.to_string())?;
+    ctx.set_hkdf_md(Md::sha256()).map_err(|e| e.to_string())?;
+    ctx.set_hkdf_key(ikm).map_err(|e| e.to_string())?;
+    ctx.set_hkdf_salt(salt).map_err(|e| e.to_string())?;
+    ctx.add_hkdf_info(info).map_err(|e| e.to_string())?;
+    let mut key = vec![0u8; KEY_LEN];
+    ctx.derive(Some(&mut key)).map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+#[test]
+fn test_frame_cipher() {
+    let key = [7u8; 32];
+    for suite in [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305] {
+        let mut encryptor = FrameCipher::new(suite, &key, FrameDirection::ClientToServer);
+        let mut decryptor = FrameCipher::new(suite, &key, FrameDirection::ClientToServer);
+        let first = encryptor.seal(b"aad", b"first").unwrap();
+        let second = encryptor.seal(b"aad", b"second").unwrap();
+
+        // 乱序的帧无法通过认证
+        assert!(FrameCipher::new(suite, &key, FrameDirection::ClientToServer).open(b"aad", &second).is_err());
+        // 篡改的帧无法通过认证
+        let mut tampered = first.clone();
+        tampered[0] ^= 0x01;
+        assert!(FrameCipher::new(suite, &key, FrameDirection::ClientToServer).open(b"aad", &tampered).is_err());
+        // 另一个方向的帧无法通过认证
+        assert!(FrameCipher::new(suite, &key, FrameDirection::ServerToClient).open(b"aad", &first).is_err());
+
+        assert_eq!(decryptor.open(b"aad", &first).unwrap(), b"first");
+        assert_eq!(decryptor.open(b"aad", &second).unwrap(), b"second");
+    }
+}
+
+#[test]
+fn test_session_keys() {
+    let client_salt = random_salt().unwrap();
+    let server_salt = random_salt().unwrap();
+    let transcript = transcript_hash(b"hello", b"hello ack").unwrap();
+    let client = SessionKeys::derive(b"password", &client_salt, &server_salt, &transcript).unwrap();
+    let server = SessionKeys::derive(b"password", &client_salt, &server_salt, &transcript).unwrap();
+    assert_ne!(client.client_key, client.server_key);
+    let now = 1_700_000_000;
+    assert!(server.verify_login_request(&client.login_request(now).unwrap(), now + 1).is_ok());
+
+    // 密码错误或会话盐不同，凭证都无法通过校验
+    let wrong_password = SessionKeys::derive(b"wrong", &client_salt, &server_salt, &transcript).unwrap();
+    assert!(server.verify_login_request(&wrong_password.login_request(now).unwrap(), now).is_err());
+    let other_session = SessionKeys::derive(b"password", &client_salt, &random_salt().unwrap(), &transcript).unwrap();
+    assert!(other_session.verify_login_request(&client.login_request(now).unwrap(), now).is_err());
+    // 握手数据被篡改时凭证无法通过校验
+    let tampered = SessionKeys::derive(b"password", &client_salt, &server_salt, &transcript_hash(b"hello", b"tampered ack").unwrap()).unwrap();
+    assert!(tampered.verify_login_request(&client.login_request(now).unwrap(), now).is_err());
+    assert_ne!(transcript_hash(b"hello", b"hello ack").unwrap(), transcript_hash(b"hellohello", b" ack").unwrap());
+    // 超出时间窗口的登录数据被拒绝
+    assert!(server.verify_login_request(&client.login_request(now).unwrap(), now + LOGIN_WINDOW_SECS + 1).is_err());
+    // 修改时间戳后凭证无法通过校验
+    let mut request = client.login_request(now).unwrap();
+    request[7] ^= 0x01;
+    assert!(server.verify_login_request(&request, now).is_err());
+    // 密码经过拉伸 和旧版本直接用密码派生的密钥不同
+    let stretched = SessionKeys::derive(b"password", &client_salt, &server_salt, &[]).unwrap();
+    assert_ne!(stretched.client_key, SessionKeys::derive_legacy(b"password", &client_salt, &server_salt).unwrap().client_key);
+    // 旧版本的登录凭证
+    let client = SessionKeys::derive_legacy(b"password", &client_salt, &server_salt).unwrap();
+    let server = SessionKeys::derive_legacy(b"password", &client_salt, &server_salt).unwrap();
+    let wrong_password = SessionKeys::derive_legacy(b"wrong", &client_salt, &server_salt).unwrap();
+    assert!(server.verify_legacy_login_proof(&client.legacy_login_proof().unwrap()).is_ok());
+    assert!(server.verify_legacy_login_proof(&wrong_password.legacy_login_proof().unwrap()).is_err());
+}
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::error::TunnelError;
+use crate::tunnel::cipher::{FrameCipher, TAG_LEN};
+use crate::tunnel::compress::{Compression, CompressionStats, Compressor, Decompressor, should_compress};
+use crate::tunnel::hello::{FRAME_COUNTER_VERSION, PROTOCOL_VERSION};
+use crate::tunnel::transport::FrameRoute;
+use crate::tunnel::tunnel::PLAIN_SUITE;
+use crate::tunnel::tunnel_package::{DecodeError, TunnelPackage};
+
+/// 数据帧头
+const FRAME_MAGIC: [u8; 2] = [0x0f, 0x2f];
+/// 帧头+数据长度+加密套件
+pub const FRAME_HEADER_LEN: usize = 7;
+/// 加密套件字节的低4位是加密套件，高4位是压缩算法
+const SUITE_MASK: u8 = 0x0f;
+/// 加密帧在帧头之后带8字节的帧计数器
+const SEQ_LEN: usize = 8;
+/// 单个数据帧的最大长度 不会按对端声明的任意长度预留内存
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// 允许乱序到达时 帧计数器落后最大值的最大距离
+pub const REPLAY_WINDOW: u64 = 64 * 1024;
+/// 握手帧的数据包格式 版本协商之前使用，任何版本的对端都能解析
+const HANDSHAKE_VERSION: u8 = 1;
+
+/// 数据帧编码器
+/// 帧头、帧计数器、数据包和认证标签直接写入同一个缓冲区，数据包原地加密
+pub struct FrameEncoder {
+    /// 为空时编码明文帧，只用于握手
+    cipher: Option<FrameCipher>,
+    /// 协商的协议版本 决定数据包格式
+    version: u8,
+    compressor: Compressor,
+    /// 复用的压缩输出缓冲区
+    compress_buffer: Vec<u8>,
+    stats: Arc<CompressionStats>,
+}
+
+impl FrameEncoder {
+    pub fn new(cipher: FrameCipher) -> FrameEncoder {
+        FrameEncoder {
+            cipher: Some(cipher),
+            version: PROTOCOL_VERSION,
+            compressor: Compressor::default(),
+            compress_buffer: Vec::new(),
+            stats: Arc::new(CompressionStats::default()),
+        }
+    }
+
+    pub fn plain() -> FrameEncoder {
+        FrameEncoder {
+            cipher: None,
+            version: HANDSHAKE_VERSION,
+            compressor: Compressor::default(),
+            compress_buffer: Vec::new(),
+            stats: Arc::new(CompressionStats::default()),
+        }
+    }
+
+    /// 按协商的协议版本编码
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    /// 设置压缩算法 只有双方都支持压缩时才可以开启
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compressor = Compressor::new(compression);
+    }
+
+    /// 发送方向的压缩统计
+    pub fn stats(&self) -> Arc<CompressionStats> {
+        self.stats.clone()
+    }
+
+    /// 编码一个数据包并追加到dst
+    pub fn encode(&mut self, package: &TunnelPackage, dst: &mut BytesMut) -> Result<(), TunnelError> {
+        let mut suite = match self.cipher.as_ref() {
+            Some(cipher) => { cipher.suite().as_byte() }
+            None => { PLAIN_SUITE }
+        };
+        // 旧版本的帧不带帧计数器
+        let seq = match self.cipher.as_ref() {
+            Some(cipher) if self.version >= FRAME_COUNTER_VERSION => { Some(cipher.counter().to_be_bytes()) }
+            _ => { None }
+        };
+        let tag_len = match (self.cipher.is_some(), seq.is_some()) {
+            (true, true) => { SEQ_LEN + TAG_LEN }
+            (true, false) => { TAG_LEN }
+            (false, _) => { 0 }
+        };
+        let header_start = dst.len();
+        dst.reserve(FRAME_HEADER_LEN + package.encoded_len_version(self.version) + tag_len);
+        dst.put_slice(&FRAME_MAGIC);
+        dst.put_u32(0);
+        dst.put_u8(suite);
+        if let Some(seq) = seq.as_ref() {
+            dst.put_slice(seq);
+        }
+
+        let body_start = dst.len();
+        package.encode_version(self.version, dst);
+        let original = dst.len() - body_start;
+
+        // 压缩后没有变小就发送原始数据
+        let compression = self.compressor.compression();
+        if compression != Compression::None && package.data.as_deref().is_some_and(should_compress) {
+            self.compressor.compress(&dst[body_start..], &mut self.compress_buffer).map_err(TunnelError::Protocol)?;
+            if self.compress_buffer.len() < original {
+                dst.truncate(body_start);
+                dst.extend_from_slice(&self.compress_buffer);
+                suite |= compression.as_flag() << 4;
+                dst[header_start + FRAME_HEADER_LEN - 1] = suite;
+            }
+        }
+        if dst.len() - header_start + tag_len > MAX_FRAME_SIZE {
+            dst.truncate(header_start);
+            return Err(DecodeError::FrameTooLarge(package.encoded_len_version(self.version)).into());
+        }
+        self.stats.record(original, dst.len() - body_start);
+
+        if let Some(cipher) = self.cipher.as_mut() {
+            let tag = match seq {
+                Some(seq) => { cipher.seal_in_place(&frame_aad(suite, &seq), &mut dst[body_start..]) }
+                None => { cipher.seal_in_place(&legacy_frame_aad(suite), &mut dst[body_start..]) }
+            }.map_err(TunnelError::Crypto)?;
+            dst.put_slice(&tag);
+        }
+        let data_length = (dst.len() - header_start - (FRAME_HEADER_LEN - 1)) as u32;
+        dst[header_start + 2..header_start + 6].copy_from_slice(&data_length.to_be_bytes());
+        Ok(())
+    }
+}
+
+/// 重放窗口 记录最近收到的帧计数器，窗口内的帧可以乱序到达但只能收到一次
+/// 同一路由的帧走同一个流，按发送顺序到达，另外按路由记录最后的帧计数器
+struct ReplayWindow {
+    /// 已收到的最大帧计数器+1
+    next: u64,
+    /// 按计数器对窗口大小取余的位图
+    bitmap: Vec<u64>,
+    /// 每个流上最后收到的帧计数器 连接关闭后删除
+    streams: HashMap<FrameRoute, u64>,
+}
+
+impl ReplayWindow {
+    fn new() -> ReplayWindow {
+        ReplayWindow { next: 0, bitmap: vec![0; (REPLAY_WINDOW / 64) as usize], streams: HashMap::new() }
+    }
+
+    /// 认证通过后判断帧是否可以接受 返回false表示丢弃这一帧
+    /// 流上的帧只要求在本流内递增，积压的流落后窗口也不会丢帧；UDP数据落后窗口时丢弃
+    fn accept(&mut self, counter: u64, route: FrameRoute) -> Result<bool, DecodeError> {
+        let key = match route {
+            FrameRoute::Datagram(_) => {
+                if self.is_stale(counter) {
+                    return Ok(false);
+                }
+                if !self.check(counter) {
+                    return Err(DecodeError::Replay(counter));
+                }
+                self.mark(counter);
+                return Ok(true);
+            }
+            FrameRoute::Close(stream_id) => { FrameRoute::Stream(stream_id) }
+            route => { route }
+        };
+        match self.streams.get(&key) {
+            Some(last) => {
+                if counter <= *last {
+                    return Err(DecodeError::Replay(counter));
+                }
+            }
+            None => {
+                // 流的第一帧 只能按窗口判断是否重复
+                if !self.check(counter) {
+                    return Err(DecodeError::Replay(counter));
+                }
+            }
+        }
+        if !self.is_stale(counter) {
+            self.mark(counter);
+        }
+        if matches!(route, FrameRoute::Close(_)) {
+            self.streams.remove(&key);
+        } else {
+            self.streams.insert(key, counter);
+        }
+        Ok(true)
+    }
+
+    fn slot(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    /// 帧计数器落后窗口 无法判断是否重复
+    fn is_stale(&self, counter: u64) -> bool {
+        counter < self.next && self.next - counter > REPLAY_WINDOW
+    }
+
+    /// 帧计数器是否可以接受 认证通过后再调用mark
+    fn check(&self, counter: u64) -> bool {
+        if counter >= self.next {
+            return true;
+        }
+        if self.next - counter > REPLAY_WINDOW {
+            return false;
+        }
+        let (word, bit) = Self::slot(counter);
+        self.bitmap[word] & bit == 0
+    }
+
+    fn mark(&mut self, counter: u64) {
+        if counter >= self.next {
+            // 窗口前移 清掉移出窗口的旧记录
+            if counter - self.next >= REPLAY_WINDOW {
+                self.bitmap.iter_mut().for_each(|word| *word = 0);
+            } else {
+                for skipped in self.next..counter {
+                    let (word, bit) = Self::slot(skipped);
+                    self.bitmap[word] &= !bit;
+                }
+            }
+            self.next = counter + 1;
+        }
+        let (word, bit) = Self::slot(counter);
+        self.bitmap[word] |= bit;
+    }
+}
+
+/// 数据帧解码器
+/// 从读缓冲区切出完整的帧原地解密，不复制也不重新分配缓冲区
+pub struct FrameDecoder {
+    /// 为空时只接受明文帧，只用于握手
+    cipher: Option<FrameCipher>,
+    /// 协商的协议版本 决定数据包格式
+    version: u8,
+    /// 不为空时允许帧乱序到达
+    replay_window: Option<ReplayWindow>,
+    decompressor: Decompressor,
+    /// 复用的解压输出缓冲区
+    decompress_buffer: Vec<u8>,
+    stats: Arc<CompressionStats>,
+}
+
+impl FrameDecoder {
+    pub fn new(cipher: FrameCipher) -> FrameDecoder {
+        FrameDecoder {
+            cipher: Some(cipher),
+            version: PROTOCOL_VERSION,
+            replay_window: None,
+            decompressor: Decompressor::default(),
+            decompress_buffer: Vec::new(),
+            stats: Arc::new(CompressionStats::default()),
+        }
+    }
+
+    pub fn plain() -> FrameDecoder {
+        FrameDecoder {
+            cipher: None,
+            version: HANDSHAKE_VERSION,
+            replay_window: None,
+            decompressor: Decompressor::default(),
+            decompress_buffer: Vec::new(),
+            stats: Arc::new(CompressionStats::default()),
+        }
+    }
+
+    /// 接收方向的压缩统计
+    pub fn stats(&self) -> Arc<CompressionStats> {
+        self.stats.clone()
+    }
+
+    /// 按协商的协议版本解码
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    /// 允许帧在重放窗口内乱序到达 用于每个连接单独成流的传输方式
+    /// 不带帧计数器的旧版本仍按到达顺序解密
+    pub fn set_unordered(&mut self) {
+        self.replay_window = Some(ReplayWindow::new());
+    }
+
+    /// 从src取出一个完整的数据包 数据不完整时返回None
+    /// 乱序到达时先认证再检查重放，落后重放窗口的UDP数据直接丢弃
+    pub fn decode(&mut self, src: &mut BytesMut) -> Result<Option<TunnelPackage>, DecodeError> {
+        loop {
+            let (suite, mut body) = match split_frame(src)? {
+                Some(frame) => { frame }
+                None => { return Ok(None); }
+            };
+
+            let mut unordered_counter = None;
+            match self.cipher.as_mut() {
+                Some(cipher) => {
+                    // 加密套件必须和登录时选择的一致
+                    if suite & SUITE_MASK != cipher.suite().as_byte() {
+                        return Err(DecodeError::SuiteMismatch { expected: cipher.suite().as_byte(), received: suite & SUITE_MASK });
+                    }
+                    if self.version < FRAME_COUNTER_VERSION {
+                        // 旧版本的帧不带帧计数器 只能按到达顺序解密
+                        if body.len() < TAG_LEN {
+                            return Err(DecodeError::InvalidFrameLength(body.len()));
+                        }
+                        let tag = body.split_off(body.len() - TAG_LEN);
+                        cipher.open_in_place(&legacy_frame_aad(suite), &mut body, &tag).map_err(DecodeError::Decrypt)?;
+                    } else {
+                        if body.len() < SEQ_LEN + TAG_LEN {
+                            return Err(DecodeError::InvalidFrameLength(body.len()));
+                        }
+                        let seq = body.split_to(SEQ_LEN);
+                        let counter = u64::from_be_bytes([seq[0], seq[1], seq[2], seq[3], seq[4], seq[5], seq[6], seq[7]]);
+                        let tag = body.split_off(body.len() - TAG_LEN);
+                        if self.replay_window.is_some() {
+                            // 认证通过、知道帧所属的流之后再检查重放
+                            cipher.open_in_place_at(counter, &frame_aad(suite, &seq), &mut body, &tag).map_err(DecodeError::Decrypt)?;
+                            unordered_counter = Some(counter);
+                        } else {
+                            // 帧计数器必须连续递增 小于期望值是重放，大于期望值是乱序或丢帧
+                            if counter < cipher.counter() {
+                                return Err(DecodeError::Replay(counter));
+                            }
+                            if counter > cipher.counter() {
+                                return Err(DecodeError::OutOfOrder { expected: cipher.counter(), received: counter });
+                            }
+                            cipher.open_in_place(&frame_aad(suite, &seq), &mut body, &tag).map_err(DecodeError::Decrypt)?;
+                        }
+                    }
+                }
+                None => {
+                    if suite != PLAIN_SUITE {
+                        return Err(DecodeError::UnexpectedSuite(suite));
+                    }
+                }
+            }
+
+            let package = self.parse(suite, &body)?;
+            if let (Some(counter), Some(replay_window)) = (unordered_counter, self.replay_window.as_mut()) {
+                if !replay_window.accept(counter, FrameRoute::of(&package))? {
+                    log::error!("drop stale datagram: {}", counter);
+                    continue;
+                }
+            }
+            return Ok(Some(package));
+        }
+    }
+
+    /// 解压并解析解密后的数据包
+    fn parse(&mut self, suite: u8, body: &[u8]) -> Result<TunnelPackage, DecodeError> {
+        let compression = Compression::from_flag(suite >> 4).ok_or(DecodeError::UnknownCompression(suite >> 4))?;
+        if compression == Compression::None {
+            self.stats.record(body.len(), body.len());
+            return TunnelPackage::from_byte_array_version(body, self.version);
+        }
+        self.decompressor.decompress(compression, body, &mut self.decompress_buffer).map_err(DecodeError::Decompress)?;
+        self.stats.record(self.decompress_buffer.len(), body.len());
+        TunnelPackage::from_byte_array_version(&self.decompress_buffer, self.version)
+    }
+}
+
+/// 附加认证数据 帧头、加密套件和帧计数器
+fn frame_aad(suite: u8, seq: &[u8]) -> [u8; 3 + SEQ_LEN] {
+    let mut aad = [0u8; 3 + SEQ_LEN];
+    aad[..2].copy_from_slice(&FRAME_MAGIC);
+    aad[2] = suite;
+    aad[3..].copy_from_slice(seq);
+    aad
+}
+
+/// 旧版本的附加认证数据 只有帧头和加密套件
+fn legacy_frame_aad(suite: u8) -> [u8; 3] {
+    [FRAME_MAGIC[0], FRAME_MAGIC[1], suite]
+}
+
+/// 读取缓冲区中下一个数据帧的加密套件 服务端据此为登录帧选择解密器
+pub fn peek_suite(src: &[u8]) -> Option<u8> {
+    if src.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    Some(src[FRAME_HEADER_LEN - 1] & SUITE_MASK)
+}
+
+/// 从缓冲区切出一个完整的数据帧 返回加密套件和数据
+pub fn split_frame(src: &mut BytesMut) -> Result<Option<(u8, BytesMut)>, DecodeError> {
+    if src.len() < FRAME_HEADER_LEN - 1 {
+        return Ok(None);
+    }
+
+    // 校验数据头
+    if src[..2] != FRAME_MAGIC {
+        return Err(DecodeError::BadMagic([src[0], src[1]]));
+    }
+
+    let data_length = u32::from_be_bytes([src[2], src[3], src[4], src[5]]) as usize;
+    if data_length < 1 {
+        return Err(DecodeError::InvalidFrameLength(data_length));
+    }
+    if data_length > MAX_FRAME_SIZE {
+        return Err(DecodeError::FrameTooLarge(data_length));
+    }
+    if src.len() < data_length + FRAME_HEADER_LEN - 1 {
+        // 提前预留整帧的空间，大帧不会反复扩容
+        src.reserve(data_length + FRAME_HEADER_LEN - 1 - src.len());
+        return Ok(None);
+    }
+
+    let mut frame = src.split_to(data_length + FRAME_HEADER_LEN - 1);
+    let suite = frame[FRAME_HEADER_LEN - 1];
+    frame.advance(FRAME_HEADER_LEN);
+    Ok(Some((suite, frame)))
+}
+
+#[test]
+fn test_frame_codec() {
+    use crate::tunnel::cipher::{CipherSuite, FrameDirection};
+    use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol};
+
+    let key = [7u8; 32];
+    let mut encoder = FrameEncoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &key, FrameDirection::ClientToServer));
+    let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &key, FrameDirection::ClientToServer));
+
+    let mut buffer = BytesMut::new();
+    for i in 0..3u8 {
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, i as u32, None, None, Some(vec![i; 100]));
+        encoder.encode(&package, &mut buffer).unwrap();
+    }
+
+    // 分段到达的帧等数据完整后再解码
+    let mut received = buffer.split_to(10);
+    assert!(decoder.decode(&mut received).unwrap().is_none());
+    received.unsplit(buffer);
+    for i in 0..3u8 {
+        let package = decoder.decode(&mut received).unwrap().unwrap();
+        assert_eq!(package.stream_id, i as u32);
+        assert_eq!(package.data, Some(vec![i; 100]));
+    }
+    assert!(received.is_empty());
+    assert!(decoder.decode(&mut received).unwrap().is_none());
+
+    // 文本数据压缩后发送，高熵数据直接发送
+    encoder.set_compression(Compression::Zstd);
+    let text = b"<html><body>hello tunnel</body></html>".repeat(100);
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 1, None, None, Some(text.clone()));
+    encoder.encode(&package, &mut received).unwrap();
+    assert!(received.len() < text.len() / 4);
+    assert_eq!(decoder.decode(&mut received).unwrap().unwrap().data, Some(text));
+    let mut random = vec![0u8; 4096];
+    openssl::rand::rand_bytes(&mut random).unwrap();
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 1, None, None, Some(random.clone()));
+    encoder.encode(&package, &mut received).unwrap();
+    assert!(received.len() > random.len());
+    assert_eq!(decoder.decode(&mut received).unwrap().unwrap().data, Some(random));
+    assert!(decoder.stats().summary().ratio() < 0.6);
+
+    // 重放的帧和乱序的帧被拒绝
+    let package = TunnelPackage::new(PackageCmd::NewConnect, PackageProtocol::TCP, 9, None, Some("example.com:443".to_string()), None);
+    encoder.encode(&package, &mut received).unwrap();
+    let mut replay = received.clone();
+    assert!(decoder.decode(&mut received).unwrap().is_some());
+    assert_eq!(decoder.decode(&mut replay).unwrap_err(), DecodeError::Replay(5));
+    let mut skipped = BytesMut::new();
+    encoder.encode(&package, &mut skipped).unwrap();
+    skipped.clear();
+    encoder.encode(&package, &mut skipped).unwrap();
+    assert_eq!(decoder.decode(&mut skipped).unwrap_err(), DecodeError::OutOfOrder { expected: 6, received: 7 });
+
+    // 声明的帧长度超过上限时不等待数据直接拒绝
+    let mut huge = BytesMut::from(&[0x0f, 0x2f, 0xff, 0xff, 0xff, 0xff, 0x01][..]);
+    assert_eq!(decoder.decode(&mut huge).unwrap_err(), DecodeError::FrameTooLarge(u32::MAX as usize));
+}
+
+#[test]
+fn test_unordered_decoder() {
+    use crate::tunnel::cipher::{CipherSuite, FrameDirection};
+    use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol};
+
+    let key = [9u8; 32];
+    let mut encoder = FrameEncoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &key, FrameDirection::ServerToClient));
+    let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &key, FrameDirection::ServerToClient));
+    decoder.set_unordered();
+
+    let mut frames = vec![];
+    for i in 0..4u8 {
+        let mut frame = BytesMut::new();
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, i as u32, None, None, Some(vec![i; 10]));
+        encoder.encode(&package, &mut frame).unwrap();
+        frames.push(frame);
+    }
+
+    // 乱序和丢失的帧都可以解码 同一帧只能收到一次
+    for i in [2usize, 0, 3] {
+        let mut frame = frames[i].clone();
+        assert_eq!(decoder.decode(&mut frame).unwrap().unwrap().stream_id, i as u32);
+    }
+    assert_eq!(decoder.decode(&mut frames[0].clone()).unwrap_err(), DecodeError::Replay(0));
+    assert_eq!(decoder.decode(&mut frames[3].clone()).unwrap_err(), DecodeError::Replay(3));
+
+    // 积压的TCP流落后重放窗口后 流上的帧仍然按顺序全部解码
+    let slow = |encoder: &mut FrameEncoder, data: u8| {
+        let mut frame = BytesMut::new();
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 5, None, None, Some(vec![data; 10]));
+        encoder.encode(&package, &mut frame).unwrap();
+        frame
+    };
+    assert_eq!(decoder.decode(&mut slow(&mut encoder, 1)).unwrap().unwrap().data, Some(vec![1; 10]));
+    let mut backlog = slow(&mut encoder, 2);
+    backlog.unsplit(slow(&mut encoder, 3));
+    let replay = backlog.clone();
+    let mut stale_datagram = BytesMut::new();
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::UDP, 6, None, Some("127.0.0.1:53".to_string()), Some(vec![6; 10]));
+    encoder.encode(&package, &mut stale_datagram).unwrap();
+    let mut forged = slow(&mut encoder, 4);
+    let last = forged.len() - 1;
+    forged[last] ^= 1;
+    let mut latest = BytesMut::new();
+    for _ in 0..=REPLAY_WINDOW {
+        latest.clear();
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 7, None, None, Some(vec![7; 10]));
+        encoder.encode(&package, &mut latest).unwrap();
+    }
+    assert!(decoder.decode(&mut latest).unwrap().is_some());
+    for data in [2u8, 3] {
+        assert_eq!(decoder.decode(&mut backlog).unwrap().unwrap().data, Some(vec![data; 10]));
+    }
+    assert_eq!(decoder.decode(&mut replay.clone()).unwrap_err(), DecodeError::Replay(5));
+    // 伪造的帧先认证 认证失败返回错误而不是丢弃
+    assert!(matches!(decoder.decode(&mut forged).unwrap_err(), DecodeError::Decrypt(_)));
+
+    // 落后窗口的UDP数据丢弃 同一缓冲区中之后的帧继续解码
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 8, None, None, Some(vec![8; 10]));
+    encoder.encode(&package, &mut stale_datagram).unwrap();
+    assert_eq!(decoder.decode(&mut stale_datagram).unwrap().unwrap().stream_id, 8);
+    assert!(stale_datagram.is_empty());
+
+    let mut window = ReplayWindow::new();
+    window.mark(5);
+    window.mark(REPLAY_WINDOW + 10);
+    assert!(!window.check(5));
+    assert!(!window.check(9));
+    assert!(window.check(11));
+    assert!(!window.check(REPLAY_WINDOW + 10));
+}
+
+#[test]
+fn test_legacy_frame_codec() {
+    use crate::tunnel::cipher::{CipherSuite, FrameDirection};
+    use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol};
+
+    let key = [5u8; 32];
+    let mut encoder = FrameEncoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &key, FrameDirection::ClientToServer));
+    let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &key, FrameDirection::ClientToServer));
+    encoder.set_version(FRAME_COUNTER_VERSION - 1);
+    decoder.set_version(FRAME_COUNTER_VERSION - 1);
+
+    // 旧版本的帧不带帧计数器 按顺序解密
+    let mut buffer = BytesMut::new();
+    for i in 1..3u8 {
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, i as u32, None, None, Some(vec![i; 10]));
+        encoder.encode(&package, &mut buffer).unwrap();
+    }
+    assert_eq!(buffer.len(), 2 * (FRAME_HEADER_LEN + 12 + 10 + TAG_LEN));
+    let mut replay = buffer.clone();
+    for i in 1..3u8 {
+        assert_eq!(decoder.decode(&mut buffer).unwrap().unwrap().data, Some(vec![i; 10]));
+    }
+    // 重放的帧无法通过认证
+    assert!(matches!(decoder.decode(&mut replay).unwrap_err(), DecodeError::Decrypt(_)));
+}
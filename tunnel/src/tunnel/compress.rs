@@ -0,0 +1,219 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 小于这个长度的数据包不压缩
+const MIN_COMPRESS_SIZE: usize = 128;
+/// 估算熵时采样的字节数
+const ENTROPY_SAMPLE_SIZE: usize = 1024;
+/// 熵超过这个值(比特/字节)时认为数据已经压缩或加密过，例如TLS记录
+const MAX_ENTROPY: f64 = 7.2;
+/// 解压后的最大长度
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+/// zstd压缩级别 偏向速度
+const ZSTD_LEVEL: i32 = 1;
+
+/// 帧压缩算法 编号写在数据帧加密套件字节的高4位
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_index(i: i32) -> Option<Compression> {
+        match i {
+            0 => { Some(Compression::None) }
+            1 => { Some(Compression::Lz4) }
+            2 => { Some(Compression::Zstd) }
+            _ => { None }
+        }
+    }
+
+    pub fn from_flag(flag: u8) -> Option<Compression> {
+        Compression::from_index(flag as i32)
+    }
+
+    pub fn as_flag(&self) -> u8 {
+        match self {
+            Compression::None => { 0 }
+            Compression::Lz4 => { 1 }
+            Compression::Zstd => { 2 }
+        }
+    }
+}
+
+/// 数据是否值得压缩 太短或者熵太高的数据直接发送
+pub fn should_compress(data: &[u8]) -> bool {
+    data.len() >= MIN_COMPRESS_SIZE && entropy(&data[..data.len().min(ENTROPY_SAMPLE_SIZE)]) <= MAX_ENTROPY
+}
+
+/// 香农熵 单位比特/字节
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0u32; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts.iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// 压缩器 复用压缩上下文和输出缓冲区
+#[derive(Default)]
+pub struct Compressor {
+    compression: Compression,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Compressor {
+        Compressor { compression, zstd: None }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// 压缩src写入dst lz4数据前加4字节原始长度
+    pub fn compress(&mut self, src: &[u8], dst: &mut Vec<u8>) -> Result<(), String> {
+        dst.clear();
+        match self.compression {
+            Compression::None => {
+                dst.extend_from_slice(src);
+            }
+            Compression::Lz4 => {
+                dst.extend_from_slice(&(src.len() as u32).to_le_bytes());
+                dst.resize(4 + lz4_flex::block::get_maximum_output_size(src.len()), 0);
+                let n = lz4_flex::compress_into(src, &mut dst[4..]).map_err(|e| e.to_string())?;
+                dst.truncate(4 + n);
+            }
+            Compression::Zstd => {
+                if self.zstd.is_none() {
+                    self.zstd = Some(zstd::bulk::Compressor::new(ZSTD_LEVEL).map_err(|e| e.to_string())?);
+                }
+                if let Some(compressor) = self.zstd.as_mut() {
+                    dst.reserve(zstd::zstd_safe::compress_bound(src.len()));
+                    compressor.compress_to_buffer(src, dst).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 解压器 复用解压上下文
+#[derive(Default)]
+pub struct Decompressor {
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl Decompressor {
+    /// 按压缩算法解压src写入dst 解压后超过最大长度时返回错误
+    pub fn decompress(&mut self, compression: Compression, src: &[u8], dst: &mut Vec<u8>) -> Result<(), String> {
+        dst.clear();
+        match compression {
+            Compression::None => {
+                dst.extend_from_slice(src);
+            }
+            Compression::Lz4 => {
+                if src.len() < 4 {
+                    return Err("压缩数据长度错误".to_string());
+                }
+                let len = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+                if len > MAX_DECOMPRESSED_SIZE {
+                    return Err("解压后数据过长".to_string());
+                }
+                dst.resize(len, 0);
+                let n = lz4_flex::decompress_into(&src[4..], dst).map_err(|e| e.to_string())?;
+                if n != len {
+                    return Err("压缩数据长度错误".to_string());
+                }
+            }
+            Compression::Zstd => {
+                if self.zstd.is_none() {
+                    self.zstd = Some(zstd::bulk::Decompressor::new().map_err(|e| e.to_string())?);
+                }
+                if let Some(decompressor) = self.zstd.as_mut() {
+                    let len = zstd::zstd_safe::get_frame_content_size(src)
+                        .ok().flatten()
+                        .ok_or("压缩数据长度错误")?;
+                    if len > MAX_DECOMPRESSED_SIZE as u64 {
+                        return Err("解压后数据过长".to_string());
+                    }
+                    dst.reserve(len as usize);
+                    decompressor.decompress_to_buffer(src, dst).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 压缩统计
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CompressionSummary {
+    /// 压缩前的字节数
+    pub original: u64,
+    /// 压缩后的字节数
+    pub compressed: u64,
+}
+
+impl CompressionSummary {
+    /// 压缩后与压缩前的比值 没有数据时为1
+    pub fn ratio(&self) -> f64 {
+        if self.original == 0 { 1.0 } else { self.compressed as f64 / self.original as f64 }
+    }
+
+    pub fn add(&mut self, other: CompressionSummary) {
+        self.original += other.original;
+        self.compressed += other.compressed;
+    }
+}
+
+/// 编解码器和读写线程共享的压缩计数
+#[derive(Default)]
+pub struct CompressionStats {
+    original: AtomicU64,
+    compressed: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn record(&self, original: usize, compressed: usize) {
+        self.original.fetch_add(original as u64, Ordering::Relaxed);
+        self.compressed.fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> CompressionSummary {
+        CompressionSummary {
+            original: self.original.load(Ordering::Relaxed),
+            compressed: self.compressed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[test]
+fn test_compression() {
+    let text = "GET /index.html HTTP/1.1\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n".repeat(20);
+    assert!(should_compress(text.as_bytes()));
+    // 随机数据和短数据不压缩
+    let mut random = [0u8; 4096];
+    openssl::rand::rand_bytes(&mut random).unwrap();
+    assert!(!should_compress(&random));
+    assert!(!should_compress(b"short"));
+
+    let mut decompressor = Decompressor::default();
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        let mut compressed = Vec::new();
+        Compressor::new(compression).compress(text.as_bytes(), &mut compressed).unwrap();
+        assert!(compressed.len() < text.len() / 4);
+        let mut decompressed = Vec::new();
+        decompressor.decompress(compression, &compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, text.as_bytes());
+    }
+}
@@ -0,0 +1,61 @@
+use tokio::sync::broadcast::Sender;
+
+/// 事件队列长度 订阅方处理不及时会丢掉最早的事件
+pub const EVENT_CAPACITY: usize = 64;
+
+/// 隧道状态事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum TunnelEventKind {
+    /// 开始连接服务器
+    Connecting { host: String, port: u16 },
+    LoginSuccess,
+    LoginFail { reason: String },
+    /// 服务端返回或本端检测到的协议错误
+    ProtocolError { reason: String },
+    /// 收到PONG 延迟毫秒数
+    RttUpdate { rtt: u32 },
+    /// 等待delay_ms毫秒后第attempt次重连
+    ReconnectScheduled { attempt: u32, delay_ms: u64 },
+    /// 隧道断开或连接失败 主动断开时没有原因
+    Closed { reason: Option<String> },
+}
+
+impl TunnelEventKind {
+    /// 对外暴露的事件名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TunnelEventKind::Connecting { .. } => { "connecting" }
+            TunnelEventKind::LoginSuccess => { "login_success" }
+            TunnelEventKind::LoginFail { .. } => { "login_fail" }
+            TunnelEventKind::ProtocolError { .. } => { "protocol_error" }
+            TunnelEventKind::RttUpdate { .. } => { "rtt_update" }
+            TunnelEventKind::ReconnectScheduled { .. } => { "reconnect_scheduled" }
+            TunnelEventKind::Closed { .. } => { "closed" }
+        }
+    }
+}
+
+/// 隧道事件 带产生事件的服务器名称
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelEvent {
+    pub server: String,
+    pub kind: TunnelEventKind,
+}
+
+/// 以某台服务器的名义发布事件
+#[derive(Clone)]
+pub struct EventPublisher {
+    server: String,
+    sender: Sender<TunnelEvent>,
+}
+
+impl EventPublisher {
+    pub fn new(server: String, sender: Sender<TunnelEvent>) -> EventPublisher {
+        EventPublisher { server, sender }
+    }
+
+    /// 发布事件 没有订阅方时直接丢弃
+    pub fn publish(&self, kind: TunnelEventKind) {
+        let _ = self.sender.send(TunnelEvent { server: self.server.clone(), kind });
+    }
+}
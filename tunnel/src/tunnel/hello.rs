@@ -0,0 +1,184 @@
+use crate::tunnel::cipher::SALT_LEN;
+
+/// 当前协议版本
+pub const PROTOCOL_VERSION: u8 = 3;
+/// 支持的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// 版本2开始数据包带连接编号 之前的版本用源地址标识连接
+pub const STREAM_ID_VERSION: u8 = 2;
+/// 版本3开始加密帧带帧计数器并参与认证、登录带时间戳、会话密钥绑定握手记录 之前的版本按顺序隐式计数
+pub const FRAME_COUNTER_VERSION: u8 = 3;
+/// 服务端选择低于自己最高版本的协议时写在服务端盐末尾
+/// 支持更高版本的客户端看到这个标记说明握手被篡改降级
+const DOWNGRADE_SENTINEL: [u8; 8] = *b"FLYDOWNG";
+
+/// 能力位图 握手时双方取交集，按会话开启功能
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// 帧压缩
+    pub const COMPRESSION: u32 = 1 << 0;
+    /// ChaCha20-Poly1305加密套件
+    pub const CHACHA20_POLY1305: u32 = 1 << 1;
+    /// UDP转发
+    pub const UDP: u32 = 1 << 2;
+    /// 多路复用 每个连接单独成流，帧可以乱序到达
+    pub const MULTIPLEX: u32 = 1 << 3;
+    /// 按连接的信用窗口流量控制
+    pub const FLOW_CONTROL: u32 = 1 << 4;
+    /// 服务端解析域名
+    pub const REMOTE_DNS: u32 = 1 << 5;
+
+    /// 本端实现的能力
+    pub fn supported() -> Capabilities {
+        Capabilities(Capabilities::COMPRESSION | Capabilities::CHACHA20_POLY1305 | Capabilities::UDP | Capabilities::MULTIPLEX | Capabilities::FLOW_CONTROL | Capabilities::REMOTE_DNS)
+    }
+
+    /// 不支持版本协商的旧服务端 只有UDP转发
+    pub fn legacy() -> Capabilities {
+        Capabilities(Capabilities::UDP)
+    }
+
+    pub fn contains(&self, capability: u32) -> bool {
+        self.0 & capability == capability
+    }
+
+    pub fn intersect(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// 客户端握手 版本范围+能力+客户端盐
+pub struct Hello {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub capabilities: Capabilities,
+    pub salt: Vec<u8>,
+}
+
+impl Hello {
+    pub fn to_byte_array(&self) -> Vec<u8> {
+        let mut vec = vec![self.min_version, self.max_version];
+        vec.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        vec.extend_from_slice(&self.salt);
+        vec
+    }
+
+    /// 之后的版本可能在末尾追加字段 多出的数据忽略
+    pub fn from_byte_array(data: &[u8]) -> Result<Hello, String> {
+        if data.len() < 6 + SALT_LEN {
+            return Err("握手数据长度错误".to_string());
+        }
+        Ok(Hello {
+            min_version: data[0],
+            max_version: data[1],
+            capabilities: Capabilities(u32::from_be_bytes([data[2], data[3], data[4], data[5]])),
+            salt: data[6..6 + SALT_LEN].to_vec(),
+        })
+    }
+
+    /// 服务端选择双方都支持的最高版本
+    pub fn select_version(&self) -> Option<u8> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version >= self.min_version && version >= MIN_PROTOCOL_VERSION {
+            Some(version)
+        } else {
+            None
+        }
+    }
+
+    /// 服务端选择双方都支持的能力 乱序到达的帧要靠帧计数器解密，旧版本不能多路复用
+    pub fn select_capabilities(&self, version: u8) -> Capabilities {
+        let capabilities = self.capabilities.intersect(Capabilities::supported());
+        if version < FRAME_COUNTER_VERSION {
+            Capabilities(capabilities.0 & !Capabilities::MULTIPLEX)
+        } else {
+            capabilities
+        }
+    }
+}
+
+/// 服务端握手响应 选定版本+能力交集+服务端盐
+pub struct HelloAck {
+    pub version: u8,
+    pub capabilities: Capabilities,
+    pub salt: Vec<u8>,
+}
+
+impl HelloAck {
+    pub fn to_byte_array(&self) -> Vec<u8> {
+        let mut vec = vec![self.version];
+        vec.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        vec.extend_from_slice(&self.salt);
+        vec
+    }
+
+    /// 服务端选择了低于自己最高版本的协议 在盐的末尾写入降级标记
+    /// 盐参与会话密钥派生，中间人去掉标记后双方的密钥不同
+    pub fn mark_downgrade(&mut self) {
+        if self.version < PROTOCOL_VERSION && self.salt.len() >= DOWNGRADE_SENTINEL.len() {
+            let start = self.salt.len() - DOWNGRADE_SENTINEL.len();
+            self.salt[start..].copy_from_slice(&DOWNGRADE_SENTINEL);
+        }
+    }
+
+    /// 客户端支持更高版本时检查降级标记 带标记说明客户端握手被改成了更低的版本
+    pub fn is_downgraded(&self) -> bool {
+        self.version < PROTOCOL_VERSION && self.salt.ends_with(&DOWNGRADE_SENTINEL)
+    }
+
+    /// 之后的版本可能在末尾追加字段 多出的数据忽略
+    pub fn from_byte_array(data: &[u8]) -> Result<HelloAck, String> {
+        if data.len() < 5 + SALT_LEN {
+            return Err("握手响应长度错误".to_string());
+        }
+        Ok(HelloAck {
+            version: data[0],
+            capabilities: Capabilities(u32::from_be_bytes([data[1], data[2], data[3], data[4]])),
+            salt: data[5..5 + SALT_LEN].to_vec(),
+        })
+    }
+}
+
+#[test]
+fn test_hello() {
+    let hello = Hello {
+        min_version: MIN_PROTOCOL_VERSION,
+        max_version: PROTOCOL_VERSION + 1,
+        capabilities: Capabilities::supported(),
+        salt: vec![1u8; SALT_LEN],
+    };
+    let hello = Hello::from_byte_array(&hello.to_byte_array()).unwrap();
+    assert_eq!(hello.select_version(), Some(PROTOCOL_VERSION));
+    assert_eq!(hello.capabilities, Capabilities::supported());
+
+    // 不支持帧计数器的对端仍可以协商旧版本
+    let older = Hello { min_version: MIN_PROTOCOL_VERSION, max_version: FRAME_COUNTER_VERSION - 1, capabilities: Capabilities::default(), salt: vec![] };
+    assert_eq!(older.select_version(), Some(FRAME_COUNTER_VERSION - 1));
+
+    let too_new = Hello { min_version: PROTOCOL_VERSION + 1, max_version: PROTOCOL_VERSION + 2, capabilities: Capabilities::default(), salt: vec![] };
+    assert_eq!(too_new.select_version(), None);
+
+    // 末尾追加的字段被忽略
+    let mut data = Hello { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, capabilities: Capabilities::supported(), salt: vec![2u8; SALT_LEN] }.to_byte_array();
+    data.extend_from_slice(&[0xff; 4]);
+    assert_eq!(Hello::from_byte_array(&data).unwrap().salt, vec![2u8; SALT_LEN]);
+    let mut data = HelloAck { version: PROTOCOL_VERSION, capabilities: Capabilities::supported(), salt: vec![3u8; SALT_LEN] }.to_byte_array();
+    data.extend_from_slice(&[0xff; 4]);
+    assert_eq!(HelloAck::from_byte_array(&data).unwrap().salt, vec![3u8; SALT_LEN]);
+    assert!(HelloAck::from_byte_array(&data[..4 + SALT_LEN]).is_err());
+
+    // 旧版本不能多路复用
+    assert!(hello.select_capabilities(PROTOCOL_VERSION).contains(Capabilities::MULTIPLEX));
+    assert!(!hello.select_capabilities(FRAME_COUNTER_VERSION - 1).contains(Capabilities::MULTIPLEX));
+
+    // 选择旧版本时带降级标记 最高版本不带
+    let mut hello_ack = HelloAck { version: FRAME_COUNTER_VERSION - 1, capabilities: Capabilities::default(), salt: vec![4u8; SALT_LEN] };
+    assert!(!hello_ack.is_downgraded());
+    hello_ack.mark_downgrade();
+    assert!(HelloAck::from_byte_array(&hello_ack.to_byte_array()).unwrap().is_downgraded());
+    let mut hello_ack = HelloAck { version: PROTOCOL_VERSION, capabilities: Capabilities::default(), salt: vec![4u8; SALT_LEN] };
+    hello_ack.mark_downgrade();
+    assert!(!hello_ack.is_downgraded());
+}
@@ -1,2 +1,14 @@
 pub mod tunnel;
-pub mod tunnel_package;
\ No newline at end of file
+pub mod tunnel_package;
+pub mod cipher;
+pub mod codec;
+pub mod compress;
+pub mod hello;
+pub mod rtt;
+pub mod tls;
+pub mod transport;
+pub mod websocket;
+pub mod window;
+pub mod event;
+pub mod resolve;
+pub mod quic;
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use openssl::x509::X509;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendDatagramError, VarInt};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::net::lookup_host;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+use crate::tunnel::codec::{FRAME_HEADER_LEN, MAX_FRAME_SIZE};
+use crate::tunnel::tls::{spki_sha256, TlsConfig};
+use crate::tunnel::transport::{FrameRoute, Transport, TransportReader, TransportWriter};
+
+/// 未配置ALPN时使用的协议名 QUIC要求双方协商出相同的ALPN
+pub const QUIC_ALPN: &[u8] = b"flyshadow";
+/// 控制帧使用的流 连接编号0不属于任何连接
+const CONTROL_STREAM: u32 = 0;
+/// 已收到但还未被隧道读取的帧数
+const RECEIVE_QUEUE: usize = 256;
+/// 每个流还未写入的帧数 队列满时写入等待
+const SEND_QUEUE: usize = 256;
+
+/// 使用QUIC连接
+/// 每个连接的数据帧走各自的单向流，互不阻塞；UDP数据走QUIC数据报，放不下时走控制流
+pub struct QuicTransport {
+    /// 证书校验配置 同TLS
+    pub config: TlsConfig,
+}
+
+#[async_trait]
+impl Transport for QuicTransport {
+    async fn connect(&self, host: &str, port: u16) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), Error> {
+        let addr = lookup_host((host, port)).await?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("无法解析服务器地址: {}", host)))?;
+        let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+        let endpoint = Endpoint::client(bind)?;
+
+        let server_name = self.config.sni.as_deref().unwrap_or(host);
+        let connection = endpoint.connect_with(client_config(&self.config)?, addr, server_name)
+            .map_err(Error::other)?
+            .await
+            .map_err(|e| Error::other(format!("QUIC握手失败: {}", e)))?;
+        if let Some(protocol) = connection.handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.protocol) {
+            log::error!("tunnel quic alpn: {}", String::from_utf8_lossy(&protocol));
+        }
+
+        let (reader, mut writer) = split_connection(connection);
+        writer.endpoint = Some(endpoint);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// 拆分QUIC连接 服务端接受连接后也使用
+pub fn split_connection(connection: Connection) -> (QuicReader, QuicWriter) {
+    let (sender, receiver) = channel(RECEIVE_QUEUE);
+    let jobs = vec![
+        tokio::spawn(accept_streams(connection.clone(), sender.clone())),
+        tokio::spawn(read_datagrams(connection.clone(), sender)),
+    ];
+    let reader = QuicReader { receiver, jobs };
+    let writer = QuicWriter { connection, streams: HashMap::new(), endpoint: None };
+    (reader, writer)
+}
+
+/// 服务端配置 证书和私钥为PEM格式
+pub fn server_config(cert_file: &str, key_file: &str) -> Result<quinn::ServerConfig, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_file)?)).collect::<Result<Vec<_>, _>>()?;
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_file)?))?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("私钥文件中没有私钥: {}", key_file)))?;
+
+    let mut config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+
+    let crypto = QuicServerConfig::try_from(config).map_err(Error::other)?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(transport_config());
+    Ok(server_config)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut config = quinn::TransportConfig::default();
+    // 每个连接占用一个单向流
+    config.max_concurrent_uni_streams(VarInt::from_u32(4096));
+    config.keep_alive_interval(Some(Duration::from_secs(10)));
+    Arc::new(config)
+}
+
+fn client_config(config: &TlsConfig) -> Result<quinn::ClientConfig, Error> {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(Error::other)?;
+    let mut crypto = if config.spki_pins.is_empty() {
+        builder.with_root_certificates(root_store(config.ca_file.as_deref())?).with_no_client_auth()
+    } else {
+        // 只校验叶子证书的公钥，自签名证书也可以使用
+        builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(SpkiPinVerifier { pins: config.spki_pins.clone(), provider: provider() }))
+            .with_no_client_auth()
+    };
+    crypto.alpn_protocols = if config.alpn.is_empty() {
+        vec![QUIC_ALPN.to_vec()]
+    } else {
+        config.alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect()
+    };
+
+    let crypto = QuicClientConfig::try_from(crypto).map_err(Error::other)?;
+    let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+    client_config.transport_config(transport_config());
+    Ok(client_config)
+}
+
+/// CA证书 配置了CA文件时只信任文件中的证书，否则使用系统证书
+fn root_store(ca_file: Option<&str>) -> Result<RootCertStore, Error> {
+    let certs = match ca_file {
+        Some(ca_file) => {
+            rustls_pemfile::certs(&mut BufReader::new(File::open(ca_file)?)).collect::<Result<Vec<_>, _>>()?
+        }
+        None => {
+            let result = rustls_native_certs::load_native_certs();
+            for e in result.errors.iter() {
+                log::error!("load native certs error: {}", e);
+            }
+            result.certs
+        }
+    };
+    let mut roots = RootCertStore::empty();
+    let (_, ignored) = roots.add_parsable_certificates(certs);
+    if ignored > 0 {
+        log::error!("ignored {} invalid ca certs", ignored);
+    }
+    Ok(roots)
+}
+
+/// 按公钥指纹校验服务端证书 不校验证书链和域名
+#[derive(Debug)]
+struct SpkiPinVerifier {
+    pins: Vec<Vec<u8>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = X509::from_der(end_entity).map_err(|e| rustls::Error::General(e.to_string()))?;
+        let pin = spki_sha256(&cert).map_err(rustls::Error::General)?;
+        if self.pins.contains(&pin) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("服务端证书公钥指纹不匹配".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// 接受对端打开的单向流 每个流一个读任务
+async fn accept_streams(connection: Connection, sender: Sender<Vec<u8>>) {
+    while let Ok(stream) = connection.accept_uni().await {
+        tokio::spawn(read_stream(stream, sender.clone()));
+    }
+}
+
+/// 从单向流中按帧读取 流内的帧保持顺序
+async fn read_stream(mut stream: RecvStream, sender: Sender<Vec<u8>>) {
+    loop {
+        let mut frame = vec![0u8; FRAME_HEADER_LEN - 1];
+        if stream.read_exact(&mut frame).await.is_err() {
+            return;
+        }
+        let data_length = u32::from_be_bytes([frame[2], frame[3], frame[4], frame[5]]) as usize;
+        // 长度非法时只转发帧头 由解码器报告协议错误
+        if (1..=MAX_FRAME_SIZE).contains(&data_length) {
+            frame.resize(FRAME_HEADER_LEN - 1 + data_length, 0);
+            if stream.read_exact(&mut frame[FRAME_HEADER_LEN - 1..]).await.is_err() {
+                return;
+            }
+        }
+        if sender.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// 读取QUIC数据报 每个数据报是一个完整的帧
+async fn read_datagrams(connection: Connection, sender: Sender<Vec<u8>>) {
+    while let Ok(datagram) = connection.read_datagram().await {
+        if sender.send(datagram.to_vec()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// QUIC连接的读端 所有流和数据报的帧汇总到一个队列
+pub struct QuicReader {
+    receiver: Receiver<Vec<u8>>,
+    jobs: Vec<JoinHandle<()>>,
+}
+
+#[async_trait]
+impl TransportReader for QuicReader {
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> Result<usize, Error> {
+        match self.receiver.recv().await {
+            Some(frame) => {
+                buf.extend_from_slice(&frame);
+                Ok(frame.len())
+            }
+            None => { Ok(0) }
+        }
+    }
+}
+
+impl Drop for QuicReader {
+    fn drop(&mut self) {
+        for job in self.jobs.iter() {
+            job.abort();
+        }
+    }
+}
+
+/// 单向流的发送队列 每个流由自己的写任务按顺序写入
+/// 一个流被对端的流量控制阻塞时只积压在自己的队列里，积压量受这个连接的信用窗口和队列长度限制
+struct StreamQueue {
+    sender: Sender<Bytes>,
+    job: JoinHandle<()>,
+}
+
+impl StreamQueue {
+    fn open(connection: Connection) -> StreamQueue {
+        let (sender, receiver) = channel(SEND_QUEUE);
+        StreamQueue { sender, job: tokio::spawn(write_stream(connection, receiver)) }
+    }
+}
+
+/// 打开单向流并按顺序写入队列中的帧 队列关闭后结束流并等待对端收到
+async fn write_stream(connection: Connection, mut receiver: Receiver<Bytes>) {
+    let mut stream = match connection.open_uni().await {
+        Ok(stream) => { stream }
+        Err(e) => {
+            log::error!("quic open stream error: {}", e);
+            return;
+        }
+    };
+    while let Some(frame) = receiver.recv().await {
+        if let Err(e) = stream.write_all(&frame).await {
+            log::error!("quic stream write error: {}", e);
+            return;
+        }
+    }
+    let _ = stream.finish();
+    let _ = stream.stopped().await;
+}
+
+/// QUIC连接的写端
+pub struct QuicWriter {
+    connection: Connection,
+    /// 连接编号对应的单向流 控制帧使用编号0
+    streams: HashMap<u32, StreamQueue>,
+    /// 客户端的本地端点 需要和连接一起保留
+    endpoint: Option<Endpoint>,
+}
+
+impl QuicWriter {
+    fn queue(&mut self, stream_id: u32) -> Sender<Bytes> {
+        self.streams.entry(stream_id).or_insert_with(|| StreamQueue::open(self.connection.clone())).sender.clone()
+    }
+
+    /// 帧放入流的发送队列后返回 不等待写入，队列满时等待写任务
+    async fn write_stream(&mut self, stream_id: u32, frame: &[u8]) -> Result<(), Error> {
+        self.queue(stream_id).send(Bytes::copy_from_slice(frame)).await.map_err(|_| Error::new(ErrorKind::BrokenPipe, "QUIC流已关闭"))
+    }
+
+    /// 数据报放不下时走控制流 控制流队列已满时丢弃，不为UDP数据等待
+    fn write_datagram_fallback(&mut self, frame: &[u8]) -> Result<(), Error> {
+        match self.queue(CONTROL_STREAM).try_send(Bytes::copy_from_slice(frame)) {
+            Ok(_) => { Ok(()) }
+            Err(TrySendError::Full(_)) => {
+                log::error!("quic control stream full, drop udp frame");
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => { Err(Error::new(ErrorKind::BrokenPipe, "QUIC流已关闭")) }
+        }
+    }
+}
+
+#[async_trait]
+impl TransportWriter for QuicWriter {
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.write_stream(CONTROL_STREAM, frame).await
+    }
+
+    async fn write_routed(&mut self, route: FrameRoute, frame: &[u8]) -> Result<(), Error> {
+        match route {
+            FrameRoute::Control => { self.write_stream(CONTROL_STREAM, frame).await }
+            FrameRoute::Stream(stream_id) => { self.write_stream(stream_id, frame).await }
+            FrameRoute::Close(stream_id) => {
+                self.write_stream(stream_id, frame).await?;
+                // 关闭队列 写任务发送完剩余的帧后结束流
+                self.streams.remove(&stream_id);
+                Ok(())
+            }
+            FrameRoute::Datagram(_) => {
+                if self.connection.max_datagram_size().is_some_and(|size| frame.len() <= size) {
+                    match self.connection.send_datagram(Bytes::copy_from_slice(frame)) {
+                        Ok(_) => { return Ok(()); }
+                        Err(SendDatagramError::ConnectionLost(e)) => { return Err(Error::new(ErrorKind::ConnectionAborted, e)); }
+                        Err(_) => {}
+                    }
+                }
+                self.write_datagram_fallback(frame)
+            }
+        }
+    }
+
+    fn multiplexed(&self) -> bool {
+        true
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        // 关闭所有发送队列 等对端确认收到所有流上的数据后再关闭连接
+        let jobs: Vec<_> = self.streams.drain().map(|(_, queue)| queue.job).collect();
+        for job in jobs {
+            let _ = job.await;
+        }
+        self.connection.close(VarInt::from_u32(0), b"");
+        if let Some(endpoint) = self.endpoint.as_ref() {
+            endpoint.wait_idle().await;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for QuicWriter {
+    fn drop(&mut self) {
+        self.connection.close(VarInt::from_u32(0), b"");
+    }
+}
@@ -0,0 +1,116 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+
+const RECORD_V4: u8 = 4;
+const RECORD_V6: u8 = 6;
+const RESULT_SUCCESS: u8 = 0x00;
+const RESULT_FAIL: u8 = 0x01;
+
+/// 一条A或AAAA记录
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolveRecord {
+    pub addr: IpAddr,
+    /// 记录的有效秒数
+    pub ttl: u32,
+}
+
+/// 服务端的解析结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveResult {
+    Success(Vec<ResolveRecord>),
+    /// 解析失败的原因
+    Fail(String),
+}
+
+impl ResolveResult {
+    /// 成功时为0x00+记录列表 每条记录为类型+大端的TTL+地址；失败时为0x01+原因
+    pub fn to_byte_array(&self) -> Vec<u8> {
+        match self {
+            ResolveResult::Success(records) => {
+                let mut vec = vec![RESULT_SUCCESS];
+                for record in records {
+                    match record.addr {
+                        IpAddr::V4(addr) => {
+                            vec.push(RECORD_V4);
+                            vec.extend_from_slice(&record.ttl.to_be_bytes());
+                            vec.extend_from_slice(&addr.octets());
+                        }
+                        IpAddr::V6(addr) => {
+                            vec.push(RECORD_V6);
+                            vec.extend_from_slice(&record.ttl.to_be_bytes());
+                            vec.extend_from_slice(&addr.octets());
+                        }
+                    }
+                }
+                vec
+            }
+            ResolveResult::Fail(reason) => {
+                let mut vec = vec![RESULT_FAIL];
+                vec.extend_from_slice(reason.as_bytes());
+                vec
+            }
+        }
+    }
+
+    pub fn from_byte_array(data: &[u8]) -> Result<ResolveResult, String> {
+        let (status, mut data) = match data.split_first() {
+            Some((status, data)) => { (*status, data) }
+            None => { return Err("解析结果为空".to_string()); }
+        };
+        match status {
+            RESULT_SUCCESS => {
+                let mut records = vec![];
+                while let Some((kind, rest)) = data.split_first() {
+                    let len = match *kind {
+                        RECORD_V4 => { 4 }
+                        RECORD_V6 => { 16 }
+                        _ => { return Err(format!("未知的记录类型: {}", kind)); }
+                    };
+                    if rest.len() < 4 + len {
+                        return Err("解析记录长度错误".to_string());
+                    }
+                    let ttl = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+                    let addr = &rest[4..4 + len];
+                    let addr = if len == 4 {
+                        IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(addr).unwrap()))
+                    } else {
+                        IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap()))
+                    };
+                    records.push(ResolveRecord { addr, ttl });
+                    data = &rest[4 + len..];
+                }
+                Ok(ResolveResult::Success(records))
+            }
+            RESULT_FAIL => { Ok(ResolveResult::Fail(String::from_utf8_lossy(data).to_string())) }
+            _ => { Err(format!("未知的解析状态: {}", status)) }
+        }
+    }
+}
+
+/// 解析请求数据包 连接编号为请求编号，目标地址为要解析的域名
+pub fn resolve_package(request_id: u32, host: &str) -> TunnelPackage {
+    TunnelPackage::new(PackageCmd::Resolve, PackageProtocol::TCP, request_id, None, Some(host.to_string()), None)
+}
+
+/// 解析结果数据包 带回请求的编号和域名
+pub fn resolve_result_package(request_id: u32, host: &str, result: &ResolveResult) -> TunnelPackage {
+    TunnelPackage::new(PackageCmd::ResolveResult, PackageProtocol::TCP, request_id, None, Some(host.to_string()), Some(result.to_byte_array()))
+}
+
+#[test]
+fn test_resolve_result() {
+    let result = ResolveResult::Success(vec![
+        ResolveRecord { addr: "1.2.3.4".parse().unwrap(), ttl: 300 },
+        ResolveRecord { addr: "2001:db8::1".parse().unwrap(), ttl: 60 },
+    ]);
+    assert_eq!(ResolveResult::from_byte_array(&result.to_byte_array()).unwrap(), result);
+    let result = ResolveResult::Fail("no such host".to_string());
+    assert_eq!(ResolveResult::from_byte_array(&result.to_byte_array()).unwrap(), result);
+
+    // 截断的记录和未知类型
+    let data = ResolveResult::Success(vec![ResolveRecord { addr: "1.2.3.4".parse().unwrap(), ttl: 1 }]).to_byte_array();
+    assert!(ResolveResult::from_byte_array(&data[..data.len() - 1]).is_err());
+    assert!(ResolveResult::from_byte_array(&[RESULT_SUCCESS, 5, 0, 0, 0, 0]).is_err());
+    assert!(ResolveResult::from_byte_array(&[]).is_err());
+}
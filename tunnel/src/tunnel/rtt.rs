@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+
+/// 保留的延迟样本数量
+const RTT_SAMPLE_SIZE: usize = 20;
+
+/// 延迟统计 单位毫秒
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RttSummary {
+    /// 样本数量
+    pub samples: u32,
+    pub last: u32,
+    pub min: u32,
+    pub avg: u32,
+    pub p95: u32,
+    pub jitter: u32,
+}
+
+/// 最近若干次PING/PONG的延迟样本
+#[derive(Default)]
+pub struct RttStats {
+    samples: VecDeque<u32>,
+}
+
+impl RttStats {
+    /// 添加一个延迟样本
+    pub fn push(&mut self, rtt: u32) {
+        if self.samples.len() >= RTT_SAMPLE_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// 最近一次延迟
+    pub fn last(&self) -> u32 {
+        self.samples.back().copied().unwrap_or(0)
+    }
+
+    /// 计算统计值 jitter为相邻样本差值的平均值
+    pub fn summary(&self) -> RttSummary {
+        if self.samples.is_empty() {
+            return RttSummary::default();
+        }
+        let mut sorted: Vec<u32> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let sum: u64 = sorted.iter().map(|rtt| *rtt as u64).sum();
+        let p95_index = ((sorted.len() * 95).div_ceil(100)).max(1) - 1;
+
+        let mut jitter_sum: u64 = 0;
+        for (previous, current) in self.samples.iter().zip(self.samples.iter().skip(1)) {
+            jitter_sum += previous.abs_diff(*current) as u64;
+        }
+        let jitter = if self.samples.len() > 1 { jitter_sum / (self.samples.len() as u64 - 1) } else { 0 };
+
+        RttSummary {
+            samples: self.samples.len() as u32,
+            last: self.last(),
+            min: sorted[0],
+            avg: (sum / sorted.len() as u64) as u32,
+            p95: sorted[p95_index],
+            jitter: jitter as u32,
+        }
+    }
+}
+
+#[test]
+fn test_rtt_summary() {
+    let mut stats = RttStats::default();
+    for rtt in [10, 20, 10, 20, 140] {
+        stats.push(rtt);
+    }
+    let summary = stats.summary();
+    assert_eq!(summary, RttSummary { samples: 5, last: 140, min: 10, avg: 40, p95: 140, jitter: 37 });
+}
@@ -0,0 +1,112 @@
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+
+use openssl::base64::decode_block;
+use openssl::hash::{hash, MessageDigest};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::X509Ref;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslStream;
+
+/// 隧道TLS配置
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    /// SNI和证书校验使用的域名 为空时使用服务器地址
+    pub sni: Option<String>,
+    /// CA证书文件 为空时使用系统证书
+    pub ca_file: Option<String>,
+    /// 服务端证书公钥(SPKI)的SHA256 配置后只校验公钥，不再校验证书链和域名
+    pub spki_pins: Vec<Vec<u8>>,
+    /// ALPN协议列表 例如h2、http/1.1
+    pub alpn: Vec<String>,
+}
+
+impl TlsConfig {
+    /// 解析公钥指纹 base64格式，可以带sha256/前缀
+    pub fn parse_pin(pin: &str) -> Result<Vec<u8>, String> {
+        let pin = pin.trim();
+        let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+        match decode_block(pin) {
+            Ok(pin) if pin.len() == 32 => { Ok(pin) }
+            Ok(_) => { Err(format!("公钥指纹长度错误: {}", pin)) }
+            Err(e) => { Err(format!("公钥指纹格式错误: {} {}", pin, e)) }
+        }
+    }
+}
+
+/// 证书公钥(SPKI)的SHA256
+pub fn spki_sha256(cert: &X509Ref) -> Result<Vec<u8>, String> {
+    let public_key = cert.public_key().map_err(|e| e.to_string())?;
+    let der = public_key.public_key_to_der().map_err(|e| e.to_string())?;
+    let digest = hash(MessageDigest::sha256(), &der).map_err(|e| e.to_string())?;
+    Ok(digest.to_vec())
+}
+
+/// ALPN协议列表编码成长度前缀格式 每个协议名最长255字节
+fn alpn_wire_format(alpn: &[String]) -> Result<Vec<u8>, Error> {
+    let mut vec = Vec::new();
+    for protocol in alpn.iter() {
+        let len = match u8::try_from(protocol.len()) {
+            Ok(len) if len > 0 => { len }
+            _ => { return Err(Error::new(ErrorKind::InvalidInput, format!("ALPN协议名长度错误: {}", protocol))); }
+        };
+        vec.push(len);
+        vec.extend_from_slice(protocol.as_bytes());
+    }
+    Ok(vec)
+}
+
+/// 在已建立的连接上完成TLS握手
+pub async fn connect_tls<S>(stream: S, host: &str, config: &TlsConfig) -> Result<SslStream<S>, Error>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let mut builder = SslConnector::builder(SslMethod::tls_client()).map_err(Error::other)?;
+    if let Some(ca_file) = config.ca_file.as_ref() {
+        builder.set_ca_file(ca_file).map_err(Error::other)?;
+    }
+    if !config.alpn.is_empty() {
+        builder.set_alpn_protos(&alpn_wire_format(&config.alpn)?).map_err(Error::other)?;
+    }
+    if !config.spki_pins.is_empty() {
+        // 只校验叶子证书的公钥，自签名证书也可以使用
+        let pins = config.spki_pins.clone();
+        builder.set_verify_callback(SslVerifyMode::PEER, move |_, x509_ctx| {
+            if x509_ctx.error_depth() != 0 {
+                return true;
+            }
+            match x509_ctx.current_cert() {
+                Some(cert) => {
+                    match spki_sha256(cert) {
+                        Ok(pin) => { pins.contains(&pin) }
+                        Err(_) => { false }
+                    }
+                }
+                None => { false }
+            }
+        });
+    }
+    let connector = builder.build();
+
+    let mut configuration = connector.configure().map_err(Error::other)?;
+    configuration.set_verify_hostname(config.spki_pins.is_empty());
+    let domain = config.sni.as_deref().unwrap_or(host);
+    let ssl = configuration.into_ssl(domain).map_err(Error::other)?;
+
+    let mut ssl_stream = SslStream::new(ssl, stream).map_err(Error::other)?;
+    Pin::new(&mut ssl_stream).connect().await.map_err(|e| {
+        let verify_result = ssl_stream.ssl().verify_result();
+        Error::other(format!("TLS握手失败: {} {}", e, verify_result))
+    })?;
+    if let Some(protocol) = ssl_stream.ssl().selected_alpn_protocol() {
+        log::error!("tunnel tls alpn: {}", String::from_utf8_lossy(protocol));
+    }
+    Ok(ssl_stream)
+}
+
+#[test]
+fn test_alpn_wire_format() {
+    let alpn = vec!["h2".to_string(), "http/1.1".to_string()];
+    assert_eq!(alpn_wire_format(&alpn).unwrap(), b"\x02h2\x08http/1.1".to_vec());
+    // 超过255字节或为空的协议名不能编码
+    assert!(alpn_wire_format(&["a".repeat(256)]).is_err());
+    assert!(alpn_wire_format(&[String::new()]).is_err());
+}
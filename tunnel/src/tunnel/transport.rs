@@ -0,0 +1,129 @@
+use std::io::Error;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, split};
+use tokio::net::TcpStream;
+
+use crate::tunnel::tls::{connect_tls, TlsConfig};
+use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+
+/// 数据帧所属的路由 多路复用的传输方式按路由把帧分到不同的流上
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameRoute {
+    /// 登录、心跳、握手、解析和窗口更新等控制帧
+    Control,
+    /// 连接的数据帧 同一连接内保持顺序
+    Stream(u32),
+    /// 连接的最后一帧 之后可以关闭该连接的流
+    Close(u32),
+    /// UDP数据 允许丢失和乱序
+    Datagram(u32),
+}
+
+impl FrameRoute {
+    pub fn of(package: &TunnelPackage) -> FrameRoute {
+        match package.cmd {
+            PackageCmd::CloseConnect => { FrameRoute::Close(package.stream_id) }
+            PackageCmd::TData if matches!(package.protocol, PackageProtocol::UDP) => { FrameRoute::Datagram(package.stream_id) }
+            PackageCmd::NewConnect | PackageCmd::TData => { FrameRoute::Stream(package.stream_id) }
+            _ => { FrameRoute::Control }
+        }
+    }
+}
+
+/// 隧道连接的读端
+#[async_trait]
+pub trait TransportReader: Send + Sync {
+    /// 读取数据追加到buf的预留空间 返回0表示连接已关闭
+    /// 调用前需要为buf预留空间
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> Result<usize, Error>;
+}
+
+/// 隧道连接的写端
+#[async_trait]
+pub trait TransportWriter: Send + Sync {
+    /// 写入一个完整的隧道数据帧
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error>;
+
+    /// 按路由写入一个完整的数据帧 单连接的传输方式忽略路由
+    async fn write_routed(&mut self, _route: FrameRoute, frame: &[u8]) -> Result<(), Error> {
+        self.write_frame(frame).await
+    }
+
+    /// 是否多路复用 为true时不同连接的帧可能乱序到达，UDP数据不做流量控制
+    fn multiplexed(&self) -> bool {
+        false
+    }
+
+    /// 刷新并关闭写端 对端读到连接结束
+    async fn shutdown(&mut self) -> Result<(), Error>;
+}
+
+/// 隧道传输方式 Tunnel只通过读写端收发数据帧，不关心底层是TCP、TLS还是WebSocket
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// 连接服务端
+    async fn connect(&self, host: &str, port: u16) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), Error>;
+}
+
+/// 字节流的读端
+pub struct StreamReader<R> {
+    reader: R,
+}
+
+/// 字节流的写端
+pub struct StreamWriter<W> {
+    writer: W,
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send + Sync> TransportReader for StreamReader<R> {
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> Result<usize, Error> {
+        self.reader.read_buf(buf).await
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send + Sync> TransportWriter for StreamWriter<W> {
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(frame).await?;
+        self.writer.flush().await
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        self.writer.shutdown().await
+    }
+}
+
+/// 拆分字节流 数据帧直接写入流中
+pub fn split_stream<S>(stream: S) -> (Box<dyn TransportReader>, Box<dyn TransportWriter>)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {
+    let (reader, writer) = split(stream);
+    (Box::new(StreamReader { reader }), Box::new(StreamWriter { writer }))
+}
+
+/// 直接使用TCP连接
+pub struct TcpTransport;
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn connect(&self, host: &str, port: u16) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), Error> {
+        let tcp_stream = TcpStream::connect((host, port)).await?;
+        Ok(split_stream(tcp_stream))
+    }
+}
+
+/// 在TCP上套一层TLS
+pub struct TlsTransport {
+    pub config: TlsConfig,
+}
+
+#[async_trait]
+impl Transport for TlsTransport {
+    async fn connect(&self, host: &str, port: u16) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), Error> {
+        let tcp_stream = TcpStream::connect((host, port)).await?;
+        let ssl_stream = connect_tls(tcp_stream, host, &self.config).await?;
+        Ok(split_stream(ssl_stream))
+    }
+}
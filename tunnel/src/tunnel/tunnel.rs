@@ -1,17 +1,37 @@
-use std::io::Error;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use openssl::symm::{Cipher, decrypt, encrypt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use bytes::BytesMut;
 use tokio::spawn;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
-use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+use crate::error::TunnelError;
+use crate::tunnel::cipher::{CipherSuite, FrameCipher, FrameDirection, random_salt, SALT_LEN, SessionKeys, transcript_hash};
+use crate::tunnel::codec::{FrameDecoder, FrameEncoder};
+use crate::tunnel::compress::{Compression, CompressionStats, CompressionSummary};
+use crate::tunnel::event::{EventPublisher, TunnelEventKind};
+use crate::tunnel::hello::{Capabilities, FRAME_COUNTER_VERSION, Hello, HelloAck, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::tunnel::rtt::{RttStats, RttSummary};
+use crate::tunnel::transport::{FrameRoute, Transport, TransportReader, TransportWriter};
+use crate::tunnel::tunnel_package::{DecodeError, PackageCmd, PackageProtocol, TunnelPackage};
+
+/// 明文数据帧的加密套件标识，只用于握手
+pub const PLAIN_SUITE: u8 = 0x00;
+/// 每次读取预留的缓冲区大小
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// 关闭隧道时通知服务端并刷新写端的最长时间
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+/// 握手的最长时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 等待登录结果的最长时间
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// 不支持版本协商的旧服务端使用的协议版本
+const LEGACY_VERSION: u8 = 1;
 
 #[derive(Copy, Clone)]
 pub enum TunnelStatus {
@@ -20,17 +40,52 @@ pub enum TunnelStatus {
     Logout,
 }
 
+impl TunnelStatus {
+    /// 对外暴露的状态编号
+    pub fn as_index(&self) -> i32 {
+        match self {
+            TunnelStatus::Success => {0}
+            TunnelStatus::WaitLogin => {1}
+            TunnelStatus::Logout => {2}
+        }
+    }
+}
+
+/// 心跳状态
+#[derive(Default)]
+struct HeartbeatState {
+    /// 还未收到PONG的PING发送时间
+    ping_time: Option<u128>,
+    /// 连续丢失的PONG数量
+    missed: u32,
+    rtt: RttStats,
+}
+
 /// 隧道结构体
 pub struct Tunnel {
-    password_md5: String,
-    upload: Arc<RwLock<i64>>,
-    download: Arc<RwLock<i64>>,
+    encoder: FrameEncoder,
+    /// 复用的写缓冲区
+    write_buffer: BytesMut,
+    version: u8,
+    capabilities: Capabilities,
+    /// 双方都支持多路复用时不同连接的帧走不同的流
+    multiplexed: bool,
+    /// 隧道上实际收发的字节数 包括帧头和认证标签，只增不减
+    upload: Arc<AtomicU64>,
+    download: Arc<AtomicU64>,
+    /// 上次调用get_upload/get_download时的累计值
+    upload_read: AtomicU64,
+    download_read: AtomicU64,
+    /// 接收方向的压缩统计 发送方向的统计在编码器里
+    download_compression: Arc<CompressionStats>,
+    /// 服务端返回或本端检测到的协议错误原因
+    protocol_error: Arc<RwLock<Option<String>>>,
     status: Arc<RwLock<TunnelStatus>>,
-    ping_time: Arc<RwLock<u128>>,
-    ping_delay: Arc<RwLock<u128>>,
+    heartbeat: Arc<RwLock<HeartbeatState>>,
     sender: Sender<TunnelPackage>,
-    tcp_reader: Option<OwnedReadHalf>,
-    tcp_writer: OwnedWriteHalf,
+    events: EventPublisher,
+    tcp_reader: Option<Box<dyn TransportReader>>,
+    tcp_writer: Box<dyn TransportWriter>,
     reader_job: Option<JoinHandle<()>>,
     pub host: String,
     pub port: u16,
@@ -38,33 +93,107 @@ pub struct Tunnel {
 
 impl Tunnel {
     /// 连接隧道
-    async fn connect(host: String, port: u16) -> Result<(OwnedReadHalf, OwnedWriteHalf), Error> {
-        match TcpStream::connect((host, port)).await {
-            Ok(tcp_stream) => {
-                log::error!("tunnel connect success");
-                Ok(tcp_stream.into_split())
-            }
-            Err(e) => { Err(e) }
-        }
+    async fn connect(host: String, port: u16, transport: &dyn Transport) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), TunnelError> {
+        let (reader, writer) = transport.connect(&host, port).await?;
+        log::error!("tunnel connect success");
+        Ok((reader, writer))
     }
 
-    async fn send_ping(&mut self) {
-        *self.ping_time.write().await = SystemTime::now()
+    async fn send_ping(&mut self) -> Result<(), TunnelError> {
+        self.heartbeat.write().await.ping_time = Some(SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_millis();
-        let package = TunnelPackage::new(PackageCmd::PING, PackageProtocol::TCP, None, None, None);
-        let _ = self.write_to_tunnel(package).await;
+            .as_millis());
+        let package = TunnelPackage::new(PackageCmd::PING, PackageProtocol::TCP, 0, None, None, None);
+        self.write_to_tunnel(package).await
+    }
+
+    /// 握手 协商协议版本和能力，交换双方的盐并派生会话密钥
+    /// 返回会话密钥、握手结果和握手帧之后已读取的数据 服务端不支持版本协商时返回None
+    async fn handshake(tcp_reader: &mut dyn TransportReader, tcp_writer: &mut dyn TransportWriter, password: &str) -> Result<Option<(SessionKeys, HelloAck, BytesMut)>, TunnelError> {
+        let client_salt = random_salt().map_err(TunnelError::Crypto)?;
+        let hello = Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::supported(),
+            salt: client_salt.to_vec(),
+        };
+        let hello = hello.to_byte_array();
+        let mut buffer_tmp = BytesMut::with_capacity(READ_BUFFER_SIZE);
+        let data = match Tunnel::exchange_handshake(tcp_reader, tcp_writer, hello.clone(), &mut buffer_tmp).await? {
+            // 旧服务端把握手数据当作盐，长度不对时直接断开，或者只回复服务端盐
+            Some(data) if data.len() != SALT_LEN => { data }
+            _ => { return Ok(None); }
+        };
+        let hello_ack = HelloAck::from_byte_array(data.as_slice()).map_err(TunnelError::Protocol)?;
+        if hello_ack.version < MIN_PROTOCOL_VERSION || hello_ack.version > PROTOCOL_VERSION {
+            return Err(TunnelError::Protocol(format!("不支持的协议版本: {}", hello_ack.version)));
+        }
+        if hello_ack.is_downgraded() {
+            return Err(TunnelError::Protocol(format!("握手被降级到版本: {}", hello_ack.version)));
+        }
+
+        // 新版本的会话密钥绑定双方实际收发的握手数据
+        let session_keys = if hello_ack.version >= FRAME_COUNTER_VERSION {
+            let transcript = transcript_hash(&hello, &data).map_err(TunnelError::Crypto)?;
+            SessionKeys::derive(password.as_bytes(), &client_salt, &hello_ack.salt, &transcript)
+        } else {
+            SessionKeys::derive_legacy(password.as_bytes(), &client_salt, &hello_ack.salt)
+        }.map_err(TunnelError::Crypto)?;
+        Ok(Some((session_keys, hello_ack, buffer_tmp)))
+    }
+
+    /// 旧服务端的握手 只交换双方的盐，按最低版本和旧服务端的能力通信
+    async fn legacy_handshake(tcp_reader: &mut dyn TransportReader, tcp_writer: &mut dyn TransportWriter, password: &str) -> Result<(SessionKeys, HelloAck, BytesMut), TunnelError> {
+        let client_salt = random_salt().map_err(TunnelError::Crypto)?;
+        let mut buffer_tmp = BytesMut::with_capacity(READ_BUFFER_SIZE);
+        let server_salt = match Tunnel::exchange_handshake(tcp_reader, tcp_writer, client_salt.to_vec(), &mut buffer_tmp).await? {
+            Some(salt) if salt.len() == SALT_LEN => { salt }
+            Some(_) => { return Err(TunnelError::Protocol("握手响应错误".to_string())); }
+            None => { return Err(TunnelError::TunnelUnavailable("握手时隧道断开".to_string())); }
+        };
+        let session_keys = SessionKeys::derive_legacy(password.as_bytes(), &client_salt, &server_salt)
+            .map_err(TunnelError::Crypto)?;
+        let hello_ack = HelloAck { version: LEGACY_VERSION, capabilities: Capabilities::legacy(), salt: server_salt };
+        Ok((session_keys, hello_ack, buffer_tmp))
+    }
+
+    /// 发送握手数据并读取服务端的握手响应数据 响应之前连接断开时返回None
+    async fn exchange_handshake(tcp_reader: &mut dyn TransportReader, tcp_writer: &mut dyn TransportWriter, data: Vec<u8>, buffer_tmp: &mut BytesMut) -> Result<Option<Vec<u8>>, TunnelError> {
+        let package = TunnelPackage::new(PackageCmd::Handshake, PackageProtocol::TCP, 0, None, None, Some(data));
+        let mut frame = BytesMut::new();
+        FrameEncoder::plain().encode(&package, &mut frame)?;
+        tcp_writer.write_frame(&frame).await?;
+
+        let mut decoder = FrameDecoder::plain();
+        loop {
+            if let Some(package) = decoder.decode(buffer_tmp)? {
+                return match (package.cmd, package.data) {
+                    (PackageCmd::HandshakeAck, Some(data)) => { Ok(Some(data)) }
+                    (PackageCmd::ProtocolError, data) => {
+                        Err(TunnelError::Protocol(format!("服务端拒绝握手: {}", protocol_error_reason(data))))
+                    }
+                    _ => {
+                        Err(TunnelError::Protocol("握手响应错误".to_string()))
+                    }
+                };
+            }
+            buffer_tmp.reserve(READ_BUFFER_SIZE);
+            if tcp_reader.read_buf(buffer_tmp).await? == 0 {
+                return Ok(None);
+            }
+        }
     }
 
     /// 登录tunnel
-    async fn login_tunnel(&mut self) {
-        let package = TunnelPackage::new(PackageCmd::Login, PackageProtocol::TCP, None, None, Some(self.password_md5.clone().into()));
+    async fn login_tunnel(&mut self, login_proof: Vec<u8>) {
+        let package = TunnelPackage::new(PackageCmd::Login, PackageProtocol::TCP, 0, None, None, Some(login_proof));
         let _ = self.write_to_tunnel(package).await;
     }
 
     /// 开始Tcp读取线程
-    async fn start_reader_job(&mut self) {
+    /// 收到登录结果、登录前出错或断开时通过login_result返回
+    async fn start_reader_job(&mut self, mut decoder: FrameDecoder, mut buffer_tmp: BytesMut, login_result: oneshot::Sender<Result<(), TunnelError>>) {
         let tcp_reader = self.tcp_reader.take();
         if tcp_reader.is_none() {
             return;
@@ -72,27 +201,27 @@ impl Tunnel {
         let mut tcp_reader = tcp_reader.unwrap();
 
         let sender = self.sender.clone();
-        let password_md5 = self.password_md5.clone();
         let login_success = self.status.clone();
-        let ping_delay = self.ping_delay.clone();
-        let ping_time = self.ping_time.clone();
+        let heartbeat = self.heartbeat.clone();
         let download = self.download.clone();
+        let protocol_error = self.protocol_error.clone();
+        let events = self.events.clone();
 
         let reader_job = spawn(async move {
-            let mut buffer_tmp: Vec<u8> = Vec::new();
-            let mut data = [0; 8192];
-
+            let mut login_result = Some(login_result);
+            let mut close_reason = None;
             'read_buff: loop {
-                match tcp_reader.read(&mut data).await {
+                // 已解码的帧都已释放，预留空间时可以复用缓冲区
+                buffer_tmp.reserve(READ_BUFFER_SIZE);
+                match tcp_reader.read_buf(&mut buffer_tmp).await {
                     Ok(0) => {
+                        close_reason = Some("隧道被服务端关闭".to_string());
                         break;
                     }
                     Ok(n) => {
-                        let mut write_guard = download.write().await;
-                        *write_guard += n as i64;
-                        buffer_tmp.append(&mut data[..n].to_vec());
+                        download.fetch_add(n as u64, Ordering::Relaxed);
                         'read_package: loop {
-                            match buffer_to_tunnel_package(&mut buffer_tmp, password_md5.as_bytes()) {
+                            match decoder.decode(&mut buffer_tmp) {
                                 Ok(tunnel_opt) => {
                                     // 转成结构体
                                     if let Some(tunnel_package) = tunnel_opt {
@@ -115,29 +244,69 @@ impl Tunnel {
                                                 }
                                             }
                                             PackageCmd::PING => {}
+                                            PackageCmd::WindowUpdate => {
+                                                if sender.send(tunnel_package).await.is_err() {
+                                                    break 'read_buff;
+                                                }
+                                            }
+                                            PackageCmd::Handshake => {}
+                                            PackageCmd::HandshakeAck => {}
+                                            PackageCmd::Resolve => {}
+                                            PackageCmd::ResolveResult => {
+                                                if sender.send(tunnel_package).await.is_err() {
+                                                    break 'read_buff;
+                                                }
+                                            }
                                             PackageCmd::LoginSuccess => {
                                                 log::error!("tunnel login success");
                                                 let mut write_guard = login_success.write().await;
                                                 *write_guard = TunnelStatus::Success;
+                                                events.publish(TunnelEventKind::LoginSuccess);
+                                                if let Some(login_result) = login_result.take() {
+                                                    let _ = login_result.send(Ok(()));
+                                                }
                                             }
                                             PackageCmd::LoginFail => {
-                                                log::error!("tunnel login fail");
+                                                let reason = protocol_error_reason(tunnel_package.data);
+                                                log::error!("tunnel login fail: {}", reason);
                                                 let mut write_guard = login_success.write().await;
                                                 *write_guard = TunnelStatus::Logout;
+                                                events.publish(TunnelEventKind::LoginFail { reason: reason.clone() });
+                                                if let Some(login_result) = login_result.take() {
+                                                    let _ = login_result.send(Err(TunnelError::Auth(reason)));
+                                                }
+                                            }
+                                            // 只针对一个连接的错误 像服务端关闭连接一样结束本地连接
+                                            PackageCmd::ProtocolError if tunnel_package.stream_id != 0 => {
+                                                log::error!("stream {} protocol error: {}", tunnel_package.stream_id, protocol_error_reason(tunnel_package.data));
+                                                let close_package = TunnelPackage::new(PackageCmd::CloseConnect, PackageProtocol::TCP, tunnel_package.stream_id, None, None, None);
+                                                if sender.send(close_package).await.is_err() {
+                                                    break 'read_buff;
+                                                }
                                             }
                                             PackageCmd::ProtocolError => {
-                                                log::error!("tunnel protocol error");
+                                                let reason = protocol_error_reason(tunnel_package.data);
+                                                log::error!("tunnel protocol error: {}", reason);
+                                                *protocol_error.write().await = Some(reason.clone());
                                                 let mut write_guard = login_success.write().await;
                                                 *write_guard = TunnelStatus::Logout;
+                                                events.publish(TunnelEventKind::ProtocolError { reason: reason.clone() });
+                                                if let Some(login_result) = login_result.take() {
+                                                    let _ = login_result.send(Err(TunnelError::Protocol(reason)));
+                                                }
                                             }
                                             PackageCmd::PONG => {
-                                                let ping_time = *ping_time.read().await;
-                                                let delay = SystemTime::now()
-                                                    .duration_since(UNIX_EPOCH)
-                                                    .unwrap()
-                                                    .as_millis() - ping_time;
-                                                log::error!("tunnel delay {}ms", delay);
-                                                *ping_delay.write().await = delay;
+                                                let mut heartbeat = heartbeat.write().await;
+                                                if let Some(ping_time) = heartbeat.ping_time.take() {
+                                                    let delay = SystemTime::now()
+                                                        .duration_since(UNIX_EPOCH)
+                                                        .unwrap()
+                                                        .as_millis().saturating_sub(ping_time);
+                                                    log::error!("tunnel delay {}ms", delay);
+                                                    heartbeat.rtt.push(delay as u32);
+                                                    heartbeat.missed = 0;
+                                                    events.publish(TunnelEventKind::RttUpdate { rtt: delay as u32 });
+                                                }
                                             }
                                             PackageCmd::NONE => {}
                                         }
@@ -146,72 +315,187 @@ impl Tunnel {
                                     }
                                 }
                                 Err(e) => {
-                                    log::error!("{}", e);
-                                    break 'read_package;
+                                    // 重放、乱序或认证失败后帧计数器已无法对齐，只能断开隧道
+                                    log::error!("tunnel protocol error: {}", e);
+                                    *protocol_error.write().await = Some(e.to_string());
+                                    events.publish(TunnelEventKind::ProtocolError { reason: e.to_string() });
+                                    close_reason = Some(e.to_string());
+                                    // 登录前的第一帧就无法解密 说明双方的会话密钥不同，即密码错误
+                                    if let Some(login_result) = login_result.take() {
+                                        let e = match e {
+                                            DecodeError::Decrypt(_) => { TunnelError::Auth(e.to_string()) }
+                                            _ => { e.into() }
+                                        };
+                                        let _ = login_result.send(Err(e));
+                                    }
+                                    break 'read_buff;
                                 }
                             }
                         }
                     }
                     Err(e) => {
                         log::error!("tunnel read err {}", e);
+                        close_reason = Some(e.to_string());
                         break;
                     }
                 };
             };
+            if let Some(login_result) = login_result.take() {
+                let reason = close_reason.clone().unwrap_or_else(|| "登录时隧道断开".to_string());
+                let _ = login_result.send(Err(TunnelError::TunnelUnavailable(reason)));
+            }
             let mut write_guard = login_success.write().await;
             *write_guard = TunnelStatus::Logout;
+            events.publish(TunnelEventKind::Closed { reason: close_reason });
         });
         self.reader_job = Some(reader_job);
     }
 }
 
 impl Tunnel {
-    pub async fn new(host: String, port: u16, password: String, sender: Sender<TunnelPackage>) -> Result<Tunnel, Error> {
-        match Tunnel::connect(host.to_string(), port).await {
-            Ok((r, w)) => {
-                // // 加密解密密钥
-                let md5_pwd = md5::compute(password.as_bytes());
+    #[allow(clippy::too_many_arguments)]
+    /// legacy_handshake为true时 服务端不支持版本协商就重新连接按旧版本握手
+    pub async fn new(host: String, port: u16, password: String, cipher_suite: CipherSuite, compression: Compression, legacy_handshake: bool, transport: &dyn Transport, sender: Sender<TunnelPackage>, events: EventPublisher) -> Result<Tunnel, TunnelError> {
+        match Tunnel::connect(host.to_string(), port, transport).await {
+            Ok((mut r, mut w)) => {
+                // 握手 协商版本和能力并派生会话密钥
+                let handshake = match timeout(HANDSHAKE_TIMEOUT, Tunnel::handshake(r.as_mut(), w.as_mut(), &password)).await {
+                    Ok(result) => { result? }
+                    Err(_) => { return Err(TunnelError::Timeout("隧道握手超时".to_string())); }
+                };
+                let (session_keys, hello_ack, buffer_tmp) = match handshake {
+                    Some(handshake) => { handshake }
+                    None if !legacy_handshake => {
+                        // 旧版本握手不带帧计数器和登录时间戳 只在配置开启时使用，避免被中间人强制降级
+                        return Err(TunnelError::Protocol("服务端不支持版本协商，未开启旧版本握手".to_string()));
+                    }
+                    None => {
+                        // 服务端不认识版本协商 重新连接后只交换盐
+                        log::error!("server not support hello, use legacy handshake");
+                        let _ = w.shutdown().await;
+                        (r, w) = Tunnel::connect(host.to_string(), port, transport).await?;
+                        match timeout(HANDSHAKE_TIMEOUT, Tunnel::legacy_handshake(r.as_mut(), w.as_mut(), &password)).await {
+                            Ok(result) => { result? }
+                            Err(_) => { return Err(TunnelError::Timeout("隧道握手超时".to_string())); }
+                        }
+                    }
+                };
+                // 旧版本的登录不带时间戳
+                let login_proof = if hello_ack.version >= FRAME_COUNTER_VERSION {
+                    session_keys.login_request(SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs())
+                } else {
+                    session_keys.legacy_login_proof()
+                }.map_err(TunnelError::Crypto)?;
+                // 只开启双方都支持的功能
+                let capabilities = hello_ack.capabilities.intersect(Capabilities::supported());
+                let cipher_suite = if cipher_suite == CipherSuite::ChaCha20Poly1305 && !capabilities.contains(Capabilities::CHACHA20_POLY1305) {
+                    log::error!("server not support chacha20-poly1305, use aes-256-gcm");
+                    CipherSuite::Aes256Gcm
+                } else {
+                    cipher_suite
+                };
+                // 加密解密器 每个方向使用各自的密钥
+                let mut encoder = FrameEncoder::new(FrameCipher::new(cipher_suite, &session_keys.client_key, FrameDirection::ClientToServer));
+                let mut decoder = FrameDecoder::new(FrameCipher::new(cipher_suite, &session_keys.server_key, FrameDirection::ServerToClient));
+                // 数据包格式按协商的版本
+                encoder.set_version(hello_ack.version);
+                decoder.set_version(hello_ack.version);
+                // 多路复用的传输方式上不同连接的帧可能乱序到达
+                let multiplexed = w.multiplexed() && capabilities.contains(Capabilities::MULTIPLEX);
+                if multiplexed {
+                    decoder.set_unordered();
+                }
+                // 服务端支持时才压缩发送的数据，接收的数据按帧上的标记解压
+                if capabilities.contains(Capabilities::COMPRESSION) {
+                    encoder.set_compression(compression);
+                } else if compression != Compression::None {
+                    log::error!("server not support compression");
+                }
 
                 let mut tunnel = Tunnel {
                     host,
                     port,
-                    password_md5: format!("{:x}", md5_pwd),
-                    upload: Arc::new(RwLock::new(0)),
-                    download: Arc::new(RwLock::new(0)),
+                    encoder,
+                    write_buffer: BytesMut::new(),
+                    version: hello_ack.version,
+                    capabilities,
+                    multiplexed,
+                    upload: Arc::new(AtomicU64::new(0)),
+                    download: Arc::new(AtomicU64::new(0)),
+                    upload_read: AtomicU64::new(0),
+                    download_read: AtomicU64::new(0),
+                    download_compression: decoder.stats(),
+                    protocol_error: Arc::new(RwLock::new(None)),
                     status: Arc::new(RwLock::new(TunnelStatus::WaitLogin)),
-                    ping_time: Arc::new(RwLock::new(0)),
-                    ping_delay: Arc::new(RwLock::new(0)),
+                    heartbeat: Arc::new(RwLock::new(HeartbeatState::default())),
                     sender,
+                    events,
                     tcp_reader: Some(r),
                     tcp_writer: w,
                     reader_job: None,
                 };
                 // 开启读线程
-                tunnel.start_reader_job().await;
-                // 登录
-                tunnel.login_tunnel().await;
+                let (login_sender, login_receiver) = oneshot::channel();
+                tunnel.start_reader_job(decoder, buffer_tmp, login_sender).await;
+                // 登录 等待服务端返回登录结果
+                tunnel.login_tunnel(login_proof).await;
+                let result = match timeout(LOGIN_TIMEOUT, login_receiver).await {
+                    Ok(Ok(result)) => { result }
+                    Ok(Err(_)) => { Err(TunnelError::TunnelUnavailable("登录时隧道断开".to_string())) }
+                    Err(_) => { Err(TunnelError::Timeout("隧道登录超时".to_string())) }
+                };
+                if let Err(e) = result {
+                    if let Some(reader_job) = tunnel.reader_job.take() {
+                        reader_job.abort();
+                    }
+                    return Err(e);
+                }
                 // 发送ping命令
-                tunnel.send_ping().await;
+                let _ = tunnel.send_ping().await;
                 Ok(tunnel)
             }
             Err(e) => { Err(e) }
         }
     }
 
-    /// 获取上传流量
+    /// 获取上传流量 返回上次读取以来的增量
     pub async fn get_upload(&self) -> i64 {
-        let mut x = self.upload.write().await;
-        let u = x.clone();
-        *x = 0;
-        u
+        let total = self.get_total_upload();
+        (total - self.upload_read.swap(total, Ordering::Relaxed)) as i64
     }
 
-    /// 获取下载流量
+    /// 获取下载流量 返回上次读取以来的增量
     pub async fn get_download(&self) -> i64 {
-        let mut x = self.download.write().await;
-        let u = x.clone();
-        *x = 0;
-        u
+        let total = self.get_total_download();
+        (total - self.download_read.swap(total, Ordering::Relaxed)) as i64
+    }
+
+    /// 累计上传流量
+    pub fn get_total_upload(&self) -> u64 {
+        self.upload.load(Ordering::Relaxed)
+    }
+
+    /// 累计下载流量
+    pub fn get_total_download(&self) -> u64 {
+        self.download.load(Ordering::Relaxed)
+    }
+
+    /// 获取发送方向的压缩统计
+    pub fn get_upload_compression(&self) -> CompressionSummary {
+        self.encoder.stats().summary()
+    }
+
+    /// 获取接收方向的压缩统计
+    pub fn get_download_compression(&self) -> CompressionSummary {
+        self.download_compression.summary()
+    }
+
+    /// 获取协议错误的原因
+    pub async fn get_protocol_error(&self) -> Option<String> {
+        self.protocol_error.read().await.clone()
     }
 
     /// 获取隧道状态
@@ -219,9 +503,50 @@ impl Tunnel {
         return self.status.read().await.clone();
     }
 
+    /// 获取协商的协议版本
+    pub fn get_version(&self) -> u8 {
+        self.version
+    }
+
+    /// 获取协商的能力
+    pub fn get_capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// 隧道是否多路复用
+    pub fn multiplexed(&self) -> bool {
+        self.multiplexed
+    }
+
     /// 获取Ping延迟
     pub async fn get_ping_delay(&self) -> u128 {
-        *self.ping_delay.read().await
+        self.heartbeat.read().await.rtt.last() as u128
+    }
+
+    /// 获取延迟统计
+    pub async fn get_rtt_summary(&self) -> RttSummary {
+        self.heartbeat.read().await.rtt.summary()
+    }
+
+    /// 心跳 上一次PING还未收到PONG时记为丢失一次
+    /// 连续丢失max_missed次或PING发送失败时判定隧道已断开
+    pub async fn heartbeat(&mut self, max_missed: u32) {
+        let missed = {
+            let mut heartbeat = self.heartbeat.write().await;
+            if heartbeat.ping_time.is_some() {
+                heartbeat.missed += 1;
+            }
+            heartbeat.missed
+        };
+        if missed >= max_missed {
+            log::error!("tunnel {}:{} missed {} pong, tunnel is dead", self.host, self.port, missed);
+            *self.status.write().await = TunnelStatus::Logout;
+            return;
+        }
+        if let Err(e) = self.send_ping().await {
+            log::error!("tunnel {}:{} send ping error: {}", self.host, self.port, e);
+            *self.status.write().await = TunnelStatus::Logout;
+        }
     }
 
     /// 断开连接
@@ -229,87 +554,59 @@ impl Tunnel {
         // 设置状态
         let mut write_guard = self.status.write().await;
         *write_guard = TunnelStatus::Logout;
-        // 停止读线程
+        // 停止读线程 读线程已结束时已经发布过断开事件
         if let Some(reader_job) = self.reader_job.take() {
-            reader_job.abort();
+            if !reader_job.is_finished() {
+                reader_job.abort();
+                self.events.publish(TunnelEventKind::Closed { reason: None });
+            }
         }
         log::error!("tunnel {}:{} disconnect", self.host, self.port);
     }
 
+    /// 有序关闭隧道 通知服务端关闭这些连接并刷新写端，超时后直接断开
+    pub async fn shutdown(&mut self, stream_ids: &[u32]) {
+        self.disconnect().await;
+        let result = timeout(SHUTDOWN_TIMEOUT, async {
+            for stream_id in stream_ids {
+                let tunnel_package = TunnelPackage::new(PackageCmd::CloseConnect, PackageProtocol::TCP, *stream_id, None, None, None);
+                self.write_to_tunnel(tunnel_package).await?;
+            }
+            self.tcp_writer.shutdown().await.map_err(TunnelError::from)
+        }).await;
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => { log::error!("tunnel {}:{} shutdown error: {}", self.host, self.port, e); }
+            Err(_) => { log::error!("tunnel {}:{} shutdown timeout", self.host, self.port); }
+        }
+    }
+
     /// 写数据包到Tunnel上
-    pub async fn write_to_tunnel(&mut self, mut tunnel_package: TunnelPackage) -> Result<(), String> {
+    pub async fn write_to_tunnel(&mut self, tunnel_package: TunnelPackage) -> Result<(), TunnelError> {
         // log::error!("tunnel write to tunnel:{:?}", tunnel_package);
-        let pwd = self.password_md5.as_bytes();
-        // 转成数组
-        let mut vec1 = Vec::new();
-        tunnel_package.to_byte_array(vec1.as_mut());
-
-        // 加密
-        let cipher = Cipher::aes_256_ecb();
-        let mut final_result = match encrypt(cipher, pwd, None, vec1.as_slice()) {
-            Ok(vec) => { vec }
-            Err(e) => { return Err(e.to_string()); }
-        };
-
-        let result_byte_arr = final_result.as_slice();
-        let data_length = (result_byte_arr.len() as u32).to_le_bytes();
-        for x in data_length {
-            final_result.insert(0, x);
-        }
-        final_result.insert(0, 0x2f);
-        final_result.insert(0, 0x0f);
-        let x1 = final_result.as_slice();
+        // 编码并加密到复用的写缓冲区
+        self.write_buffer.clear();
+        self.encoder.encode(&tunnel_package, &mut self.write_buffer)?;
 
-        // log::error!("write data:{:02x?}", x1);
-        match self.tcp_writer.write_all(x1).await {
+        // log::error!("write data:{:02x?}", self.write_buffer);
+        let route = if self.multiplexed { FrameRoute::of(&tunnel_package) } else { FrameRoute::Control };
+        match self.tcp_writer.write_routed(route, &self.write_buffer).await {
             Ok(_) => {
-                let _ = self.tcp_writer.flush().await;
-                let mut write_guard = self.upload.write().await;
-                *write_guard += x1.len() as i64;
+                self.upload.fetch_add(self.write_buffer.len() as u64, Ordering::Relaxed);
                 Ok(())
             }
             Err(e) => {
                 log::error!("tunnel write err {}", e);
-                Err(e.to_string())
+                Err(e.into())
             }
         }
     }
 }
 
-/// 数据包转TunnelPackage结构体
-fn buffer_to_tunnel_package(buffer_tmp: &mut Vec<u8>, password_md5_byte: &[u8]) -> Result<Option<TunnelPackage>, String> {
-    // 数据长度小于6
-    if buffer_tmp.len() < 6 {
-        return Ok(None);
-    }
-
-    // 校验数据头
-    if buffer_tmp[0] != 0x0f && buffer_tmp[1] != 0x2f {
-        return Err("数据包头错误".to_string());
+/// ProtocolError数据包中的错误原因
+fn protocol_error_reason(data: Option<Vec<u8>>) -> String {
+    match data {
+        Some(data) => { String::from_utf8_lossy(&data).to_string() }
+        None => { "未知原因".to_string() }
     }
-
-    let data_length = i32::from_be_bytes([buffer_tmp[2], buffer_tmp[3], buffer_tmp[4], buffer_tmp[5]]);
-    if buffer_tmp.len() < (data_length + 6) as usize {
-        return Ok(None);
-    }
-
-    let mut new_buffer = buffer_tmp.split_off((data_length + 6) as usize);
-    let read_data_arr = &buffer_tmp.as_slice()[6..];
-
-    // 解密
-    let cipher = Cipher::aes_256_ecb();
-    let result = match decrypt(cipher, password_md5_byte, None, read_data_arr) {
-        Ok(vec) => { vec }
-        Err(e) => { return Err(e.to_string()); }
-    };
-
-
-    // 转成结构体
-    let data = result.as_slice();
-    let tunnel_package = TunnelPackage::from_byte_array(data);
-    buffer_tmp.clear();
-    buffer_tmp.append(&mut new_buffer);
-
-
-    return Ok(Some(tunnel_package));
-}
+}
\ No newline at end of file
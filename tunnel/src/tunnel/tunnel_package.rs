@@ -1,15 +1,25 @@
+use std::fmt::{Display, Formatter};
+
+use bytes::BufMut;
+
+use crate::tunnel::hello::{PROTOCOL_VERSION, STREAM_ID_VERSION};
+
+/// 数据包头
+const PACKAGE_MAGIC: [u8; 2] = [0x0f, 0x2f];
 
 /// 隧道数据包
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TunnelPackage {
     pub cmd: PackageCmd,
     pub protocol: PackageProtocol,
+    /// 连接编号 NewConnect时建立，之后的TCP数据包只带编号不带地址，0表示不属于任何连接
+    pub stream_id: u32,
     pub source_address: Option<String>,
     pub target_address: Option<String>,
     pub data: Option<Vec<u8>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PackageProtocol {
     TCP = 0x01,
     UDP = 0x02,
@@ -34,17 +44,23 @@ impl PackageProtocol {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PackageCmd {
     Login = 0x01,
     NewConnect = 0x03,
     CloseConnect = 0x04,
     TData = 0x05,
     PING = 0x06,
+    Handshake = 0x07,
+    WindowUpdate = 0x08,
+    /// 请求服务端解析域名
+    Resolve = 0x09,
     LoginSuccess = 0x41,
     LoginFail = 0x42,
     ProtocolError = 0x43,
     PONG = 0x44,
+    HandshakeAck = 0x45,
+    ResolveResult = 0x46,
     NONE,
 }
 
@@ -56,10 +72,15 @@ impl PackageCmd {
             0x04 => { PackageCmd::CloseConnect }
             0x05 => { PackageCmd::TData }
             0x06 => { PackageCmd::PING }
+            0x07 => { PackageCmd::Handshake }
+            0x08 => { PackageCmd::WindowUpdate }
+            0x09 => { PackageCmd::Resolve }
             0x41 => { PackageCmd::LoginSuccess }
             0x42 => { PackageCmd::LoginFail }
             0x43 => { PackageCmd::ProtocolError }
             0x44 => { PackageCmd::PONG }
+            0x45 => { PackageCmd::HandshakeAck }
+            0x46 => { PackageCmd::ResolveResult }
             _ => { PackageCmd::NONE }
         }
     }
@@ -71,114 +92,285 @@ impl PackageCmd {
             PackageCmd::CloseConnect => { 0x04 }
             PackageCmd::TData => { 0x05 }
             PackageCmd::PING => { 0x06 }
+            PackageCmd::Handshake => { 0x07 }
+            PackageCmd::WindowUpdate => { 0x08 }
+            PackageCmd::Resolve => { 0x09 }
             PackageCmd::NONE => { 0xf0 }
             PackageCmd::LoginSuccess => { 0x41 }
             PackageCmd::LoginFail => { 0x42 }
             PackageCmd::ProtocolError => { 0x43 }
             PackageCmd::PONG => { 0x44 }
+            PackageCmd::HandshakeAck => { 0x45 }
+            PackageCmd::ResolveResult => { 0x46 }
         }
     }
 }
 
 impl TunnelPackage {
-    pub fn new(cmd: PackageCmd, protocol: PackageProtocol, source_address: Option<String>, target_address: Option<String>, data: Option<Vec<u8>>) -> TunnelPackage {
+    pub fn new(cmd: PackageCmd, protocol: PackageProtocol, stream_id: u32, source_address: Option<String>, target_address: Option<String>, data: Option<Vec<u8>>) -> TunnelPackage {
         TunnelPackage {
             cmd,
             protocol,
+            stream_id,
             source_address,
             target_address,
             data,
         }
     }
+
+    /// 协议错误 数据为拒绝的原因
+    pub fn protocol_error(stream_id: u32, reason: &str) -> TunnelPackage {
+        TunnelPackage::new(PackageCmd::ProtocolError, PackageProtocol::TCP, stream_id, None, None, Some(reason.as_bytes().to_vec()))
+    }
 }
 
 impl TunnelPackage {
-    pub fn to_byte_array(&mut self, vec: &mut Vec<u8>) {
-        vec.push(0x0f);
-        vec.push(0x2f);
-        vec.push(self.cmd.as_byte());
-        vec.push(self.protocol.as_byte());
-
-        let source_addr = self.source_address.take();
-        match source_addr {
-            None => {
-                vec.push(0u8);
-                vec.push(0u8);
-                vec.push(0u8);
-                vec.push(0u8);
-            }
-            Some(addr) => {
-                vec.append(&mut (addr.len() as u32).to_le_bytes().to_vec());
-                vec.append(&mut addr.as_bytes().to_vec());
-            }
+    /// 只属于某个连接、不需要地址的命令 版本2开始只带连接编号
+    fn stream_only(cmd: &PackageCmd, protocol: &PackageProtocol) -> bool {
+        match cmd {
+            PackageCmd::CloseConnect | PackageCmd::WindowUpdate => { true }
+            PackageCmd::TData => { matches!(protocol, PackageProtocol::TCP) }
+            _ => { false }
         }
+    }
 
-        let target_addr = self.target_address.take();
-        match target_addr {
-            None => {
-                vec.push(0u8);
-                vec.push(0u8);
-                vec.push(0u8);
-                vec.push(0u8);
-            }
-            Some(addr) => {
-                vec.append(&mut (addr.len() as u32).to_le_bytes().to_vec());
-                vec.append(&mut addr.as_bytes().to_vec());
-            }
+    /// 版本1用源地址标识连接 连接编号写成源地址
+    fn legacy_source_address(&self) -> Option<String> {
+        if self.stream_id == 0 {
+            self.source_address.clone()
+        } else {
+            Some(self.stream_id.to_string())
         }
+    }
 
-        let data = self.data.take();
-        match data {
-            None => {
-                vec.push(0u8);
-                vec.push(0u8);
-                vec.push(0u8);
-                vec.push(0u8);
-            }
-            Some(mut data) => {
-                let mut vec1 = (data.len() as u32).to_le_bytes().to_vec();
-                vec.append(&mut vec1);
-                vec.append(&mut data);
+    /// 按当前协议版本编码后的长度
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len_version(PROTOCOL_VERSION)
+    }
+
+    /// 按指定协议版本编码后的长度
+    pub fn encoded_len_version(&self, version: u8) -> usize {
+        let data_len = 4 + self.data.as_ref().map_or(0, |data| data.len());
+        if version < STREAM_ID_VERSION {
+            return 12 + self.legacy_source_address().map_or(0, |addr| addr.len())
+                + self.target_address.as_ref().map_or(0, |addr| addr.len())
+                + data_len;
+        }
+        if Self::stream_only(&self.cmd, &self.protocol) {
+            return 8 + data_len;
+        }
+        16 + self.source_address.as_ref().map_or(0, |addr| addr.len())
+            + self.target_address.as_ref().map_or(0, |addr| addr.len())
+            + data_len
+    }
+
+    /// 按当前协议版本编码并追加到缓冲区 不产生中间数组
+    pub fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.encode_version(PROTOCOL_VERSION, buf)
+    }
+
+    /// 按协商的协议版本编码
+    /// 版本1不带连接编号，每个数据包都带源地址和目标地址
+    /// 版本2开始带连接编号，只属于连接的命令不再带地址
+    pub fn encode_version<B: BufMut>(&self, version: u8, buf: &mut B) {
+        buf.put_slice(&PACKAGE_MAGIC);
+        buf.put_u8(self.cmd.as_byte());
+        buf.put_u8(self.protocol.as_byte());
+
+        if version < STREAM_ID_VERSION {
+            put_string(buf, self.legacy_source_address().as_deref());
+            put_string(buf, self.target_address.as_deref());
+        } else {
+            buf.put_u32_le(self.stream_id);
+            if !Self::stream_only(&self.cmd, &self.protocol) {
+                put_string(buf, self.source_address.as_deref());
+                put_string(buf, self.target_address.as_deref());
             }
         }
+
+        let data = self.data.as_deref().unwrap_or_default();
+        buf.put_u32_le(data.len() as u32);
+        buf.put_slice(data);
     }
 
-    pub fn from_byte_array(data: &[u8]) -> TunnelPackage {
-        let mut index = 0;
-
-        let _header1 = data[index];
-        index += 1;
-        let _header2 = data[index];
-        index += 1;
-        let cmd = PackageCmd::from_cmd(data[index]);
-        index += 1;
-        let protocol = PackageProtocol::from_protocol(data[index]);
-        index += 1;
-
-        let source_address_len = u32::from_le_bytes([data[index], data[index + 1], data[index + 2], data[index + 3]]);
-        index += 4;
-        let source_address = if source_address_len == 0 { None } else {
-            let source_addr_str = Some(String::from_utf8_lossy(&data[index..index + source_address_len as usize]).to_string());
-            index += source_address_len as usize;
-            source_addr_str
-        };
-        let target_address_len = u32::from_le_bytes([data[index], data[index + 1], data[index + 2], data[index + 3]]);
-        index += 4;
-        let target_address = if target_address_len == 0 { None } else {
-            let target_addr_str = Some(String::from_utf8_lossy(&data[index..index + target_address_len as usize]).to_string());
-            index += target_address_len as usize;
-            target_addr_str
-        };
+    pub fn to_byte_array(&self, vec: &mut Vec<u8>) {
+        vec.reserve(self.encoded_len());
+        self.encode(vec);
+    }
+
+    /// 按当前协议版本解析数据包 数据不完整或格式错误时返回错误，不会panic
+    pub fn from_byte_array(data: &[u8]) -> Result<TunnelPackage, DecodeError> {
+        TunnelPackage::from_byte_array_version(data, PROTOCOL_VERSION)
+    }
+
+    /// 按协商的协议版本解析数据包
+    pub fn from_byte_array_version(data: &[u8], version: u8) -> Result<TunnelPackage, DecodeError> {
+        let mut reader = PackageReader { data, index: 0 };
 
-        let data_len = u32::from_le_bytes([data[index], data[index + 1], data[index + 2], data[index + 3]]);
-        index += 4;
+        let magic = reader.take("header", 2)?;
+        if magic != PACKAGE_MAGIC {
+            return Err(DecodeError::BadMagic([magic[0], magic[1]]));
+        }
+        let cmd = PackageCmd::from_cmd(reader.u8("cmd")?);
+        let protocol = PackageProtocol::from_protocol(reader.u8("protocol")?);
+        let (stream_id, source_address, target_address) = if version < STREAM_ID_VERSION {
+            let source_address = reader.string("source_address")?;
+            let target_address = reader.string("target_address")?;
+            // 源地址是本端写入的连接编号时还原成编号
+            match source_address.as_deref().map(str::parse::<u32>) {
+                Some(Ok(stream_id)) if stream_id != 0 => { (stream_id, None, target_address) }
+                _ => { (0, source_address, target_address) }
+            }
+        } else {
+            let stream_id = reader.u32_le("stream_id")?;
+            if Self::stream_only(&cmd, &protocol) {
+                (stream_id, None, None)
+            } else {
+                (stream_id, reader.string("source_address")?, reader.string("target_address")?)
+            }
+        };
+        let data = reader.bytes("data")?;
 
-        return TunnelPackage {
+        Ok(TunnelPackage {
             cmd,
             protocol,
+            stream_id,
             source_address,
             target_address,
-            data: if data_len != 0 { Some(data[index..(index + data_len as usize)].to_vec()) } else { None },
-        };
+            data: if data.is_empty() { None } else { Some(data.to_vec()) },
+        })
+    }
+}
+
+/// 4字节长度+字符串 None写成长度0
+fn put_string<B: BufMut>(buf: &mut B, value: Option<&str>) {
+    let value = value.unwrap_or_default();
+    buf.put_u32_le(value.len() as u32);
+    buf.put_slice(value.as_bytes());
+}
+
+/// 按字段读取数据包 每次读取前检查剩余长度
+struct PackageReader<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> PackageReader<'a> {
+    fn take(&mut self, field: &'static str, len: usize) -> Result<&'a [u8], DecodeError> {
+        let remaining = self.data.len() - self.index;
+        if len > remaining {
+            return Err(DecodeError::Truncated { field, need: len, remaining });
+        }
+        let slice = &self.data[self.index..self.index + len];
+        self.index += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self, field: &'static str) -> Result<u8, DecodeError> {
+        Ok(self.take(field, 1)?[0])
+    }
+
+    fn u32_le(&mut self, field: &'static str) -> Result<u32, DecodeError> {
+        let bytes = self.take(field, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// 4字节长度+数据
+    fn bytes(&mut self, field: &'static str) -> Result<&'a [u8], DecodeError> {
+        let len = self.u32_le(field)? as usize;
+        self.take(field, len)
+    }
+
+    /// 长度为0时为None
+    fn string(&mut self, field: &'static str) -> Result<Option<String>, DecodeError> {
+        let bytes = self.bytes(field)?;
+        Ok(if bytes.is_empty() { None } else { Some(String::from_utf8_lossy(bytes).to_string()) })
     }
-}
\ No newline at end of file
+}
+
+/// 数据帧和数据包的解码错误
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// 字段需要的长度超过剩余数据
+    Truncated { field: &'static str, need: usize, remaining: usize },
+    /// 数据包头错误
+    BadMagic([u8; 2]),
+    /// 帧长度为0或者放不下帧计数器和认证标签
+    InvalidFrameLength(usize),
+    /// 帧长度超过上限
+    FrameTooLarge(usize),
+    /// 明文解码器收到加密帧
+    UnexpectedSuite(u8),
+    /// 加密套件和登录时选择的不一致
+    SuiteMismatch { expected: u8, received: u8 },
+    /// 帧计数器小于期望值
+    Replay(u64),
+    /// 帧计数器大于期望值
+    OutOfOrder { expected: u64, received: u64 },
+    /// 解密或认证失败
+    Decrypt(String),
+    UnknownCompression(u8),
+    Decompress(String),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated { field, need, remaining } => { write!(f, "数据包长度错误: {} 需要{}字节 剩余{}字节", field, need, remaining) }
+            DecodeError::BadMagic(magic) => { write!(f, "数据包头错误: {:02x}{:02x}", magic[0], magic[1]) }
+            DecodeError::InvalidFrameLength(len) => { write!(f, "数据帧长度错误: {}", len) }
+            DecodeError::FrameTooLarge(len) => { write!(f, "数据帧过长: {}", len) }
+            DecodeError::UnexpectedSuite(suite) => { write!(f, "握手数据帧错误: {}", suite) }
+            DecodeError::SuiteMismatch { expected, received } => { write!(f, "加密套件不匹配: 期望{} 收到{}", expected, received) }
+            DecodeError::Replay(counter) => { write!(f, "重复的数据帧: {}", counter) }
+            DecodeError::OutOfOrder { expected, received } => { write!(f, "数据帧乱序: 期望{} 收到{}", expected, received) }
+            DecodeError::Decrypt(e) => { write!(f, "数据帧解密失败: {}", e) }
+            DecodeError::UnknownCompression(flag) => { write!(f, "未知的压缩算法: {}", flag) }
+            DecodeError::Decompress(e) => { write!(f, "数据帧解压失败: {}", e) }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[test]
+fn test_stream_package() {
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 7, None, None, Some(vec![1, 2, 3]));
+    let mut vec = Vec::new();
+    package.to_byte_array(&mut vec);
+    // 头部4字节+连接编号4字节+数据长度4字节 不带地址
+    assert_eq!(vec.len(), 12 + 3);
+    assert_eq!(vec.len(), package.encoded_len());
+
+    let decoded = TunnelPackage::from_byte_array(&vec).unwrap();
+    assert_eq!(decoded, package);
+
+    // 截断和长度字段越界的数据包返回错误
+    assert_eq!(TunnelPackage::from_byte_array(&vec[..vec.len() - 1]),
+               Err(DecodeError::Truncated { field: "data", need: 3, remaining: 2 }));
+    let connect = TunnelPackage::new(PackageCmd::NewConnect, PackageProtocol::TCP, 7, None, Some("example.com:443".to_string()), None);
+    let mut vec = Vec::new();
+    connect.to_byte_array(&mut vec);
+    assert_eq!(TunnelPackage::from_byte_array(&vec).unwrap(), connect);
+    vec[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(TunnelPackage::from_byte_array(&vec), Err(DecodeError::Truncated { field: "source_address", .. })));
+    assert_eq!(TunnelPackage::from_byte_array(&[0x0f, 0x30]), Err(DecodeError::BadMagic([0x0f, 0x30])));
+    assert!(TunnelPackage::from_byte_array(&[]).is_err());
+}
+
+#[test]
+fn test_legacy_package() {
+    // 版本1没有连接编号字段 连接编号写在源地址里
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 7, None, None, Some(vec![1, 2, 3]));
+    let mut vec = Vec::new();
+    package.encode_version(1, &mut vec);
+    assert_eq!(vec.len(), package.encoded_len_version(1));
+    assert_eq!(&vec[4..9], &[1, 0, 0, 0, b'7']);
+    assert_eq!(TunnelPackage::from_byte_array_version(&vec, 1).unwrap(), package);
+
+    // 不属于连接的数据包原样保留源地址
+    let package = TunnelPackage::new(PackageCmd::Handshake, PackageProtocol::TCP, 0, Some("127.0.0.1:1080".to_string()), None, Some(vec![9]));
+    let mut vec = Vec::new();
+    package.encode_version(1, &mut vec);
+    assert_eq!(TunnelPackage::from_byte_array_version(&vec, 1).unwrap(), package);
+}
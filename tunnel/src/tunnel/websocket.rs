@@ -0,0 +1,292 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use openssl::base64::encode_block;
+use openssl::hash::{hash, MessageDigest};
+use openssl::rand::rand_bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, split, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::tunnel::codec::{FRAME_HEADER_LEN, MAX_FRAME_SIZE};
+use crate::tunnel::tls::{connect_tls, TlsConfig};
+use crate::tunnel::transport::{Transport, TransportReader, TransportWriter};
+
+/// 计算Sec-WebSocket-Accept使用的固定GUID
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// HTTP升级响应头的最大长度
+const MAX_HEADER_SIZE: usize = 8192;
+/// 每次读取预留的缓冲区大小
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// 单个WebSocket消息的最大长度 每个消息是一个完整的隧道数据帧
+/// 帧头之后的长度字段最大为MAX_FRAME_SIZE，再加上长度字段之前的帧头
+const MAX_PAYLOAD_SIZE: u64 = (MAX_FRAME_SIZE + FRAME_HEADER_LEN - 1) as u64;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// WebSocket配置
+#[derive(Clone, Default)]
+pub struct WsConfig {
+    /// 升级请求的路径
+    pub path: String,
+    /// Host请求头 为空时使用服务器地址
+    pub host: Option<String>,
+    /// 使用wss时的TLS配置
+    pub tls: Option<TlsConfig>,
+}
+
+/// WebSocket传输 隧道数据帧作为二进制消息发送
+pub struct WsTransport {
+    pub config: WsConfig,
+}
+
+#[async_trait]
+impl Transport for WsTransport {
+    async fn connect(&self, host: &str, port: u16) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), Error> {
+        let tcp_stream = TcpStream::connect((host, port)).await?;
+        match self.config.tls.as_ref() {
+            Some(tls_config) => {
+                let ssl_stream = connect_tls(tcp_stream, host, tls_config).await?;
+                connect_ws(ssl_stream, host, port, &self.config).await
+            }
+            None => { connect_ws(tcp_stream, host, port, &self.config).await }
+        }
+    }
+}
+
+/// 在已建立的连接上完成WebSocket升级
+pub async fn connect_ws<S>(mut stream: S, host: &str, port: u16, config: &WsConfig) -> Result<(Box<dyn TransportReader>, Box<dyn TransportWriter>), Error>
+    where S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {
+    let mut key = [0u8; 16];
+    rand_bytes(&mut key).map_err(Error::other)?;
+    let key = encode_block(&key);
+    let host_header = match config.host.as_ref() {
+        Some(host_header) => { host_header.clone() }
+        None => { format!("{}:{}", host, port) }
+    };
+    let path = if config.path.starts_with('/') { config.path.clone() } else { format!("/{}", config.path) };
+    let request = format!("GET {} HTTP/1.1\r\n\
+                           Host: {}\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: {}\r\n\
+                           Sec-WebSocket-Version: 13\r\n\r\n", path, host_header, key);
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // 读取响应头 之后的数据已经是WebSocket帧
+    let mut buffer = BytesMut::new();
+    let mut data = [0; 1024];
+    let header_end = loop {
+        if let Some(index) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break index + 4;
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "WebSocket响应头过长"));
+        }
+        let n = stream.read(&mut data).await?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "WebSocket升级时连接断开"));
+        }
+        buffer.extend_from_slice(&data[..n]);
+    };
+    let header = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    check_upgrade_response(&header, &key)?;
+    buffer.advance(header_end);
+
+    let (reader, writer) = split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    Ok((Box::new(WsReader { reader, writer: writer.clone(), buffer, message: BytesMut::new(), payload: BytesMut::new() }),
+        Box::new(WsWriter { writer })))
+}
+
+/// 校验升级响应的状态码和Sec-WebSocket-Accept
+fn check_upgrade_response(header: &str, key: &str) -> Result<(), Error> {
+    let mut lines = header.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    if status_line.split(' ').nth(1) != Some("101") {
+        return Err(Error::new(ErrorKind::InvalidData, format!("WebSocket升级失败: {}", status_line)));
+    }
+    let expected = accept_key(key)?;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+                if value.trim() == expected {
+                    return Ok(());
+                }
+                return Err(Error::new(ErrorKind::InvalidData, "Sec-WebSocket-Accept错误"));
+            }
+        }
+    }
+    Err(Error::new(ErrorKind::InvalidData, "缺少Sec-WebSocket-Accept"))
+}
+
+/// 根据Sec-WebSocket-Key计算Sec-WebSocket-Accept
+pub fn accept_key(key: &str) -> Result<String, Error> {
+    let digest = hash(MessageDigest::sha1(), format!("{}{}", key, WS_GUID).as_bytes()).map_err(Error::other)?;
+    Ok(encode_block(&digest))
+}
+
+/// 编码一个客户端帧 客户端发出的帧必须加掩码
+fn encode_frame(opcode: u8, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    let mut mask = [0u8; 4];
+    rand_bytes(&mut mask).map_err(Error::other)?;
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    Ok(frame)
+}
+
+/// 从缓冲区取出一个完整的帧 返回FIN、操作码和数据
+/// 数据直接从缓冲区切出并原地去掉掩码，不复制
+fn decode_frame(buffer: &mut BytesMut) -> Result<Option<(bool, u8, BytesMut)>, Error> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0f;
+    let masked = buffer[1] & 0x80 != 0;
+    let (payload_len, mut index) = match buffer[1] & 0x7f {
+        126 => {
+            if buffer.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4)
+        }
+        127 => {
+            if buffer.len() < 10 {
+                return Ok(None);
+            }
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => { (len as u64, 2) }
+    };
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket消息过长"));
+    }
+    let mask_len = if masked { 4 } else { 0 };
+    if buffer.len() < index + mask_len + payload_len as usize {
+        // 提前预留整帧的空间，大帧不会反复扩容
+        buffer.reserve(index + mask_len + payload_len as usize - buffer.len());
+        return Ok(None);
+    }
+    let mut mask = [0u8; 4];
+    if masked {
+        mask.copy_from_slice(&buffer[index..index + 4]);
+        index += 4;
+    }
+    buffer.advance(index);
+    let mut payload = buffer.split_to(payload_len as usize);
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok(Some((fin, opcode, payload)))
+}
+
+/// WebSocket读端 读取二进制消息中的隧道数据
+struct WsReader<S> {
+    reader: ReadHalf<S>,
+    /// 回复PING使用
+    writer: Arc<Mutex<WriteHalf<S>>>,
+    /// 还未解析的原始数据
+    buffer: BytesMut,
+    /// 未收完的分片消息
+    message: BytesMut,
+    /// 已收到但还未读走的消息数据
+    payload: BytesMut,
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> TransportReader for WsReader<S> {
+    async fn read_buf(&mut self, buf: &mut BytesMut) -> Result<usize, Error> {
+        while self.payload.is_empty() {
+            match decode_frame(&mut self.buffer)? {
+                Some((fin, opcode, payload)) => {
+                    match opcode {
+                        OPCODE_BINARY | OPCODE_TEXT | OPCODE_CONTINUATION => {
+                            // 没有分片的消息直接使用切出的数据
+                            if self.message.is_empty() {
+                                self.message = payload;
+                            } else {
+                                self.message.extend_from_slice(&payload);
+                            }
+                            if self.message.len() as u64 > MAX_PAYLOAD_SIZE {
+                                return Err(Error::new(ErrorKind::InvalidData, "WebSocket消息过长"));
+                            }
+                            if fin {
+                                self.payload = self.message.split();
+                            }
+                        }
+                        OPCODE_PING => {
+                            let frame = encode_frame(OPCODE_PONG, &payload)?;
+                            let mut writer = self.writer.lock().await;
+                            writer.write_all(&frame).await?;
+                            writer.flush().await?;
+                        }
+                        OPCODE_PONG => {}
+                        OPCODE_CLOSE => {
+                            return Ok(0);
+                        }
+                        _ => {
+                            return Err(Error::new(ErrorKind::InvalidData, format!("未知的WebSocket操作码: {}", opcode)));
+                        }
+                    }
+                }
+                None => {
+                    self.buffer.reserve(READ_BUFFER_SIZE);
+                    if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+        let n = self.payload.len();
+        buf.extend_from_slice(&self.payload);
+        self.payload.clear();
+        Ok(n)
+    }
+}
+
+/// WebSocket写端 每个隧道数据帧作为一个二进制消息
+struct WsWriter<S> {
+    writer: Arc<Mutex<WriteHalf<S>>>,
+}
+
+#[async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> TransportWriter for WsWriter<S> {
+    async fn write_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let frame = encode_frame(OPCODE_BINARY, frame)?;
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+
+    /// 先发送关闭消息再关闭底层连接
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        let frame = encode_frame(OPCODE_CLOSE, &[])?;
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&frame).await?;
+        writer.shutdown().await
+    }
+}
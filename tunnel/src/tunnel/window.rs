@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use tokio::sync::Semaphore;
+
+use crate::error::TunnelError;
+use crate::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+
+/// 每个连接的初始窗口 对端最多可以发送这么多还未确认的数据
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// 累计消费这么多数据后才发送窗口更新，避免每个数据包都回一次
+pub const WINDOW_UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 2;
+
+/// 发送窗口 数据包按数据长度消耗信用，收到对端的窗口更新后恢复
+/// 只阻塞当前连接的发送，不影响隧道上的其他连接
+#[derive(Clone)]
+pub struct SendWindow {
+    credits: Arc<Semaphore>,
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        SendWindow {
+            credits: Arc::new(Semaphore::new(INITIAL_WINDOW as usize)),
+        }
+    }
+}
+
+impl SendWindow {
+    /// 等待足够的信用后发送 消耗的信用和对端归还的一致
+    /// 单个数据包不能超过一个完整窗口，更大的数据需要先拆分
+    pub async fn acquire(&self, len: usize) -> Result<(), TunnelError> {
+        if len > INITIAL_WINDOW as usize {
+            return Err(TunnelError::Protocol(format!("数据包超过窗口大小: {}", len)));
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        match self.credits.acquire_many(len as u32).await {
+            Ok(permit) => {
+                permit.forget();
+                Ok(())
+            }
+            Err(_) => { Err(TunnelError::TunnelUnavailable("Stream is closed".to_string())) }
+        }
+    }
+
+    /// 对端确认后增加信用
+    pub fn grant(&self, increment: u32) {
+        let available = Semaphore::MAX_PERMITS - self.credits.available_permits();
+        self.credits.add_permits((increment as usize).min(available));
+    }
+
+    /// 关闭窗口 唤醒所有等待的发送
+    pub fn close(&self) {
+        self.credits.close();
+    }
+
+    /// 当前可用的信用
+    pub fn available(&self) -> usize {
+        self.credits.available_permits()
+    }
+}
+
+/// 接收窗口 记录已收到但还未归还给对端的数据
+/// 对端遵守流量控制时不会超过一个完整窗口，超过时返回错误，本地排队的数据不会无限增长
+#[derive(Clone)]
+pub struct ReceiveWindow {
+    /// 是否开启了流量控制
+    enabled: bool,
+    /// 多路复用时UDP数据走数据报，发送端不消耗信用
+    multiplexed: bool,
+    pending: Arc<AtomicU32>,
+}
+
+impl ReceiveWindow {
+    pub fn new(enabled: bool, multiplexed: bool) -> ReceiveWindow {
+        ReceiveWindow { enabled, multiplexed, pending: Arc::new(AtomicU32::new(0)) }
+    }
+
+    /// 数据占用的窗口 和发送端消耗的信用一致
+    pub fn cost(&self, protocol: &PackageProtocol, len: usize) -> u32 {
+        if !self.enabled || (matches!(protocol, PackageProtocol::UDP) && self.multiplexed) {
+            return 0;
+        }
+        len as u32
+    }
+
+    /// 收到对端的数据
+    pub fn receive(&self, protocol: &PackageProtocol, len: usize) -> Result<(), TunnelError> {
+        let cost = self.cost(protocol, len);
+        if cost == 0 {
+            return Ok(());
+        }
+        let pending = self.pending.fetch_add(cost, Ordering::Relaxed).saturating_add(cost);
+        if pending > INITIAL_WINDOW {
+            return Err(TunnelError::Protocol(format!("对端发送的数据超过接收窗口: {}", pending)));
+        }
+        Ok(())
+    }
+
+    /// 已经向对端归还的窗口
+    pub fn release(&self, increment: u32) {
+        let _ = self.pending.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| Some(pending.saturating_sub(increment)));
+    }
+}
+
+/// 窗口更新数据包 数据为大端的窗口增量
+pub fn window_update_package(stream_id: u32, increment: u32) -> TunnelPackage {
+    TunnelPackage::new(PackageCmd::WindowUpdate, PackageProtocol::TCP, stream_id, None, None, Some(increment.to_be_bytes().to_vec()))
+}
+
+/// 读取窗口更新的增量
+pub fn window_increment(tunnel_package: &TunnelPackage) -> Option<u32> {
+    let data = tunnel_package.data.as_ref()?;
+    let data: [u8; 4] = data.as_slice().try_into().ok()?;
+    Some(u32::from_be_bytes(data))
+}
+
+/// 数据包占用的窗口大小
+pub fn window_cost(tunnel_package: &TunnelPackage) -> u32 {
+    match tunnel_package.cmd {
+        PackageCmd::TData => { tunnel_package.data.as_ref().map_or(0, |data| data.len() as u32) }
+        _ => { 0 }
+    }
+}
+
+#[tokio::test]
+async fn test_send_window() {
+    let window = SendWindow::default();
+    // 超过一个窗口的数据需要发送端分段
+    assert!(window.acquire(INITIAL_WINDOW as usize + 1).await.is_err());
+    window.acquire(INITIAL_WINDOW as usize).await.unwrap();
+    assert_eq!(window.available(), 0);
+
+    // 窗口用完后等待对端的窗口更新
+    let waiting = window.clone();
+    let job = tokio::spawn(async move { waiting.acquire(1024).await });
+    tokio::task::yield_now().await;
+    assert!(!job.is_finished());
+    let package = window_update_package(1, 4096);
+    window.grant(window_increment(&package).unwrap());
+    job.await.unwrap().unwrap();
+    assert_eq!(window.available(), 3072);
+
+    // 连接关闭后等待的发送返回错误
+    let waiting = window.clone();
+    let job = tokio::spawn(async move { waiting.acquire(INITIAL_WINDOW as usize).await });
+    tokio::task::yield_now().await;
+    window.close();
+    assert!(job.await.unwrap().is_err());
+}
+
+#[test]
+fn test_receive_window() {
+    let window = ReceiveWindow::new(true, true);
+    window.receive(&PackageProtocol::TCP, INITIAL_WINDOW as usize).unwrap();
+    // 多路复用时UDP数据不占用窗口
+    window.receive(&PackageProtocol::UDP, 1024).unwrap();
+    // 对端超出窗口继续发送
+    assert!(window.receive(&PackageProtocol::TCP, 1).is_err());
+    window.release(INITIAL_WINDOW + 1);
+    window.receive(&PackageProtocol::TCP, INITIAL_WINDOW as usize).unwrap();
+
+    // 没有开启流量控制时不限制
+    let window = ReceiveWindow::new(false, false);
+    window.receive(&PackageProtocol::TCP, INITIAL_WINDOW as usize * 2).unwrap();
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::{sleep, timeout};
+
+use tunnel::error::TunnelError;
+use tunnel::tunnel::cipher::{CipherSuite, FrameCipher, FrameDirection, random_salt, SALT_LEN, SessionKeys, transcript_hash};
+use tunnel::tunnel::codec::{FrameDecoder, FrameEncoder};
+use tunnel::tunnel::compress::Compression;
+use tunnel::tunnel::event::EventPublisher;
+use tunnel::tunnel::hello::{Capabilities, FRAME_COUNTER_VERSION, Hello, HelloAck};
+use tunnel::tunnel::transport::TcpTransport;
+use tunnel::tunnel::tunnel::{Tunnel, TunnelStatus};
+use tunnel::tunnel::tunnel_package::{DecodeError, PackageCmd, PackageProtocol, TunnelPackage};
+
+const PASSWORD: &str = "password";
+
+/// 从连接读取下一个数据包
+async fn read_package(stream: &mut TcpStream, decoder: &mut FrameDecoder, buffer: &mut BytesMut) -> Result<Option<TunnelPackage>, DecodeError> {
+    loop {
+        if let Some(package) = decoder.decode(buffer)? {
+            return Ok(Some(package));
+        }
+        buffer.reserve(64 * 1024);
+        if stream.read_buf(buffer).await.unwrap() == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+async fn write_package(stream: &mut TcpStream, encoder: &mut FrameEncoder, package: TunnelPackage) {
+    let mut buffer = BytesMut::new();
+    encoder.encode(&package, &mut buffer).unwrap();
+    stream.write_all(&buffer).await.unwrap();
+}
+
+/// 登录后把收到的数据原样发回
+async fn serve(mut stream: TcpStream, version: u8, session_keys: SessionKeys, mut read_buffer: BytesMut) {
+    let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &session_keys.client_key, FrameDirection::ClientToServer));
+    let mut encoder = FrameEncoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &session_keys.server_key, FrameDirection::ServerToClient));
+    decoder.set_version(version);
+    encoder.set_version(version);
+    loop {
+        let package = match read_package(&mut stream, &mut decoder, &mut read_buffer).await {
+            Ok(Some(package)) => { package }
+            Ok(None) => { return; }
+            Err(e) => {
+                // 和服务端一样 解码失败时通知客户端原因后断开
+                write_package(&mut stream, &mut encoder, TunnelPackage::protocol_error(0, &e.to_string())).await;
+                return;
+            }
+        };
+        let reply = match package.cmd {
+            PackageCmd::Login => {
+                assert!(session_keys.verify_legacy_login_proof(&package.data.unwrap()).is_ok());
+                TunnelPackage::new(PackageCmd::LoginSuccess, PackageProtocol::TCP, 0, None, None, None)
+            }
+            PackageCmd::PING => { TunnelPackage::new(PackageCmd::PONG, PackageProtocol::TCP, 0, None, None, None) }
+            PackageCmd::TData => { TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, package.stream_id, None, None, package.data) }
+            _ => { continue; }
+        };
+        write_package(&mut stream, &mut encoder, reply).await;
+    }
+}
+
+/// 不支持版本协商的旧服务端替身
+/// 握手数据不是盐时直接断开，只接受客户端盐并回复服务端盐
+async fn start_legacy_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut read_buffer = BytesMut::new();
+            let package = read_package(&mut stream, &mut FrameDecoder::plain(), &mut read_buffer).await.unwrap().unwrap();
+            assert_eq!(package.cmd, PackageCmd::Handshake);
+            let client_salt = package.data.unwrap();
+            if client_salt.len() != SALT_LEN {
+                continue;
+            }
+            let server_salt = random_salt().unwrap().to_vec();
+            let package = TunnelPackage::new(PackageCmd::HandshakeAck, PackageProtocol::TCP, 0, None, None, Some(server_salt.clone()));
+            write_package(&mut stream, &mut FrameEncoder::plain(), package).await;
+            let session_keys = SessionKeys::derive_legacy(PASSWORD.as_bytes(), &client_salt, &server_salt).unwrap();
+            serve(stream, 1, session_keys, read_buffer).await;
+            return;
+        }
+    });
+    port
+}
+
+/// 只支持到指定版本的服务端替身 modify模拟中间人修改发往客户端的握手响应
+async fn start_versioned_server(version: u8, modify: fn(&mut HelloAck)) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut read_buffer = BytesMut::new();
+        let package = read_package(&mut stream, &mut FrameDecoder::plain(), &mut read_buffer).await.unwrap().unwrap();
+        let hello_data = package.data.unwrap();
+        let hello = Hello::from_byte_array(&hello_data).unwrap();
+        assert!(hello.min_version <= version && version <= hello.max_version);
+        let hello_ack = HelloAck {
+            version,
+            capabilities: hello.select_capabilities(version),
+            salt: random_salt().unwrap().to_vec(),
+        };
+        let mut sent = HelloAck::from_byte_array(&hello_ack.to_byte_array()).unwrap();
+        modify(&mut sent);
+        let package = TunnelPackage::new(PackageCmd::HandshakeAck, PackageProtocol::TCP, 0, None, None, Some(sent.to_byte_array()));
+        write_package(&mut stream, &mut FrameEncoder::plain(), package).await;
+        let session_keys = if version >= FRAME_COUNTER_VERSION {
+            let transcript = transcript_hash(&hello_data, &hello_ack.to_byte_array()).unwrap();
+            SessionKeys::derive(PASSWORD.as_bytes(), &hello.salt, &hello_ack.salt, &transcript).unwrap()
+        } else {
+            SessionKeys::derive_legacy(PASSWORD.as_bytes(), &hello.salt, &hello_ack.salt).unwrap()
+        };
+        serve(stream, version, session_keys, read_buffer).await;
+    });
+    port
+}
+
+async fn connect(port: u16, legacy_handshake: bool) -> Result<(Tunnel, Receiver<TunnelPackage>), TunnelError> {
+    let (sender, receiver) = channel(16);
+    let events = EventPublisher::new("legacy".to_string(), broadcast::channel(1).0);
+    let tunnel = Tunnel::new("127.0.0.1".to_string(), port, PASSWORD.to_string(), CipherSuite::Aes256Gcm, Compression::None, legacy_handshake, &TcpTransport, sender, events).await?;
+    Ok((tunnel, receiver))
+}
+
+/// 登录后经隧道收发一个数据包
+async fn connect_and_echo(port: u16, legacy_handshake: bool) -> Tunnel {
+    let (mut tunnel, mut receiver) = connect(port, legacy_handshake).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while !matches!(tunnel.get_status().await, TunnelStatus::Success) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 7, None, None, Some(b"hello".to_vec()));
+    tunnel.write_to_tunnel(package).await.unwrap();
+    let package = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+    assert_eq!(package.stream_id, 7);
+    assert_eq!(package.data, Some(b"hello".to_vec()));
+    tunnel
+}
+
+#[tokio::test]
+async fn test_legacy_server() {
+    // 没有开启旧版本握手时不降级
+    let port = start_legacy_server().await;
+    assert!(matches!(connect(port, false).await.err().unwrap(), TunnelError::Protocol(_)));
+
+    let port = start_legacy_server().await;
+    let mut tunnel = connect_and_echo(port, true).await;
+    assert_eq!(tunnel.get_version(), 1);
+    assert_eq!(tunnel.get_capabilities(), Capabilities::legacy());
+    tunnel.disconnect().await;
+}
+
+#[tokio::test]
+async fn test_older_version() {
+    for version in [1, 2] {
+        let port = start_versioned_server(version, |_| {}).await;
+        let mut tunnel = connect_and_echo(port, false).await;
+        assert_eq!(tunnel.get_version(), version);
+        assert!(!tunnel.get_capabilities().contains(Capabilities::MULTIPLEX));
+        tunnel.disconnect().await;
+    }
+}
+
+#[tokio::test]
+async fn test_tampered_handshake() {
+    // 新服务端因为客户端握手被改成旧版本而降级时，客户端发现降级标记
+    let port = start_versioned_server(2, |hello_ack| hello_ack.mark_downgrade()).await;
+    assert!(matches!(connect(port, false).await.err().unwrap(), TunnelError::Protocol(_)));
+
+    // 握手响应中的能力被去掉后双方的会话密钥不同，登录失败
+    let port = start_versioned_server(3, |hello_ack| hello_ack.capabilities = Capabilities::legacy()).await;
+    assert!(matches!(connect(port, false).await.err().unwrap(), TunnelError::Auth(_)));
+}
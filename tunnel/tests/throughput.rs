@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::channel;
+use tokio::time::{sleep, timeout};
+
+use tunnel::tunnel::cipher::{CipherSuite, FrameCipher, FrameDirection, random_salt, SessionKeys, transcript_hash};
+use tunnel::tunnel::codec::{FrameDecoder, FrameEncoder};
+use tunnel::tunnel::compress::Compression;
+use tunnel::tunnel::event::EventPublisher;
+use tunnel::tunnel::hello::{Capabilities, Hello, HelloAck};
+use tunnel::tunnel::transport::TcpTransport;
+use tunnel::tunnel::tunnel::{Tunnel, TunnelStatus};
+use tunnel::tunnel::tunnel_package::{PackageCmd, PackageProtocol, TunnelPackage};
+
+const PASSWORD: &str = "password";
+/// 每个方向传输的数据量
+const TOTAL_SIZE: usize = 32 * 1024 * 1024;
+/// 每个数据包的大小
+const CHUNK_SIZE: usize = 16 * 1024;
+/// 最低吞吐量(MB/s) 按调试构建设置，明显低于它说明编解码出现了性能退化
+const MIN_THROUGHPUT: f64 = 50.0;
+
+/// 从连接读取下一个数据包
+async fn read_package(stream: &mut TcpStream, decoder: &mut FrameDecoder, buffer: &mut BytesMut) -> Option<TunnelPackage> {
+    loop {
+        if let Some(package) = decoder.decode(buffer).unwrap() {
+            return Some(package);
+        }
+        buffer.reserve(64 * 1024);
+        if stream.read_buf(buffer).await.unwrap() == 0 {
+            return None;
+        }
+    }
+}
+
+async fn write_package(stream: &mut TcpStream, encoder: &mut FrameEncoder, buffer: &mut BytesMut, package: TunnelPackage) {
+    buffer.clear();
+    encoder.encode(&package, buffer).unwrap();
+    stream.write_all(buffer).await.unwrap();
+}
+
+/// 本地替身服务端
+/// 完成握手和登录，统计连接1收到的数据，收齐后用CloseConnect确认；
+/// 收到连接2的数据后向客户端发送同样多的数据
+async fn start_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut read_buffer = BytesMut::new();
+        let mut write_buffer = BytesMut::new();
+
+        let mut decoder = FrameDecoder::plain();
+        let package = read_package(&mut stream, &mut decoder, &mut read_buffer).await.unwrap();
+        assert_eq!(package.cmd, PackageCmd::Handshake);
+        let hello_data = package.data.unwrap();
+        let hello = Hello::from_byte_array(&hello_data).unwrap();
+        let hello_ack = HelloAck {
+            version: hello.select_version().unwrap(),
+            capabilities: hello.capabilities.intersect(Capabilities::supported()),
+            salt: random_salt().unwrap().to_vec(),
+        };
+        let package = TunnelPackage::new(PackageCmd::HandshakeAck, PackageProtocol::TCP, 0, None, None, Some(hello_ack.to_byte_array()));
+        write_package(&mut stream, &mut FrameEncoder::plain(), &mut write_buffer, package).await;
+
+        let transcript = transcript_hash(&hello_data, &hello_ack.to_byte_array()).unwrap();
+        let session_keys = SessionKeys::derive(PASSWORD.as_bytes(), &hello.salt, &hello_ack.salt, &transcript).unwrap();
+        let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &session_keys.client_key, FrameDirection::ClientToServer));
+        let mut encoder = FrameEncoder::new(FrameCipher::new(CipherSuite::Aes256Gcm, &session_keys.server_key, FrameDirection::ServerToClient));
+
+        let mut received = 0;
+        while let Some(package) = read_package(&mut stream, &mut decoder, &mut read_buffer).await {
+            let reply = match package.cmd {
+                PackageCmd::Login => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    assert!(session_keys.verify_login_request(&package.data.unwrap(), now).is_ok());
+                    TunnelPackage::new(PackageCmd::LoginSuccess, PackageProtocol::TCP, 0, None, None, None)
+                }
+                PackageCmd::PING => {
+                    TunnelPackage::new(PackageCmd::PONG, PackageProtocol::TCP, 0, None, None, None)
+                }
+                PackageCmd::TData if package.stream_id == 1 => {
+                    received += package.data.map_or(0, |data| data.len());
+                    if received < TOTAL_SIZE {
+                        continue;
+                    }
+                    TunnelPackage::new(PackageCmd::CloseConnect, PackageProtocol::TCP, 1, None, None, None)
+                }
+                PackageCmd::TData => {
+                    for _ in 0..TOTAL_SIZE / CHUNK_SIZE {
+                        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 2, None, None, Some(vec![0x5a; CHUNK_SIZE]));
+                        write_package(&mut stream, &mut encoder, &mut write_buffer, package).await;
+                    }
+                    continue;
+                }
+                _ => { continue; }
+            };
+            write_package(&mut stream, &mut encoder, &mut write_buffer, reply).await;
+        }
+    });
+    port
+}
+
+fn throughput(elapsed: Duration) -> f64 {
+    TOTAL_SIZE as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_tunnel_throughput() {
+    let port = start_server().await;
+    let (sender, mut receiver) = channel(1024);
+    let events = EventPublisher::new("throughput".to_string(), broadcast::channel(1).0);
+    let mut tunnel = Tunnel::new("127.0.0.1".to_string(), port, PASSWORD.to_string(), CipherSuite::Aes256Gcm, Compression::None, false, &TcpTransport, sender, events).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while !matches!(tunnel.get_status().await, TunnelStatus::Success) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+
+    // 上传
+    let start = Instant::now();
+    for _ in 0..TOTAL_SIZE / CHUNK_SIZE {
+        let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 1, None, None, Some(vec![0xa5; CHUNK_SIZE]));
+        tunnel.write_to_tunnel(package).await.unwrap();
+    }
+    let package = timeout(Duration::from_secs(60), receiver.recv()).await.unwrap().unwrap();
+    assert_eq!(package.cmd, PackageCmd::CloseConnect);
+    let upload = throughput(start.elapsed());
+
+    // 下载
+    let start = Instant::now();
+    let package = TunnelPackage::new(PackageCmd::TData, PackageProtocol::TCP, 2, None, None, Some(vec![1]));
+    tunnel.write_to_tunnel(package).await.unwrap();
+    let mut received = 0;
+    while received < TOTAL_SIZE {
+        let package = timeout(Duration::from_secs(60), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(package.stream_id, 2);
+        received += package.data.map_or(0, |data| data.len());
+    }
+    let download = throughput(start.elapsed());
+
+    println!("tunnel throughput upload {:.1}MB/s download {:.1}MB/s", upload, download);
+    assert!(upload >= MIN_THROUGHPUT, "upload {:.1}MB/s", upload);
+    assert!(download >= MIN_THROUGHPUT, "download {:.1}MB/s", download);
+    tunnel.disconnect().await;
+}
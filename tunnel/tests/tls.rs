@@ -0,0 +1,146 @@
+use std::pin::Pin;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{AlpnError, select_next_proto, Ssl, SslAcceptor, SslMethod};
+use openssl::x509::{X509, X509NameBuilder};
+use openssl::x509::extension::SubjectAlternativeName;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio_openssl::SslStream;
+
+use tunnel::tunnel::tls::{connect_tls, spki_sha256, TlsConfig};
+
+/// 生成localhost的自签名证书
+fn self_signed_cert() -> (X509, PKey<Private>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new().dns("localhost").ip("127.0.0.1").build(&builder.x509v3_context(None, None)).unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (builder.build(), key)
+}
+
+/// 本地TLS服务端 协商ALPN后回显收到的数据
+async fn start_server(cert: &X509, key: &PKey<Private>) -> u16 {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+    acceptor.set_certificate(cert).unwrap();
+    acceptor.set_private_key(key).unwrap();
+    acceptor.set_alpn_select_callback(|_, client| {
+        select_next_proto(b"\x08http/1.1", client).ok_or(AlpnError::NOACK)
+    });
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    spawn(async move {
+        while let Ok((tcp_stream, _)) = listener.accept().await {
+            let ssl = Ssl::new(acceptor.context()).unwrap();
+            spawn(async move {
+                let mut ssl_stream = SslStream::new(ssl, tcp_stream).unwrap();
+                if Pin::new(&mut ssl_stream).accept().await.is_err() {
+                    return;
+                }
+                let mut data = [0; 1024];
+                while let Ok(n) = ssl_stream.read(&mut data).await {
+                    if n == 0 || ssl_stream.write_all(&data[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+async fn connect(port: u16, config: &TlsConfig) -> Result<SslStream<TcpStream>, std::io::Error> {
+    let tcp_stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    connect_tls(tcp_stream, "127.0.0.1", config).await
+}
+
+#[tokio::test]
+async fn test_tls_spki_pin() {
+    let (cert, key) = self_signed_cert();
+    let port = start_server(&cert, &key).await;
+
+    let config = TlsConfig {
+        sni: Some("localhost".to_string()),
+        spki_pins: vec![spki_sha256(&cert).unwrap()],
+        alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+        ..Default::default()
+    };
+    let mut ssl_stream = connect(port, &config).await.unwrap();
+    assert_eq!(ssl_stream.ssl().selected_alpn_protocol(), Some(&b"http/1.1"[..]));
+    assert_eq!(ssl_stream.ssl().servername(openssl::ssl::NameType::HOST_NAME), Some("localhost"));
+    ssl_stream.write_all(b"hello").await.unwrap();
+    let mut data = [0; 5];
+    ssl_stream.read_exact(&mut data).await.unwrap();
+    assert_eq!(&data, b"hello");
+
+    // 公钥不匹配
+    let (other_cert, _) = self_signed_cert();
+    let config = TlsConfig {
+        spki_pins: vec![spki_sha256(&other_cert).unwrap()],
+        ..Default::default()
+    };
+    assert!(connect(port, &config).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tls_ca_file() {
+    let (cert, key) = self_signed_cert();
+    let port = start_server(&cert, &key).await;
+    let ca_file = std::env::temp_dir().join(format!("flyshadow-tls-test-{}.pem", port));
+    std::fs::write(&ca_file, cert.to_pem().unwrap()).unwrap();
+
+    let config = TlsConfig {
+        sni: Some("localhost".to_string()),
+        ca_file: Some(ca_file.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+    assert!(connect(port, &config).await.is_ok());
+
+    // 域名和证书不匹配
+    let config = TlsConfig {
+        sni: Some("example.com".to_string()),
+        ..config
+    };
+    assert!(connect(port, &config).await.is_err());
+
+    // 系统证书不信任自签名证书
+    let config = TlsConfig {
+        sni: Some("localhost".to_string()),
+        ..Default::default()
+    };
+    assert!(connect(port, &config).await.is_err());
+
+    let _ = std::fs::remove_file(ca_file);
+}
+
+#[test]
+fn test_parse_pin() {
+    let pin = TlsConfig::parse_pin("sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").unwrap();
+    assert_eq!(pin.len(), 32);
+    assert!(TlsConfig::parse_pin("abc").is_err());
+}
@@ -0,0 +1,113 @@
+use bytes::BytesMut;
+use proptest::prelude::*;
+
+use tunnel::tunnel::cipher::{CipherSuite, FrameCipher, FrameDirection};
+use tunnel::tunnel::codec::{FrameDecoder, FrameEncoder};
+use tunnel::tunnel::compress::Compression;
+use tunnel::tunnel::tunnel_package::{DecodeError, PackageCmd, PackageProtocol, TunnelPackage};
+
+fn package_cmd() -> impl Strategy<Value=PackageCmd> {
+    prop_oneof![
+        Just(PackageCmd::Login),
+        Just(PackageCmd::NewConnect),
+        Just(PackageCmd::CloseConnect),
+        Just(PackageCmd::TData),
+        Just(PackageCmd::PING),
+        Just(PackageCmd::WindowUpdate),
+        Just(PackageCmd::LoginFail),
+        Just(PackageCmd::ProtocolError),
+        Just(PackageCmd::PONG),
+        Just(PackageCmd::Resolve),
+        Just(PackageCmd::ResolveResult),
+    ]
+}
+
+fn package_protocol() -> impl Strategy<Value=PackageProtocol> {
+    prop_oneof![Just(PackageProtocol::TCP), Just(PackageProtocol::UDP)]
+}
+
+/// 空地址和空数据编码后解析为None 只属于连接的命令不带地址
+fn tunnel_package() -> impl Strategy<Value=TunnelPackage> {
+    (package_cmd(),
+     package_protocol(),
+     any::<u32>(),
+     proptest::option::of("[a-z0-9.:]{1,40}"),
+     proptest::option::of("[a-z0-9.:]{1,40}"),
+     proptest::option::of(proptest::collection::vec(any::<u8>(), 1..2048)))
+        .prop_map(|(cmd, protocol, stream_id, source_address, target_address, data)| {
+            let stream_only = match cmd {
+                PackageCmd::CloseConnect | PackageCmd::WindowUpdate => { true }
+                PackageCmd::TData => { protocol == PackageProtocol::TCP }
+                _ => { false }
+            };
+            if stream_only {
+                TunnelPackage::new(cmd, protocol, stream_id, None, None, data)
+            } else {
+                TunnelPackage::new(cmd, protocol, stream_id, source_address, target_address, data)
+            }
+        })
+}
+
+proptest! {
+    #[test]
+    fn test_package_round_trip(package in tunnel_package()) {
+        let mut vec = Vec::new();
+        package.to_byte_array(&mut vec);
+        prop_assert_eq!(vec.len(), package.encoded_len());
+        prop_assert_eq!(TunnelPackage::from_byte_array(&vec).unwrap(), package);
+    }
+
+    #[test]
+    fn test_legacy_package_round_trip(package in tunnel_package()) {
+        // 版本1用源地址携带连接编号
+        let mut package = package;
+        if package.stream_id != 0 {
+            package.source_address = None;
+        }
+        // 数字源地址会被当成连接编号
+        prop_assume!(package.source_address.as_deref().map_or(true, |addr| addr.parse::<u32>().is_err()));
+        let mut vec = Vec::new();
+        package.encode_version(1, &mut vec);
+        prop_assert_eq!(vec.len(), package.encoded_len_version(1));
+        prop_assert_eq!(TunnelPackage::from_byte_array_version(&vec, 1).unwrap(), package);
+    }
+
+    #[test]
+    fn test_truncated_package(package in tunnel_package(), cut in any::<prop::sample::Index>()) {
+        let mut vec = Vec::new();
+        package.to_byte_array(&mut vec);
+        let len = cut.index(vec.len());
+        let truncated = matches!(TunnelPackage::from_byte_array(&vec[..len]), Err(DecodeError::Truncated { .. }));
+        prop_assert!(truncated);
+    }
+
+    #[test]
+    fn test_arbitrary_package(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+        // 任意输入只会返回错误，不会panic
+        let _ = TunnelPackage::from_byte_array(&data);
+    }
+
+    #[test]
+    fn test_frame_round_trip(packages in proptest::collection::vec(tunnel_package(), 1..8), compression in 0..3i32) {
+        let key = [9u8; 32];
+        let mut encoder = FrameEncoder::new(FrameCipher::new(CipherSuite::ChaCha20Poly1305, &key, FrameDirection::ServerToClient));
+        encoder.set_compression(Compression::from_index(compression).unwrap());
+        let mut decoder = FrameDecoder::new(FrameCipher::new(CipherSuite::ChaCha20Poly1305, &key, FrameDirection::ServerToClient));
+
+        let mut buffer = BytesMut::new();
+        for package in packages.iter() {
+            encoder.encode(package, &mut buffer).unwrap();
+        }
+        for package in packages {
+            prop_assert_eq!(decoder.decode(&mut buffer).unwrap(), Some(package));
+        }
+        prop_assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_arbitrary_frame(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let mut decoder = FrameDecoder::plain();
+        let mut buffer = BytesMut::from(&data[..]);
+        while let Ok(Some(_)) = decoder.decode(&mut buffer) {}
+    }
+}
@@ -0,0 +1,107 @@
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::sync::oneshot;
+
+use tunnel::tunnel::transport::Transport;
+use tunnel::tunnel::websocket::{accept_key, WsConfig, WsTransport};
+
+/// 读取一个客户端帧 返回操作码和去掉掩码后的数据
+async fn read_client_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await.unwrap();
+    assert_eq!(head[1] & 0x80, 0x80, "客户端帧必须加掩码");
+    let len = match head[1] & 0x7f {
+        126 => { stream.read_u16().await.unwrap() as usize }
+        127 => { stream.read_u64().await.unwrap() as usize }
+        len => { len as usize }
+    };
+    let mut mask = [0u8; 4];
+    stream.read_exact(&mut mask).await.unwrap();
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await.unwrap();
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    (head[0] & 0x0f, payload)
+}
+
+/// 本地WebSocket服务端 只接受/tunnel路径
+/// 先发送PING，再把收到的二进制消息分两片回显，收到PONG后通知测试
+async fn start_server(pong_sender: oneshot::Sender<Vec<u8>>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    spawn(async move {
+        let mut pong_sender = Some(pong_sender);
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).await.unwrap();
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            if !request.starts_with("GET /tunnel HTTP/1.1\r\n") || !request.contains("\r\nHost: example.com\r\n") {
+                stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+                continue;
+            }
+            let key = request.lines()
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                    Upgrade: websocket\r\n\
+                                    Connection: Upgrade\r\n\
+                                    Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key).unwrap());
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.write_all(&[0x89, 2, b'h', b'i']).await.unwrap();
+
+            loop {
+                let (opcode, payload) = read_client_frame(&mut stream).await;
+                match opcode {
+                    0x2 => {
+                        let (first, second) = payload.split_at(payload.len() / 2);
+                        stream.write_all(&[0x02, first.len() as u8]).await.unwrap();
+                        stream.write_all(first).await.unwrap();
+                        stream.write_all(&[0x80, second.len() as u8]).await.unwrap();
+                        stream.write_all(second).await.unwrap();
+                    }
+                    0xA => {
+                        if let Some(pong_sender) = pong_sender.take() {
+                            let _ = pong_sender.send(payload);
+                        }
+                        break;
+                    }
+                    _ => { break; }
+                }
+            }
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn test_websocket_transport() {
+    let (pong_sender, pong_receiver) = oneshot::channel();
+    let port = start_server(pong_sender).await;
+
+    let transport = WsTransport {
+        config: WsConfig { path: "/tunnel".to_string(), host: Some("example.com".to_string()), tls: None },
+    };
+    let (mut reader, mut writer) = transport.connect("127.0.0.1", port).await.unwrap();
+    writer.write_frame(b"hello tunnel").await.unwrap();
+
+    let mut received = BytesMut::with_capacity(64);
+    while received.len() < 12 {
+        let n = reader.read_buf(&mut received).await.unwrap();
+        assert_ne!(n, 0);
+    }
+    assert_eq!(&received[..], b"hello tunnel");
+    assert_eq!(pong_receiver.await.unwrap(), b"hi");
+
+    // 路径错误时升级失败
+    let transport = WsTransport {
+        config: WsConfig { path: "/other".to_string(), host: Some("example.com".to_string()), tls: None },
+    };
+    assert!(transport.connect("127.0.0.1", port).await.is_err());
+}